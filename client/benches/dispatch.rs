@@ -0,0 +1,70 @@
+//! Latency of the request/response round trip through the `BasicXtbConnection`.
+//!
+//! The server side is an in-memory responder answering every request immediately, so the
+//! measured time is the cost of the client side (tagging, routing, delivery of responses).
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use futures_util::future::join_all;
+use serde_json::{from_str, json, Value};
+use tokio::runtime::{Builder, Runtime};
+use tokio::spawn;
+use tokio_tungstenite::tungstenite::Message;
+use xtb_client::{BasicXtbConnection, ChannelTransport, XtbConnection};
+
+
+/// Create the runtime. The single threaded runtime is used, so the results do not depend on the
+/// number of cores.
+fn runtime() -> Runtime {
+    Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+
+/// Connect the connection to the responder answering all requests by an empty response.
+fn connect(runtime: &Runtime) -> BasicXtbConnection<ChannelTransport> {
+    runtime.block_on(async {
+        let (client_end, mut server_end) = ChannelTransport::pair();
+        spawn(async move {
+            while let Some(message) = server_end.recv().await {
+                let request: Value = from_str(&message.to_string()).unwrap();
+                let response = json!({"status": true, "returnData": {"ask": 1.0}, "customTag": request["customTag"]});
+                if server_end.send(Message::text(response.to_string())).is_err() {
+                    break;
+                }
+            }
+        });
+        BasicXtbConnection::from_transport(client_end)
+    })
+}
+
+
+fn round_trip(c: &mut Criterion) {
+    let runtime = runtime();
+    let connection = connect(&runtime);
+    c.bench_function("round_trip", |b| {
+        b.to_async(&runtime).iter(|| async {
+            connection.send_command("getTickPrices", None).await.unwrap().await.unwrap()
+        })
+    });
+}
+
+
+fn concurrent_round_trips(c: &mut Criterion) {
+    let runtime = runtime();
+    let connection = connect(&runtime);
+    let mut group = c.benchmark_group("concurrent_round_trips");
+    for concurrency in [16usize, 128] {
+        group.bench_with_input(BenchmarkId::from_parameter(concurrency), &concurrency, |b, &concurrency| {
+            b.to_async(&runtime).iter(|| async {
+                let requests = (0..concurrency).map(|_| async {
+                    connection.send_command("getTickPrices", None).await.unwrap().await.unwrap()
+                });
+                join_all(requests).await
+            })
+        });
+    }
+    group.finish();
+}
+
+
+criterion_group!(benches, round_trip, concurrent_round_trips);
+criterion_main!(benches);
@@ -0,0 +1,87 @@
+//! Delivery of stream data messages through the `BasicXtbStreamConnection` to many message streams.
+//!
+//! Each message stream is scoped to a single symbol. The `indexed` group uses the filters made by
+//! the `XtbClient` for symbol scoped subscriptions, so messages are routed by the command and the
+//! symbol. The `custom_filters` group uses equivalent `Custom` filters, which are tested for each
+//! message.
+
+use std::time::Instant;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use futures_util::StreamExt;
+use serde_json::json;
+use tokio::runtime::{Builder, Runtime};
+use tokio_tungstenite::tungstenite::Message;
+use xtb_client::{BasicMessageStream, BasicXtbStreamConnection, ChannelTransport, DataMessageFilter, DeliveryPolicy};
+
+
+/// Create the runtime. The single threaded runtime is used, so the results do not depend on the
+/// number of cores.
+fn runtime() -> Runtime {
+    Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+
+/// Filter of tick prices of the `symbol` as made by the `XtbClient`.
+fn indexed_filter(symbol: &str) -> DataMessageFilter {
+    DataMessageFilter::All(vec![
+        DataMessageFilter::Command("tickPrices".to_owned()),
+        DataMessageFilter::FieldValue { name: "symbol".to_owned(), value: json!(symbol) },
+    ])
+}
+
+
+/// Filter of tick prices of the `symbol` tested for each message.
+fn custom_filter(symbol: &str) -> DataMessageFilter {
+    let symbol = json!(symbol);
+    DataMessageFilter::Custom(Box::new(move |msg| msg.command == "tickPrices" && msg.data["symbol"] == symbol))
+}
+
+
+/// Measure delivery of ticks to `subscribers` message streams made by the `make_filter`.
+///
+/// Ticks of all symbols are sent in turn and each tick is received from its message stream, so
+/// all message streams are drained and none of them drops messages.
+fn bench_dispatch(c: &mut Criterion, name: &str, make_filter: fn(&str) -> DataMessageFilter) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group(name);
+    for subscribers in [10usize, 300] {
+        let (client_end, server_end) = ChannelTransport::pair();
+        let (_connection, mut streams) = runtime.block_on(async {
+            let connection = BasicXtbStreamConnection::from_transport(client_end, "session".to_owned());
+            let streams: Vec<BasicMessageStream> = (0..subscribers)
+                .map(|idx| connection.make_message_stream_with_policy(make_filter(&format!("SYMBOL{idx}")), DeliveryPolicy::DropOldest(64)))
+                .collect();
+            (connection, streams)
+        });
+        let ticks: Vec<String> = (0..subscribers)
+            .map(|idx| json!({"command": "tickPrices", "data": {"symbol": format!("SYMBOL{idx}"), "ask": 1.0}}).to_string())
+            .collect();
+        group.bench_with_input(BenchmarkId::from_parameter(subscribers), &subscribers, |b, _| {
+            b.iter_custom(|iters| runtime.block_on(async {
+                let start = Instant::now();
+                for iter in 0..iters as usize {
+                    let idx = iter % subscribers;
+                    server_end.send(Message::text(ticks[idx].clone())).unwrap();
+                    streams[idx].next().await.unwrap().unwrap();
+                }
+                start.elapsed()
+            }))
+        });
+    }
+    group.finish();
+}
+
+
+fn indexed(c: &mut Criterion) {
+    bench_dispatch(c, "indexed", indexed_filter);
+}
+
+
+fn custom_filters(c: &mut Criterion) {
+    bench_dispatch(c, "custom_filters", custom_filter);
+}
+
+
+criterion_group!(benches, indexed, custom_filters);
+criterion_main!(benches);
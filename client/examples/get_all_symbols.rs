@@ -13,7 +13,7 @@ async fn main() {
     let api_server = dotenvy::var("XTB_API_SERVER").unwrap();
     let stream_server = dotenvy::var("XTB_STREAM_SERVER").unwrap();
 
-    let mut client = xtb_client::XtbClientBuilder::new(&api_server, &stream_server).build(&username, &password).await.unwrap();
+    let client = xtb_client::XtbClientBuilder::new(&api_server, &stream_server).build(&username, &password).await.unwrap();
 
     let symbols = client.get_all_symbols(GetAllSymbolsRequest::default()).await.unwrap();
     println!("{}", serde_json::to_string_pretty(&symbols).unwrap())
@@ -1,24 +1,31 @@
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, ready};
 use std::time::Duration;
 
 use async_trait::async_trait;
 use derive_setters::Setters;
+use futures_util::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use serde_json::{from_value, to_value, Value};
+use serde_json::{from_value, json, to_value, Value};
 use thiserror::Error;
+use tokio::runtime::Handle;
 use tokio::spawn;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
+use tokio::sync::broadcast::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 use url::Url;
 
-use crate::{BasicMessageStream, BasicXtbConnection, BasicXtbStreamConnection, DataMessageFilter, MessageStream, ResponsePromise, XtbConnection, BasicXtbConnectionError, XtbStreamConnection, BasicXtbStreamConnectionError};
+use crate::{ConnectionEvent, DataStreamError, DeliveryPolicy, DEFAULT_STREAM_CHANNEL_CAPACITY, XtbServer, ConnectOptions, Interceptor, InterceptorChain, Proxy, TlsOptions, RateLimit, SessionRecorder, WireConnectionKind, BasicMessageStream, BasicXtbConnection, BasicXtbStreamConnection, DataMessageFilter, ResponsePromise, UnroutableMessage, XtbConnection, BasicXtbConnectionError, XtbStreamConnection, BasicXtbStreamConnectionError};
+use crate::events::emit_event;
 use crate::message_processing::ProcessedMessage;
-use crate::schema::{COMMAND_GET_ALL_SYMBOLS, COMMAND_GET_CALENDAR, COMMAND_GET_CHART_LAST_REQUEST, COMMAND_GET_CHART_RANGE_REQUEST, COMMAND_GET_COMMISSION_DEF, COMMAND_GET_CURRENT_USER_DATA, COMMAND_GET_IBS_HISTORY, COMMAND_GET_MARGIN_LEVEL, COMMAND_GET_MARGIN_TRADE, COMMAND_GET_NEWS, COMMAND_GET_PROFIT_CALCULATION, COMMAND_GET_SERVER_TIME, COMMAND_GET_STEP_RULES, COMMAND_GET_SYMBOL, COMMAND_GET_TICK_PRICES, COMMAND_GET_TRADE_RECORDS, COMMAND_GET_TRADES, COMMAND_GET_TRADES_HISTORY, COMMAND_GET_TRADING_HOURS, COMMAND_GET_VERSION, COMMAND_LOGIN, COMMAND_PING, COMMAND_TRADE_TRANSACTION, COMMAND_TRADE_TRANSACTION_STATUS, ErrorResponse, GetAllSymbolsRequest, GetAllSymbolsResponse, GetCalendarRequest, GetCalendarResponse, GetChartLastRequestRequest, GetChartLastRequestResponse, GetChartRangeRequestRequest, GetChartRangeRequestResponse, GetCommissionDefRequest, GetCommissionDefResponse, GetCurrentUserDataRequest, GetCurrentUserDataResponse, GetIbsHistoryRequest, GetIbsHistoryResponse, GetMarginLevelRequest, GetMarginLevelResponse, GetMarginTradeRequest, GetMarginTradeResponse, GetNewsRequest, GetNewsResponse, GetProfitCalculationRequest, GetProfitCalculationResponse, GetServerTimeRequest, GetServerTimeResponse, GetStepRulesRequest, GetStepRulesResponse, GetSymbolRequest, GetSymbolResponse, GetTickPricesRequest, GetTickPricesResponse, GetTradeRecordsRequest, GetTradeRecordsResponse, GetTradesHistoryRequest, GetTradesHistoryResponse, GetTradesRequest, GetTradesResponse, GetTradingHoursRequest, GetTradingHoursResponse, GetVersionRequest, GetVersionResponse, LoginRequest, PingRequest, STREAM_BALANCE, STREAM_CANDLES, STREAM_BALANCE_SUBSCRIBE, STREAM_CANDLES_SUBSCRIBE, STREAM_KEEP_ALIVE_SUBSCRIBE, STREAM_NEWS_SUBSCRIBE, STREAM_PROFITS_SUBSCRIBE, STREAM_TICK_PRICES_SUBSCRIBE, STREAM_TRADE_STATUS_SUBSCRIBE, STREAM_TRADES_SUBSCRIBE, STREAM_KEEP_ALIVE, STREAM_NEWS, STREAM_PING, STREAM_PROFITS, STREAM_BALANCE_UNSUBSCRIBE, STREAM_CANDLES_UNSUBSCRIBE, STREAM_KEEP_ALIVE_UNSUBSCRIBE, STREAM_NEWS_UNSUBSCRIBE, STREAM_PROFITS_UNSUBSCRIBE, STREAM_TICK_PRICES_UNSUBSCRIBE, STREAM_TRADE_STATUS_UNSUBSCRIBE, STREAM_TRADES_UNSUBSCRIBE, STREAM_TICK_PRICES, STREAM_TRADE_STATUS, STREAM_TRADES, StreamDataMessage, StreamGetBalanceData, StreamGetBalanceSubscribe, StreamGetBalanceUnsubscribe, StreamGetCandlesData, StreamGetCandlesSubscribe, StreamGetCandlesUnsubscribe, StreamGetKeepAliveData, StreamGetKeepAliveSubscribe, StreamGetKeepAliveUnsubscribe, StreamGetNewsData, StreamGetNewsSubscribe, StreamGetNewsUnsubscribe, StreamGetProfitData, StreamGetProfitSubscribe, StreamGetProfitUnsubscribe, StreamGetTickPricesData, StreamGetTickPricesSubscribe, StreamGetTickPricesUnsubscribe, StreamGetTradesData, StreamGetTradesSubscribe, StreamGetTradeStatusData, StreamGetTradeStatusSubscribe, StreamGetTradeStatusUnsubscribe, StreamGetTradesUnsubscribe, StreamPingSubscribe, TradeTransactionRequest, TradeTransactionResponse, TradeTransactionStatusRequest, TradeTransactionStatusResponse};
+use crate::schema::{Command, DecodedStreamMessage, StreamData, COMMAND_LOGIN, COMMAND_LOGOUT, COMMAND_PING, ErrorResponse, Response, GetAllSymbolsRequest, GetAllSymbolsResponse, GetCalendarRequest, GetCalendarResponse, GetChartLastRequestRequest, GetChartLastRequestResponse, GetChartRangeRequestRequest, GetChartRangeRequestResponse, GetCommissionDefRequest, GetCommissionDefResponse, GetCurrentUserDataRequest, GetCurrentUserDataResponse, GetIbsHistoryRequest, GetIbsHistoryResponse, GetMarginLevelRequest, GetMarginLevelResponse, GetMarginTradeRequest, GetMarginTradeResponse, GetNewsRequest, GetNewsResponse, GetProfitCalculationRequest, GetProfitCalculationResponse, GetServerTimeRequest, GetServerTimeResponse, GetStepRulesRequest, GetStepRulesResponse, GetSymbolRequest, GetSymbolResponse, GetTickPricesRequest, GetTickPricesResponse, GetTradeRecordsRequest, GetTradeRecordsResponse, GetTradesHistoryRequest, GetTradesHistoryResponse, GetTradesRequest, GetTradesResponse, GetTradingHoursRequest, GetTradingHoursResponse, GetVersionRequest, GetVersionResponse, LoginRequest, PingRequest, STREAM_BALANCE, STREAM_CANDLES, STREAM_BALANCE_SUBSCRIBE, STREAM_CANDLES_SUBSCRIBE, STREAM_KEEP_ALIVE_SUBSCRIBE, STREAM_NEWS_SUBSCRIBE, STREAM_PROFITS_SUBSCRIBE, STREAM_TICK_PRICES_SUBSCRIBE, STREAM_TRADE_STATUS_SUBSCRIBE, STREAM_TRADES_SUBSCRIBE, STREAM_KEEP_ALIVE, STREAM_NEWS, STREAM_PING, STREAM_PROFITS, STREAM_BALANCE_UNSUBSCRIBE, STREAM_CANDLES_UNSUBSCRIBE, STREAM_KEEP_ALIVE_UNSUBSCRIBE, STREAM_NEWS_UNSUBSCRIBE, STREAM_PROFITS_UNSUBSCRIBE, STREAM_TICK_PRICES_UNSUBSCRIBE, STREAM_TRADE_STATUS_UNSUBSCRIBE, STREAM_TRADES_UNSUBSCRIBE, STREAM_TICK_PRICES, STREAM_TRADE_STATUS, STREAM_TRADES, StreamGetBalanceData, StreamGetBalanceSubscribe, StreamGetBalanceUnsubscribe, StreamGetCandlesData, StreamGetCandlesSubscribe, StreamGetCandlesUnsubscribe, StreamGetKeepAliveData, StreamGetKeepAliveSubscribe, StreamGetKeepAliveUnsubscribe, StreamGetNewsData, StreamGetNewsSubscribe, StreamGetNewsUnsubscribe, StreamGetProfitData, StreamGetProfitSubscribe, StreamGetProfitUnsubscribe, StreamGetTickPricesData, StreamGetTickPricesSubscribe, StreamGetTickPricesUnsubscribe, StreamGetTradesData, StreamGetTradesSubscribe, StreamGetTradeStatusData, StreamGetTradeStatusSubscribe, StreamGetTradeStatusUnsubscribe, StreamGetTradesUnsubscribe, StreamPingSubscribe, TradeTransactionRequest, TradeTransactionResponse, TradeTransactionStatusRequest, TradeTransactionStatusResponse};
 
 
 /// Builder for `XtbClient`.
@@ -33,6 +40,19 @@ use crate::schema::{COMMAND_GET_ALL_SYMBOLS, COMMAND_GET_CALENDAR, COMMAND_GET_C
 /// * `app_id` - application identifier (deprecated by the official API documentation)
 /// * `app_name` - application name (deprecated by the official API documentation)
 /// * `ping_period` - interval between ping commands. Default interval is 30s.
+/// * `reconnect_policy` - configuration of the automatic reconnection. Reconnection is enabled by default.
+/// * `request_timeout` - default time limit for responses of the request/response API. There is no limit by default.
+/// * `rate_limit` - pacing of the requests sent to the request/response API server. The default limit
+///   follows the official documentation (see `RateLimit`).
+/// * `recorder` - wire-tap recording traffic of both connections (see `SessionRecorder`). Nothing is
+///   recorded by default.
+/// * `proxy` - HTTP CONNECT or SOCKS5 proxy used by both connections. Connections are direct by default.
+/// * `tls` - TLS configuration of both connections: custom rustls config, extra root certificates or
+///   pinned public keys (see `TlsOptions`). The native root certificates are used by default.
+/// * `interceptors` - hooks called for every request and response of both connections (see
+///   `Interceptor`). Single interceptor can be appended by the `with_interceptor` method.
+/// * `stream_channel_capacity` - number of stream messages kept for each slow `DataStream`. When
+///   a consumer falls behind, it receives `DataStreamError::Lagged`. Default capacity is 64.
 ///
 /// The required configuration values are `api_url` and `stream_api_url`. Other values are optional.
 ///
@@ -51,11 +71,35 @@ pub struct XtbClientBuilder {
     app_name: Option<String>,
     /// Interval between pings. Shouldn't be greater than 1 minute.
     ping_period: Option<u64>,
+    /// Configuration of the automatic reconnection
+    reconnect_policy: Option<ReconnectPolicy>,
+    /// Default time limit for responses to commands
+    request_timeout: Option<Duration>,
+    /// Limit of the request rate
+    rate_limit: Option<RateLimit>,
+    /// Recorder of the traffic
+    recorder: Option<SessionRecorder>,
+    /// Proxy used by connections
+    proxy: Option<Proxy>,
+    /// Time limit for opening the tunnel through the proxy
+    proxy_handshake_timeout: Option<Duration>,
+    /// TLS configuration used by connections
+    tls: Option<TlsOptions>,
+    /// Hooks called for every request and response
+    interceptors: InterceptorChain,
+    /// Number of stream messages kept for slow consumers
+    stream_channel_capacity: Option<usize>,
 }
 
 
 const DEFAULT_PING_INTERVAL_S: u64 = 30;
 
+/// Number of connection events kept for slow receivers
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+/// Default time limit for pending requests and for the logout when the client is closed
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 const DEFAULT_XTB_REAL: &'static str = "wss://ws.xtb.com/real";
 const DEFAULT_XTB_REAL_STREAM: &'static str = "wss://ws.xtb.com/realStream";
 const DEFAULT_XTB_DEMO: &'static str = "wss://ws.xtb.com/demo";
@@ -73,6 +117,15 @@ impl XtbClientBuilder {
             app_id: None,
             app_name: None,
             ping_period: None,
+            reconnect_policy: None,
+            request_timeout: None,
+            rate_limit: None,
+            recorder: None,
+            proxy: None,
+            proxy_handshake_timeout: None,
+            tls: None,
+            interceptors: InterceptorChain::default(),
+            stream_channel_capacity: None,
         }
     }
 
@@ -86,9 +139,24 @@ impl XtbClientBuilder {
             app_id: None,
             app_name: None,
             ping_period: None,
+            reconnect_policy: None,
+            request_timeout: None,
+            rate_limit: None,
+            recorder: None,
+            proxy: None,
+            proxy_handshake_timeout: None,
+            tls: None,
+            interceptors: InterceptorChain::default(),
+            stream_channel_capacity: None,
         }
     }
 
+    /// Append the interceptor to the end of the interceptor chain.
+    pub fn with_interceptor(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.interceptors = self.interceptors.with_interceptor(interceptor);
+        self
+    }
+
     /// Shorthand for `XtbClientBuilder::new("wss://ws.xtb.com/real", "wss://ws.xtb.com/realStream")`
     pub fn new_real() -> Self {
         Self::new(DEFAULT_XTB_REAL, DEFAULT_XTB_REAL_STREAM)
@@ -120,7 +188,23 @@ impl XtbClientBuilder {
         let stream_api_url = Self::make_url(self.stream_api_url)?;
 
         // create connection and perform login
-        let mut connection = BasicXtbConnection::new(api_url).await.map_err(|err| XtbClientBuilderError::CannotMakeConnection(err))?;
+        let rate_limit = self.rate_limit.unwrap_or_default();
+        let api_tap = self.recorder.as_ref().map(|recorder| recorder.tap(WireConnectionKind::Api));
+        let mut connect_options = ConnectOptions::default();
+        if let Some(proxy) = self.proxy {
+            connect_options = connect_options.with_proxy(proxy);
+        }
+        if let Some(proxy_handshake_timeout) = self.proxy_handshake_timeout {
+            connect_options = connect_options.with_proxy_handshake_timeout(proxy_handshake_timeout);
+        }
+        if let Some(tls) = self.tls {
+            connect_options = connect_options.with_tls(tls);
+        }
+        let (events, _) = channel(EVENT_CHANNEL_CAPACITY);
+        let mut connection = BasicXtbConnection::connect(api_url.clone(), &connect_options, api_tap).await.map_err(|err| XtbClientBuilderError::CannotMakeConnection(err))?;
+        emit_event(&events, ConnectionEvent::Connected { server: XtbServer::RequestResponse });
+        connection.set_rate_limit(Some(rate_limit.clone()));
+        connection.set_interceptors(self.interceptors.clone());
         let mut login_request = LoginRequest::default().with_user_id(user_id).with_password(password);
 
         if let Some(app_id) = self.app_id {
@@ -130,21 +214,28 @@ impl XtbClientBuilder {
             login_request = login_request.with_app_name(app_name);
         }
 
-        let login_request_value = to_value(login_request).map_err(|err| XtbClientBuilderError::UnexpectedError(format!("{:?}", err)))?;
-
-        let response = connection
-            .send_command(COMMAND_LOGIN, Some(login_request_value)).await
-            .map_err(|err| XtbClientBuilderError::UnexpectedError(format!("{:?}", err)))?.await
-            .map_err(|err| XtbClientBuilderError::UnexpectedError(format!("{:?}", err)))?;
-
-        let stream_session_id = match response {
-            ProcessedMessage::ErrorResponse(msg) => return Err(XtbClientBuilderError::LoginFailed { user_id: user_id.to_string(), extra_info: format!("{:?}", msg) }),
-            ProcessedMessage::Response(response) => response.stream_session_id.unwrap(),
+        let stream_session_id = login(&connection, &login_request).await?;
+        emit_event(&events, ConnectionEvent::LoggedIn);
+
+        let stream_tap = self.recorder.as_ref().map(|recorder| recorder.tap(WireConnectionKind::Stream));
+        let mut stream_connection = BasicXtbStreamConnection::connect_with_capacity(stream_api_url.clone(), stream_session_id, &connect_options, stream_tap, self.stream_channel_capacity.unwrap_or(DEFAULT_STREAM_CHANNEL_CAPACITY)).await.map_err(|err| XtbClientBuilderError::CannotMakeStreamConnection(err))?;
+        stream_connection.set_interceptors(self.interceptors.clone());
+        emit_event(&events, ConnectionEvent::Connected { server: XtbServer::Stream });
+
+        let session = SessionConfig {
+            api_url,
+            stream_api_url,
+            login_request,
+            reconnect_policy: self.reconnect_policy.unwrap_or_default(),
+            rate_limit,
+            recorder: self.recorder,
+            connect_options,
+            interceptors: self.interceptors,
+            unroutable_sender: connection.unroutable_sender().clone(),
         };
-
-        let stream_connection = BasicXtbStreamConnection::new(stream_api_url, stream_session_id).await.map_err(|err| XtbClientBuilderError::CannotMakeStreamConnection(err))?;
-
-        Ok(XtbClient::new(connection, stream_connection, self.ping_period.unwrap_or(DEFAULT_PING_INTERVAL_S)))
+        let mut client = XtbClient::new_with_session(connection, stream_connection, self.ping_period.unwrap_or(DEFAULT_PING_INTERVAL_S), Some(session), events);
+        client.set_request_timeout(self.request_timeout);
+        Ok(client)
     }
 
     /// Convert string into an `Url` instance. This method is also used for validation of url presence.
@@ -178,6 +269,112 @@ pub enum XtbClientBuilderError {
 }
 
 
+/// Configuration of the automatic reconnection.
+///
+/// When the connection to the request/response server or to the stream server is lost, the
+/// `XtbClient` connects to both servers again, performs the login and restores all active stream
+/// subscriptions. The delay before the first attempt is `initial_delay` and it is multiplied by
+/// `multiplier` after each failed attempt until the `max_delay` is reached.
+///
+/// Default values are:
+///
+/// * `enabled` - true
+/// * `initial_delay` - 1s
+/// * `max_delay` - 60s
+/// * `multiplier` - 2
+/// * `max_attempts` - `None` (try forever)
+/// * `check_period` - 1s
+#[derive(Clone, Debug, Setters)]
+#[setters(into, prefix = "with_")]
+pub struct ReconnectPolicy {
+    /// Enable or disable the automatic reconnection
+    enabled: bool,
+    /// Delay before the first reconnection attempt
+    initial_delay: Duration,
+    /// Upper limit of the delay between reconnection attempts
+    max_delay: Duration,
+    /// The delay is multiplied by this factor after each failed attempt
+    multiplier: u32,
+    /// Maximal number of attempts. If `None`, the client tries to reconnect forever.
+    max_attempts: Option<u32>,
+    /// Interval between checks of the connection state
+    check_period: Duration,
+}
+
+
+impl ReconnectPolicy {
+    /// Create policy with the automatic reconnection turned off.
+    pub fn disabled() -> Self {
+        Self::default().with_enabled(false)
+    }
+
+    /// Return delay before the reconnection attempt. The first attempt has number 1.
+    pub fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2,
+            max_attempts: None,
+            check_period: Duration::from_secs(1),
+        }
+    }
+}
+
+
+/// Data needed to establish a new session when the connection is lost.
+#[derive(Clone, Debug)]
+struct SessionConfig {
+    /// Url of the request/response API server
+    api_url: Url,
+    /// Url of the stream API server
+    stream_api_url: Url,
+    /// Credentials used for the login
+    login_request: LoginRequest,
+    /// The reconnection configuration
+    reconnect_policy: ReconnectPolicy,
+    /// Limit of the request rate applied to the new connection
+    rate_limit: RateLimit,
+    /// Recorder of the traffic of new connections
+    recorder: Option<SessionRecorder>,
+    /// Options used for opening new connections
+    connect_options: ConnectOptions,
+    /// Interceptors of new connections
+    interceptors: InterceptorChain,
+    /// Sender of unroutable messages owned by the client and passed to new connections
+    unroutable_sender: Sender<UnroutableMessage>,
+}
+
+
+/// Perform the login command on the `connection`.
+///
+/// # Returns
+///
+/// * `Ok(String)` - the stream session id returned by the server.
+/// * `Err(XtbClientBuilderError)` - the login failed.
+async fn login(connection: &BasicXtbConnection, login_request: &LoginRequest) -> Result<String, XtbClientBuilderError> {
+    let login_request_value = to_value(login_request).map_err(|err| XtbClientBuilderError::UnexpectedError(format!("{:?}", err)))?;
+
+    let response = connection
+        .send_command(COMMAND_LOGIN, Some(login_request_value)).await
+        .map_err(|err| XtbClientBuilderError::UnexpectedError(format!("{:?}", err)))?.await
+        .map_err(|err| XtbClientBuilderError::UnexpectedError(format!("{:?}", err)))?;
+
+    match response {
+        ProcessedMessage::ErrorResponse(msg) => Err(XtbClientBuilderError::LoginFailed { user_id: login_request.user_id.clone(), extra_info: format!("{:?}", msg) }),
+        ProcessedMessage::Response(response) => response.stream_session_id.ok_or_else(|| XtbClientBuilderError::UnexpectedError("The login response has no stream session id".to_owned())),
+    }
+}
+
+
 /// Declaration of the Request/response API interface.
 #[async_trait]
 pub trait RequestResponseApi {
@@ -185,10 +382,10 @@ pub trait RequestResponseApi {
     type Error;
 
     /// Returns array of all symbols available for the user.
-    async fn get_all_symbols(&mut self, request: GetAllSymbolsRequest) -> Result<GetAllSymbolsResponse, Self::Error>;
+    async fn get_all_symbols(&self, request: GetAllSymbolsRequest) -> Result<GetAllSymbolsResponse, Self::Error>;
 
     /// Returns calendar with market events.
-    async fn get_calendar(&mut self, request: GetCalendarRequest) -> Result<GetCalendarResponse, Self::Error>;
+    async fn get_calendar(&self, request: GetCalendarRequest) -> Result<GetCalendarResponse, Self::Error>;
 
     /// Please note that this function can be usually replaced by its streaming equivalent
     /// getCandles which is the preferred way of retrieving current candle data. Returns chart info,
@@ -215,7 +412,7 @@ pub trait RequestResponseApi {
     /// * request charts of 5 minutes period, for 3 months time span, back from now;
     /// * response: you are guaranteed to get 1 month of 5 minutes charts; because, 5 minutes period
     /// charts are not accessible 2 months and 3 months back from now.
-    async fn get_chart_last_request(&mut self, request: GetChartLastRequestRequest) -> Result<GetChartLastRequestResponse, Self::Error>;
+    async fn get_chart_last_request(&self, request: GetChartLastRequestRequest) -> Result<GetChartLastRequestResponse, Self::Error>;
 
     /// Please note that this function can be usually replaced by its streaming equivalent
     /// getCandles which is the preferred way of retrieving current candle data. Returns chart info
@@ -234,70 +431,70 @@ pub trait RequestResponseApi {
     /// PERIOD_H1, PERIOD_H4, PERIOD_D1, PERIOD_W1, PERIOD_MN1. Specific data ranges availability
     /// is guaranteed, however those ranges may be wider, e.g.: PERIOD_M1 may be accessible
     /// for 1.5 months back from now, where 1.0 months is guaranteed.
-    async fn get_chart_range_request(&mut self, request: GetChartRangeRequestRequest) -> Result<GetChartRangeRequestResponse, Self::Error>;
+    async fn get_chart_range_request(&self, request: GetChartRangeRequestRequest) -> Result<GetChartRangeRequestResponse, Self::Error>;
 
     /// Returns calculation of commission and rate of exchange. The value is calculated as expected
     /// value, and therefore might not be perfectly accurate.
-    async fn get_commission_def(&mut self, request: GetCommissionDefRequest) -> Result<GetCommissionDefResponse, Self::Error>;
+    async fn get_commission_def(&self, request: GetCommissionDefRequest) -> Result<GetCommissionDefResponse, Self::Error>;
 
     /// Returns information about account currency, and account leverage.
-    async fn get_current_user_data(&mut self, request: GetCurrentUserDataRequest) -> Result<GetCurrentUserDataResponse, Self::Error>;
+    async fn get_current_user_data(&self, request: GetCurrentUserDataRequest) -> Result<GetCurrentUserDataResponse, Self::Error>;
 
     /// Returns IBs data from the given time range.
-    async fn get_ibs_history(&mut self, request: GetIbsHistoryRequest) -> Result<GetIbsHistoryResponse, Self::Error>;
+    async fn get_ibs_history(&self, request: GetIbsHistoryRequest) -> Result<GetIbsHistoryResponse, Self::Error>;
 
     /// Please note that this function can be usually replaced by its streaming equivalent
     /// getBalance which is the preferred way of retrieving account indicators. Returns various
     /// account indicators.
-    async fn get_margin_level(&mut self, request: GetMarginLevelRequest) -> Result<GetMarginLevelResponse, Self::Error>;
+    async fn get_margin_level(&self, request: GetMarginLevelRequest) -> Result<GetMarginLevelResponse, Self::Error>;
 
     /// Returns expected margin for given instrument and volume. The value is calculated as expected
     /// margin value, and therefore might not be perfectly accurate.
-    async fn get_margin_trade(&mut self, request: GetMarginTradeRequest) -> Result<GetMarginTradeResponse, Self::Error>;
+    async fn get_margin_trade(&self, request: GetMarginTradeRequest) -> Result<GetMarginTradeResponse, Self::Error>;
 
     /// Please note that this function can be usually replaced by its streaming equivalent getNews
     /// which is the preferred way of retrieving news data. Returns news from trading server which
     /// were sent within specified period of time.
-    async fn get_news(&mut self, request: GetNewsRequest) -> Result<GetNewsResponse, Self::Error>;
+    async fn get_news(&self, request: GetNewsRequest) -> Result<GetNewsResponse, Self::Error>;
 
     /// Calculates estimated profit for given deal data Should be used for calculator-like apps
     /// only. Profit for opened transactions should be taken from server, due to higher precision of
     /// server calculation.
-    async fn get_profit_calculation(&mut self, request: GetProfitCalculationRequest) -> Result<GetProfitCalculationResponse, Self::Error>;
+    async fn get_profit_calculation(&self, request: GetProfitCalculationRequest) -> Result<GetProfitCalculationResponse, Self::Error>;
 
     /// Returns current time on trading server.
-    async fn get_server_time(&mut self, request: GetServerTimeRequest) -> Result<GetServerTimeResponse, Self::Error>;
+    async fn get_server_time(&self, request: GetServerTimeRequest) -> Result<GetServerTimeResponse, Self::Error>;
 
     /// Returns a list of step rules for DMAs.
-    async fn get_step_rules(&mut self, request: GetStepRulesRequest) -> Result<GetStepRulesResponse, Self::Error>;
+    async fn get_step_rules(&self, request: GetStepRulesRequest) -> Result<GetStepRulesResponse, Self::Error>;
 
     /// Returns information about symbol available for the user.
-    async fn get_symbol(&mut self, request: GetSymbolRequest) -> Result<GetSymbolResponse, Self::Error>;
+    async fn get_symbol(&self, request: GetSymbolRequest) -> Result<GetSymbolResponse, Self::Error>;
 
     /// Please note that this function can be usually replaced by its streaming equivalent
     /// getTickPrices which is the preferred way of retrieving ticks data. Returns array of current
     /// quotations for given symbols, only quotations that changed from given timestamp are
     /// returned. New timestamp obtained from output will be used as an argument of the next call
     /// of this command.
-    async fn get_tick_prices(&mut self, request: GetTickPricesRequest) -> Result<GetTickPricesResponse, Self::Error>;
+    async fn get_tick_prices(&self, request: GetTickPricesRequest) -> Result<GetTickPricesResponse, Self::Error>;
 
     /// Returns array of trades listed in orders argument.
-    async fn get_trade_records(&mut self, request: GetTradeRecordsRequest) -> Result<GetTradeRecordsResponse, Self::Error>;
+    async fn get_trade_records(&self, request: GetTradeRecordsRequest) -> Result<GetTradeRecordsResponse, Self::Error>;
 
     /// Please note that this function can be usually replaced by its streaming equivalent getTrades
     /// which is the preferred way of retrieving trades data. Returns array of user's trades.
-    async fn get_trades(&mut self, request: GetTradesRequest) -> Result<GetTradesResponse, Self::Error>;
+    async fn get_trades(&self, request: GetTradesRequest) -> Result<GetTradesResponse, Self::Error>;
 
     /// Please note that this function can be usually replaced by its streaming equivalent getTrades
     /// which is the preferred way of retrieving trades data. Returns array of user's trades which
     /// were closed within specified period of time.
-    async fn get_trades_history(&mut self, request: GetTradesHistoryRequest) -> Result<GetTradesHistoryResponse, Self::Error>;
+    async fn get_trades_history(&self, request: GetTradesHistoryRequest) -> Result<GetTradesHistoryResponse, Self::Error>;
 
     /// Returns quotes and trading times.
-    async fn get_trading_hours(&mut self, request: GetTradingHoursRequest) -> Result<GetTradingHoursResponse, Self::Error>;
+    async fn get_trading_hours(&self, request: GetTradingHoursRequest) -> Result<GetTradingHoursResponse, Self::Error>;
 
     /// Returns the current API version.
-    async fn get_version(&mut self, request: GetVersionRequest) -> Result<GetVersionResponse, Self::Error>;
+    async fn get_version(&self, request: GetVersionRequest) -> Result<GetVersionResponse, Self::Error>;
 
     /// Starts trade transaction. tradeTransaction sends main transaction information to the server.
     ///
@@ -310,14 +507,14 @@ pub trait RequestResponseApi {
     /// tradeTransactionStatus command with the order number, that came back with the response of
     /// the tradeTransaction command. You can find the example here:
     /// https://developers.xstore.pro/api/tutorials/opening_and_closing_trades2
-    async fn trade_transaction(&mut self, request: TradeTransactionRequest) -> Result<TradeTransactionResponse, Self::Error>;
+    async fn trade_transaction(&self, request: TradeTransactionRequest) -> Result<TradeTransactionResponse, Self::Error>;
 
     /// Description: Please note that this function can be usually replaced by its streaming
     /// equivalent getTradeStatus which is the preferred way of retrieving transaction status data.
     /// Returns current transaction status. At any time of transaction processing client might check
     /// the status of transaction on server side. In order to do that client must provide unique
     /// order taken from tradeTransaction invocation.
-    async fn trade_transaction_status(&mut self, request: TradeTransactionStatusRequest) -> Result<TradeTransactionStatusResponse, Self::Error>;
+    async fn trade_transaction_status(&self, request: TradeTransactionStatusRequest) -> Result<TradeTransactionStatusResponse, Self::Error>;
 }
 
 
@@ -327,7 +524,7 @@ pub trait StreamApi {
     /// Error returned from the client when something went wrong
     type Error;
 
-    type Stream<T: Send + Sync + for<'de> Deserialize<'de>>;
+    type Stream<T: StreamData>;
 
     /// Each streaming command takes as an argument streamSessionId which is sent in response
     /// message for login command performed in main connection. streamSessionId token allows to
@@ -335,38 +532,38 @@ pub trait StreamApi {
     /// different streamSessionId can be invoked. It will cause sending streaming data for multiple
     /// login sessions in one streaming connection. streamSessionId is valid until logout command is
     /// performed on main connection or main connection is disconnected.
-    async fn subscribe_balance(&mut self, arguments: StreamGetBalanceSubscribe) -> Result<Self::Stream<StreamGetBalanceData>, Self::Error>;
+    async fn subscribe_balance(&self, arguments: StreamGetBalanceSubscribe) -> Result<Self::Stream<StreamGetBalanceData>, Self::Error>;
 
     /// Subscribes for and unsubscribes from API chart candles. The interval of every candle
     /// is 1 minute. A new candle arrives every minute.
-    async fn subscribe_candles(&mut self, arguments: StreamGetCandlesSubscribe) -> Result<Self::Stream<StreamGetCandlesData>, Self::Error>;
+    async fn subscribe_candles(&self, arguments: StreamGetCandlesSubscribe) -> Result<Self::Stream<StreamGetCandlesData>, Self::Error>;
 
     /// Subscribes for and unsubscribes from 'keep alive' messages. A new 'keep alive' message
     /// is sent by the API every 3 seconds.
-    async fn subscribe_keep_alive(&mut self, arguments: StreamGetKeepAliveSubscribe) -> Result<Self::Stream<StreamGetKeepAliveData>, Self::Error>;
+    async fn subscribe_keep_alive(&self, arguments: StreamGetKeepAliveSubscribe) -> Result<Self::Stream<StreamGetKeepAliveData>, Self::Error>;
 
     /// Subscribes for and unsubscribes from news.
-    async fn subscribe_news(&mut self, arguments: StreamGetNewsSubscribe) -> Result<Self::Stream<StreamGetNewsData>, Self::Error>;
+    async fn subscribe_news(&self, arguments: StreamGetNewsSubscribe) -> Result<Self::Stream<StreamGetNewsData>, Self::Error>;
 
     /// Subscribes for and unsubscribes from profits.
-    async fn subscribe_profits(&mut self, arguments: StreamGetProfitSubscribe) -> Result<Self::Stream<StreamGetProfitData>, Self::Error>;
+    async fn subscribe_profits(&self, arguments: StreamGetProfitSubscribe) -> Result<Self::Stream<StreamGetProfitData>, Self::Error>;
 
     /// Establishes subscription for quotations and allows to obtain the relevant information
     /// in real-time, as soon as it is available in the system. The getTickPrices command can
     /// be invoked many times for the same symbol, but only one subscription for a given symbol
     /// will be created. Please beware that when multiple records are available, the order in which
     /// they are received is not guaranteed.
-    async fn subscribe_tick_prices(&mut self, arguments: StreamGetTickPricesSubscribe) -> Result<Self::Stream<StreamGetTickPricesData>, Self::Error>;
+    async fn subscribe_tick_prices(&self, arguments: StreamGetTickPricesSubscribe) -> Result<Self::Stream<StreamGetTickPricesData>, Self::Error>;
 
     /// Establishes subscription for user trade status data and allows to obtain the relevant
     /// information in real-time, as soon as it is available in the system. Please beware that when
     /// multiple records are available, the order in which they are received is not guaranteed.
-    async fn subscribe_trades(&mut self, arguments: StreamGetTradesSubscribe) -> Result<Self::Stream<StreamGetTradesData>, Self::Error>;
+    async fn subscribe_trades(&self, arguments: StreamGetTradesSubscribe) -> Result<Self::Stream<StreamGetTradesData>, Self::Error>;
 
     /// Allows to get status for sent trade requests in real-time, as soon as it is available
     /// in the system. Please beware that when multiple records are available, the order in which
     /// they are received is not guaranteed.
-    async fn subscribe_trade_status(&mut self, arguments: StreamGetTradeStatusSubscribe) -> Result<Self::Stream<StreamGetTradeStatusData>, Self::Error>;
+    async fn subscribe_trade_status(&self, arguments: StreamGetTradeStatusSubscribe) -> Result<Self::Stream<StreamGetTradeStatusData>, Self::Error>;
 }
 
 
@@ -374,16 +571,97 @@ pub trait StreamApi {
 ///
 /// This struct is designed to be an interface between user (application) and XTB API servers.
 ///
-/// The `XtbClient` is responsible for sending and receiving pings and logout when instance is dropped.
+/// The `XtbClient` is responsible for sending and receiving pings. When the connection is lost,
+/// the client reconnects and restores stream subscriptions (see `ReconnectPolicy`).
+///
+/// The client should be closed by the `close` method which waits for pending requests, cancels
+/// stream subscriptions and performs the logout. If the client is dropped without closing, the
+/// logout is sent on the best-effort basis.
+///
+/// The client is a cheap handle which can be cloned and shared between tasks. All clones use
+/// the same connections and commands sent from different tasks are not serialized, their
+/// responses are routed by message tags. Background workers are stopped when the last clone
+/// is dropped or when any clone is closed.
+#[derive(Clone)]
 pub struct XtbClient {
-    /// Connection to the request/response server
-    connection: Arc<Mutex<BasicXtbConnection>>,
+    /// Connection to the request/response server. The write lock is taken only for reconnection.
+    connection: Arc<RwLock<BasicXtbConnection>>,
     /// Connection to the stream server
     stream_manager: StreamManager,
+    /// Background workers and the closing state shared by all clones
+    lifecycle: Arc<ClientLifecycle>,
+    /// Sender of the connection lifecycle events
+    events: Sender<ConnectionEvent>,
+    /// time limit for responses to commands
+    request_timeout: Option<Duration>,
+    /// Delivery policy of new data streams. If `None`, the default policy of the stream connection is used.
+    delivery_policy: Option<DeliveryPolicy>,
+    /// Sender of unroutable messages shared by all request/response connections of the client
+    unroutable_sender: Sender<UnroutableMessage>,
+}
+
+
+/// State shared by all clones of the `XtbClient`.
+///
+/// When the struct is dropped, workers are stopped. If the client was not closed, the logout is
+/// sent on the best-effort basis.
+struct ClientLifecycle {
     /// handle of the request/response server ping worker
     ping_join_handle: JoinHandle<()>,
     /// handle of the stream server ping worker
     stream_ping_join_handle: JoinHandle<()>,
+    /// handle of the worker watching the connections
+    watchdog_join_handle: JoinHandle<()>,
+    /// Set when the client is being closed. No new command is accepted then.
+    closed: AtomicBool,
+    /// Connection to the request/response server used for the fallback logout
+    connection: Arc<RwLock<BasicXtbConnection>>,
+    /// Connection to the stream server closed by the fallback logout
+    stream_manager: StreamManager,
+}
+
+
+impl ClientLifecycle {
+    /// Stop all background workers.
+    fn stop_workers(&self) {
+        self.ping_join_handle.abort();
+        self.stream_ping_join_handle.abort();
+        self.watchdog_join_handle.abort();
+    }
+
+    /// Mark the client as closed. Return true if it was already closed.
+    fn mark_closed(&self) -> bool {
+        self.closed.swap(true, Ordering::AcqRel)
+    }
+
+    /// Return true if the client is closed or being closed.
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+
+impl Drop for ClientLifecycle {
+    fn drop(&mut self) {
+        self.stop_workers();
+        if self.mark_closed() {
+            return;
+        }
+        // The client may be dropped outside of the runtime. Nothing can be sent then.
+        let Ok(runtime) = Handle::try_current() else {
+            warn!("The client was dropped outside of the tokio runtime, the logout was not sent");
+            return;
+        };
+        let connection = self.connection.clone();
+        let stream_manager = self.stream_manager.clone();
+        runtime.spawn(async move {
+            let connection = connection.read().await;
+            if let Err(err) = logout(&connection, DEFAULT_CLOSE_TIMEOUT).await {
+                warn!("The logout of the dropped client failed: {err:?}");
+            }
+            close_connections(&connection, &stream_manager).await;
+        });
+    }
 }
 
 
@@ -403,26 +681,234 @@ impl XtbClient {
     ///
     /// The login is performed by the builder because the stream server implementation needs to know
     /// a stream session id which is provided by the `login` command.
+    ///
+    /// The client created by this method does not reconnect when the connection is lost, because
+    /// it does not know the credentials. Use the `XtbClientBuilder` to get the reconnecting client.
     pub fn new(connection: BasicXtbConnection, stream_connection: BasicXtbStreamConnection, ping_period: u64) -> Self {
-        let connection = Arc::new(Mutex::new(connection));
+        let (events, _) = channel(EVENT_CHANNEL_CAPACITY);
+        Self::new_with_session(connection, stream_connection, ping_period, None, events)
+    }
+
+    /// Create new instance of the `XtbClient`. If `session` is set and its reconnect policy is
+    /// enabled, the lost connections are made again. Connection events are sent by the `events`.
+    fn new_with_session(connection: BasicXtbConnection, stream_connection: BasicXtbStreamConnection, ping_period: u64, session: Option<SessionConfig>, events: Sender<ConnectionEvent>) -> Self {
+        // new connections made by the reconnection get the same sender (see `SessionConfig`)
+        let unroutable_sender = connection.unroutable_sender().clone();
+        let connection = Arc::new(RwLock::new(connection));
 
-        let ping_join_handle = spawn_ping(connection.clone(), ping_period);
+        let ping_join_handle = spawn_ping(connection.clone(), ping_period, events.clone());
 
         let stream_manager = StreamManager::new(stream_connection);
-        let stream_ping_join_handle = spawn_stream_ping(stream_manager.clone(), ping_period);
+        let stream_ping_join_handle = spawn_stream_ping(stream_manager.clone(), ping_period, events.clone());
+
+        let watchdog_join_handle = spawn_connection_watchdog(connection.clone(), stream_manager.clone(), session, events.clone());
+
+        let lifecycle = ClientLifecycle {
+            ping_join_handle,
+            stream_ping_join_handle,
+            watchdog_join_handle,
+            closed: AtomicBool::new(false),
+            connection: connection.clone(),
+            stream_manager: stream_manager.clone(),
+        };
 
         Self {
             connection,
             stream_manager,
-            ping_join_handle,
-            stream_ping_join_handle,
+            lifecycle: Arc::new(lifecycle),
+            events,
+            request_timeout: None,
+            delivery_policy: None,
+            unroutable_sender,
         }
     }
 
+    /// Close the client gracefully. The time limit for pending requests and for the logout is 5s.
+    ///
+    /// See the `close_with_timeout` for details.
+    pub async fn close(self) -> Result<(), XtbClientError> {
+        self.close_with_timeout(DEFAULT_CLOSE_TIMEOUT).await
+    }
+
+    /// Close the client gracefully. The close affects all clones of the client.
+    ///
+    /// The steps are:
+    ///
+    /// 1. New commands are rejected with the `XtbClientError::ClientClosed` error.
+    /// 2. Pending requests are waited for, but at most for the `timeout`.
+    /// 3. All active stream subscriptions are cancelled.
+    /// 4. The logout command is sent and its response is waited for at most for the `timeout`.
+    /// 5. Both connections are closed by the close frame.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - the client was closed or it was already closed by another clone.
+    /// * `Err(XtbClientError)` - the logout failed. Connections are closed anyway.
+    pub async fn close_with_timeout(self, timeout: Duration) -> Result<(), XtbClientError> {
+        if self.lifecycle.mark_closed() {
+            return Ok(());
+        }
+        self.lifecycle.stop_workers();
+        info!("Closing the XTB client");
+
+        let connection = self.connection.read().await;
+        if !connection.wait_for_pending_requests(timeout).await {
+            warn!("{} requests got no response before the client was closed", connection.pending_requests());
+        }
+        self.stream_manager.unsubscribe_all().await;
+        let result = logout(&connection, timeout).await;
+        close_connections(&connection, &self.stream_manager).await;
+        result
+    }
+
+    /// Create receiver of the connection lifecycle events (see `ConnectionEvent`).
+    ///
+    /// The receiver gets only events emitted after it was created. If the receiver does not keep
+    /// pace with events, the oldest events are dropped and the receiver gets the `Lagged` error.
+    pub fn events(&self) -> Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Create receiver of messages which cannot be routed to any pending command (e.g. responses
+    /// received after the timeout). See `BasicXtbConnection::unroutable_messages`.
+    ///
+    /// The receiver survives reconnections - messages of new connections are delivered to it too.
+    pub fn unroutable_messages(&self) -> Receiver<UnroutableMessage> {
+        self.unroutable_sender.subscribe()
+    }
+
+    /// Send any command with the `arguments` and wait for the whole response.
+    ///
+    /// The escape hatch for commands not supported by the library. The logged-in session, the
+    /// request timeout and interceptors of the client are used. Use `Value::Null` for commands
+    /// without arguments.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Response)` - the response with the `return_data` as is.
+    /// * `Err(XtbClientError::CommandFailed)` - the server returned the error response.
+    /// * `Err(XtbClientError)` - any other error.
+    pub async fn send_raw(&self, command: &str, arguments: Value) -> Result<Response, XtbClientError> {
+        let promise = self.send(command, arguments).await?;
+        let result = match self.request_timeout {
+            Some(timeout) => promise.with_timeout(timeout).await,
+            None => promise.await,
+        };
+        let response = result.map_err(|err| match err {
+            BasicXtbConnectionError::Timeout { tag } => XtbClientError::Timeout { command: command.to_owned(), tag },
+            err => XtbClientError::from_connection_error(err),
+        })?;
+        match response {
+            ProcessedMessage::Response(response) => Ok(response),
+            ProcessedMessage::ErrorResponse(err) => Err(XtbClientError::CommandFailed(err)),
+        }
+    }
+
+    /// Subscribe for any stream with the `arguments` and return the stream of raw data.
+    ///
+    /// The escape hatch for streams not supported by the library. The stream is shared with other
+    /// subscriptions of the same stream (the `unsubscribe` command is sent when the last stream is
+    /// dropped) and it is restored after reconnection.
+    ///
+    /// # Parameters
+    ///
+    /// * `subscribe` - command name of the subscribe command (e.g. `getCandles`)
+    /// * `unsubscribe` - command name of the unsubscribe command (e.g. `stopCandles`)
+    /// * `data_command` - command name in data messages (e.g. `candle`)
+    /// * `arguments` - arguments of the subscribe command (`Value::Object` or `Value::Null`). If
+    ///   the arguments contain the `symbol` field, the symbol is passed to the unsubscribe command too
+    ///   and the subscription is scoped to the symbol.
+    /// * `filter` - extra filter of data messages applied after the filter by the `data_command`
+    pub async fn subscribe_raw(&self, subscribe: &str, unsubscribe: &str, data_command: &str, arguments: Value, filter: DataMessageFilter) -> Result<DataStream<Value>, XtbClientError> {
+        if self.is_closed() {
+            return Err(XtbClientError::ClientClosed);
+        }
+        let symbol = arguments.get("symbol").and_then(Value::as_str).map(str::to_owned);
+        let (subscription_key, unsubscribe_arguments) = match &symbol {
+            Some(symbol) => (format!("{}.{}", data_command, symbol), json!({"symbol": symbol})),
+            None => (data_command.to_owned(), Value::Null),
+        };
+        let mut filters = vec![DataMessageFilter::Command(data_command.to_owned())];
+        if let Some(symbol) = symbol {
+            filters.push(DataMessageFilter::FieldValue { name: "symbol".to_owned(), value: Value::String(symbol) });
+        }
+        filters.push(filter);
+        let stream = self.stream_manager.make_message_stream(DataMessageFilter::All(filters), self.delivery_policy.clone()).await;
+        self.stream_manager.subscribe(subscribe, Some(arguments), unsubscribe, Some(unsubscribe_arguments), &subscription_key, stream).await
+    }
+
+    /// Execute the `command` and wait for its response.
+    ///
+    /// All commands of the `RequestResponseApi` can be executed by this method. Custom commands
+    /// can be defined by implementing the `Command` trait:
+    ///
+    /// `let version = client.execute(GetVersionRequest).await?;`
+    pub async fn execute<C: Command>(&self, command: C) -> Result<C::Response, XtbClientError> {
+        self.send_and_wait_or_default(C::NAME, command).await
+    }
+
+    /// Return true if the client was closed.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.lifecycle.is_closed()
+    }
+
+    /// Set time limit for responses to commands sent by the request/response API methods.
+    ///
+    /// If no response is received in time, the method returns `XtbClientError::Timeout`. The limit
+    /// applies to all following calls made by this handle until it is changed again. The `None`
+    /// value means waiting without any limit.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Create a clone of the client with different time limit for responses.
+    ///
+    /// The clone shares connections with the original client, so it can be used to override
+    /// the limit for a single call:
+    ///
+    /// `client.with_request_timeout(Some(Duration::from_secs(5))).trade_transaction(request).await`
+    pub fn with_request_timeout(&self, timeout: Option<Duration>) -> Self {
+        let mut client = self.clone();
+        client.request_timeout = timeout;
+        client
+    }
+
+    /// Get time limit for responses to commands.
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    /// Create a clone of the client subscribing new data streams with the delivery `policy`.
+    ///
+    /// The clone shares connections with the original client, so it can be used to set the policy
+    /// of a single subscription:
+    ///
+    /// `client.with_delivery_policy(DeliveryPolicy::conflate_latest_by("symbol")).subscribe_tick_prices(request).await`
+    ///
+    /// Data streams made without any policy keep up to `stream_channel_capacity` messages and drop
+    /// the oldest ones (see `XtbClientBuilder`).
+    pub fn with_delivery_policy(&self, policy: DeliveryPolicy) -> Self {
+        let mut client = self.clone();
+        client.delivery_policy = Some(policy);
+        client
+    }
+
+    /// Get delivery policy of new data streams.
+    pub fn delivery_policy(&self) -> Option<&DeliveryPolicy> {
+        self.delivery_policy.as_ref()
+    }
+
+    /// Return number of responses received after their requests were given up (e.g. because of
+    /// the timeout). The counter belongs to the current connection, it starts from zero again when
+    /// the client reconnects.
+    pub async fn late_responses(&self) -> u64 {
+        self.connection.read().await.late_responses()
+    }
+
     /// Send command to the server and wait for response.
     ///
     /// If command does not return any response, create default one with type of `RESP`.
-    async fn send_and_wait_or_default<REQ, RESP>(&mut self, command: &str, request: REQ) -> Result<RESP, XtbClientError>
+    pub(crate) async fn send_and_wait_or_default<REQ, RESP>(&self, command: &str, request: REQ) -> Result<RESP, XtbClientError>
         where
             REQ: Serialize,
             RESP: for<'de> Deserialize<'de> + Default {
@@ -430,41 +916,29 @@ impl XtbClient {
     }
 
     /// Send the command and wait for a response.
-    async fn send_and_wait<REQ, RESP>(&mut self, command: &str, request: REQ) -> Result<Option<RESP>, XtbClientError>
+    async fn send_and_wait<REQ, RESP>(&self, command: &str, request: REQ) -> Result<Option<RESP>, XtbClientError>
         where
             REQ: Serialize,
             RESP: for<'de> Deserialize<'de>
     {
-        let promise = self.send(command, request).await?;
-        let response = promise.await.map_err(|err| {
-            error!("Unexpected error: {:?}", err);
-            XtbClientError::UnexpectedError
-        })?;
-        match response {
-            ProcessedMessage::Response(response) => {
-                match response.return_data {
-                    Some(data) => from_value(data).map_err(|err| XtbClientError::DeserializationFailed(err)).map(|v| Some(v)),
-                    None => Ok(None)
-                }
-            }
-            ProcessedMessage::ErrorResponse(err) => Err(XtbClientError::CommandFailed(err)),
+        let response = self.send_raw(command, Self::convert_data_to_value(request)?).await?;
+        match response.return_data {
+            Some(data) => from_value(data).map_err(|err| XtbClientError::DeserializationFailed(err)).map(|v| Some(v)),
+            None => Ok(None)
         }
     }
 
     /// Send a command request to the server and return `Ok(ResponsePromise)` o
-    async fn send<A>(&mut self, command: &str, request: A) -> Result<ResponsePromise, XtbClientError>
+    async fn send<A>(&self, command: &str, request: A) -> Result<ResponsePromise, XtbClientError>
         where
             A: Serialize
     {
-        let mut conn = self.connection.lock().await;
+        if self.is_closed() {
+            return Err(XtbClientError::ClientClosed);
+        }
+        let conn = self.connection.read().await;
         let payload = Self::convert_data_to_value(request)?;
-        conn.send_command(command, Some(payload)).await.map_err(|err| {
-            match err {
-                BasicXtbConnectionError::SerializationError(err) => XtbClientError::SerializationFailed(err),
-                BasicXtbConnectionError::CannotSendRequest(err) => XtbClientError::CannotSendCommand(err),
-                _ => XtbClientError::UnexpectedError,
-            }
-        })
+        conn.send_command(command, Some(payload)).await.map_err(XtbClientError::from_connection_error)
     }
 
     /// Serialize payload data into value.
@@ -492,7 +966,7 @@ impl XtbClient {
     /// * `Ok<DataStream<T>>` - data stream with filter set to messages related to sent command
     /// * `Err<XtbClientError>` - unable to send command
     async fn send_simple_stream_command<T, SA, UA>(
-        &mut self,
+        &self,
         subscribe_command: &str,
         subscribe_arguments: SA,
         unsubscribe_command: &str,
@@ -500,14 +974,18 @@ impl XtbClient {
         data_command: &str,
     ) -> Result<DataStream<T>, XtbClientError>
         where
-            T: for<'de> Deserialize<'de> + Send + Sync,
+            T: StreamData,
             SA: Serialize,
             UA: Serialize,
     {
+        if self.is_closed() {
+            return Err(XtbClientError::ClientClosed);
+        }
         let unsubscribe_arguments = Self::convert_data_to_value(unsubscribe_arguments)?;
         let filter = DataMessageFilter::Command(data_command.to_owned());
         let subscribe_arguments = Self::convert_data_to_value(subscribe_arguments)?;
-        self.stream_manager.subscribe(subscribe_command, Some(subscribe_arguments), unsubscribe_command, Some(unsubscribe_arguments), data_command, filter).await
+        let stream = self.stream_manager.make_message_stream(filter, self.delivery_policy.clone()).await;
+        self.stream_manager.subscribe(subscribe_command, Some(subscribe_arguments), unsubscribe_command, Some(unsubscribe_arguments), data_command, stream).await
     }
 
     /// Send stream command to the stream API server and add filter by the `symbol` field to the
@@ -526,7 +1004,7 @@ impl XtbClient {
     /// * `Ok<DataStream<T>>` - data stream with filter set to messages related to sent command
     /// * `Err<XtbClientError>` - unable to send command
     async fn send_symbol_scoped_stream_command<T, SA, UA>(
-        &mut self,
+        &self,
         subscribe_command: &str,
         subscribe_arguments: SA,
         unsubscribe_command: &str,
@@ -535,10 +1013,13 @@ impl XtbClient {
         symbol: &str,
     ) -> Result<DataStream<T>, XtbClientError>
         where
-            T: for<'de> Deserialize<'de> + Send + Sync,
+            T: StreamData,
             SA: Serialize,
             UA: Serialize,
     {
+        if self.is_closed() {
+            return Err(XtbClientError::ClientClosed);
+        }
         let unsubscribe_arguments = Self::convert_data_to_value(unsubscribe_arguments)?;
         let subscribe_arguments = Self::convert_data_to_value(subscribe_arguments)?;
         let subscription_key = format!("{}.{}", data_command, symbol);
@@ -547,15 +1028,8 @@ impl XtbClient {
             DataMessageFilter::Command(data_command.to_owned()),
             DataMessageFilter::FieldValue { name: "symbol".to_owned(), value: Value::String(symbol.to_owned()) },
         ]);
-        self.stream_manager.subscribe(subscribe_command, Some(subscribe_arguments), unsubscribe_command, Some(unsubscribe_arguments), &subscription_key, filter).await
-    }
-}
-
-
-impl Drop for XtbClient {
-    fn drop(&mut self) {
-        self.ping_join_handle.abort();
-        self.stream_ping_join_handle.abort();
+        let stream = self.stream_manager.make_message_stream(filter, self.delivery_policy.clone()).await;
+        self.stream_manager.subscribe(subscribe_command, Some(subscribe_arguments), unsubscribe_command, Some(unsubscribe_arguments), &subscription_key, stream).await
     }
 }
 
@@ -564,92 +1038,92 @@ impl Drop for XtbClient {
 impl RequestResponseApi for XtbClient {
     type Error = XtbClientError;
 
-    async fn get_all_symbols(&mut self, request: GetAllSymbolsRequest) -> Result<GetAllSymbolsResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_ALL_SYMBOLS, request).await
+    async fn get_all_symbols(&self, request: GetAllSymbolsRequest) -> Result<GetAllSymbolsResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_calendar(&mut self, request: GetCalendarRequest) -> Result<GetCalendarResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_CALENDAR, request).await
+    async fn get_calendar(&self, request: GetCalendarRequest) -> Result<GetCalendarResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_chart_last_request(&mut self, request: GetChartLastRequestRequest) -> Result<GetChartLastRequestResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_CHART_LAST_REQUEST, request).await
+    async fn get_chart_last_request(&self, request: GetChartLastRequestRequest) -> Result<GetChartLastRequestResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_chart_range_request(&mut self, request: GetChartRangeRequestRequest) -> Result<GetChartRangeRequestResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_CHART_RANGE_REQUEST, request).await
+    async fn get_chart_range_request(&self, request: GetChartRangeRequestRequest) -> Result<GetChartRangeRequestResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_commission_def(&mut self, request: GetCommissionDefRequest) -> Result<GetCommissionDefResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_COMMISSION_DEF, request).await
+    async fn get_commission_def(&self, request: GetCommissionDefRequest) -> Result<GetCommissionDefResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_current_user_data(&mut self, request: GetCurrentUserDataRequest) -> Result<GetCurrentUserDataResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_CURRENT_USER_DATA, request).await
+    async fn get_current_user_data(&self, request: GetCurrentUserDataRequest) -> Result<GetCurrentUserDataResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_ibs_history(&mut self, request: GetIbsHistoryRequest) -> Result<GetIbsHistoryResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_IBS_HISTORY, request).await
+    async fn get_ibs_history(&self, request: GetIbsHistoryRequest) -> Result<GetIbsHistoryResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_margin_level(&mut self, request: GetMarginLevelRequest) -> Result<GetMarginLevelResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_MARGIN_LEVEL, request).await
+    async fn get_margin_level(&self, request: GetMarginLevelRequest) -> Result<GetMarginLevelResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_margin_trade(&mut self, request: GetMarginTradeRequest) -> Result<GetMarginTradeResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_MARGIN_TRADE, request).await
+    async fn get_margin_trade(&self, request: GetMarginTradeRequest) -> Result<GetMarginTradeResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_news(&mut self, request: GetNewsRequest) -> Result<GetNewsResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_NEWS, request).await
+    async fn get_news(&self, request: GetNewsRequest) -> Result<GetNewsResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_profit_calculation(&mut self, request: GetProfitCalculationRequest) -> Result<GetProfitCalculationResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_PROFIT_CALCULATION, request).await
+    async fn get_profit_calculation(&self, request: GetProfitCalculationRequest) -> Result<GetProfitCalculationResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_server_time(&mut self, request: GetServerTimeRequest) -> Result<GetServerTimeResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_SERVER_TIME, request).await
+    async fn get_server_time(&self, request: GetServerTimeRequest) -> Result<GetServerTimeResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_step_rules(&mut self, request: GetStepRulesRequest) -> Result<GetStepRulesResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_STEP_RULES, request).await
+    async fn get_step_rules(&self, request: GetStepRulesRequest) -> Result<GetStepRulesResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_symbol(&mut self, request: GetSymbolRequest) -> Result<GetSymbolResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_SYMBOL, request).await
+    async fn get_symbol(&self, request: GetSymbolRequest) -> Result<GetSymbolResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_tick_prices(&mut self, request: GetTickPricesRequest) -> Result<GetTickPricesResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_TICK_PRICES, request).await
+    async fn get_tick_prices(&self, request: GetTickPricesRequest) -> Result<GetTickPricesResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_trade_records(&mut self, request: GetTradeRecordsRequest) -> Result<GetTradeRecordsResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_TRADE_RECORDS, request).await
+    async fn get_trade_records(&self, request: GetTradeRecordsRequest) -> Result<GetTradeRecordsResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_trades(&mut self, request: GetTradesRequest) -> Result<GetTradesResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_TRADES, request).await
+    async fn get_trades(&self, request: GetTradesRequest) -> Result<GetTradesResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_trades_history(&mut self, request: GetTradesHistoryRequest) -> Result<GetTradesHistoryResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_TRADES_HISTORY, request).await
+    async fn get_trades_history(&self, request: GetTradesHistoryRequest) -> Result<GetTradesHistoryResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_trading_hours(&mut self, request: GetTradingHoursRequest) -> Result<GetTradingHoursResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_TRADING_HOURS, request).await
+    async fn get_trading_hours(&self, request: GetTradingHoursRequest) -> Result<GetTradingHoursResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn get_version(&mut self, request: GetVersionRequest) -> Result<GetVersionResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_GET_VERSION, request).await
+    async fn get_version(&self, request: GetVersionRequest) -> Result<GetVersionResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn trade_transaction(&mut self, request: TradeTransactionRequest) -> Result<TradeTransactionResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_TRADE_TRANSACTION, request).await
+    async fn trade_transaction(&self, request: TradeTransactionRequest) -> Result<TradeTransactionResponse, Self::Error> {
+        self.execute(request).await
     }
 
-    async fn trade_transaction_status(&mut self, request: TradeTransactionStatusRequest) -> Result<TradeTransactionStatusResponse, Self::Error> {
-        self.send_and_wait_or_default(COMMAND_TRADE_TRANSACTION_STATUS, request).await
+    async fn trade_transaction_status(&self, request: TradeTransactionStatusRequest) -> Result<TradeTransactionStatusResponse, Self::Error> {
+        self.execute(request).await
     }
 }
 
@@ -658,46 +1132,46 @@ impl RequestResponseApi for XtbClient {
 impl StreamApi for XtbClient {
     type Error = XtbClientError;
 
-    type Stream<T: Send + Sync + for<'de> Deserialize<'de>> = DataStream<T>;
+    type Stream<T: StreamData> = DataStream<T>;
 
-    async fn subscribe_balance(&mut self, arguments: StreamGetBalanceSubscribe) -> Result<Self::Stream<StreamGetBalanceData>, Self::Error> {
+    async fn subscribe_balance(&self, arguments: StreamGetBalanceSubscribe) -> Result<Self::Stream<StreamGetBalanceData>, Self::Error> {
         let stop_arguments = Self::convert_data_to_value(StreamGetBalanceUnsubscribe::default())?;
         self.send_simple_stream_command(STREAM_BALANCE_SUBSCRIBE, arguments, STREAM_BALANCE_UNSUBSCRIBE, stop_arguments, STREAM_BALANCE).await
     }
 
-    async fn subscribe_candles(&mut self, arguments: StreamGetCandlesSubscribe) -> Result<Self::Stream<StreamGetCandlesData>, Self::Error> {
+    async fn subscribe_candles(&self, arguments: StreamGetCandlesSubscribe) -> Result<Self::Stream<StreamGetCandlesData>, Self::Error> {
         let stop_arguments = Self::convert_data_to_value(StreamGetCandlesUnsubscribe::default().with_symbol(&arguments.symbol))?;
         let symbol = arguments.symbol.clone();
         self.send_symbol_scoped_stream_command(STREAM_CANDLES_SUBSCRIBE, arguments, STREAM_CANDLES_UNSUBSCRIBE, stop_arguments, STREAM_CANDLES, &symbol).await
     }
 
-    async fn subscribe_keep_alive(&mut self, arguments: StreamGetKeepAliveSubscribe) -> Result<Self::Stream<StreamGetKeepAliveData>, Self::Error> {
+    async fn subscribe_keep_alive(&self, arguments: StreamGetKeepAliveSubscribe) -> Result<Self::Stream<StreamGetKeepAliveData>, Self::Error> {
         let stop_arguments = Self::convert_data_to_value(StreamGetKeepAliveUnsubscribe::default())?;
         self.send_simple_stream_command(STREAM_KEEP_ALIVE_SUBSCRIBE, arguments, STREAM_KEEP_ALIVE_UNSUBSCRIBE, stop_arguments, STREAM_KEEP_ALIVE).await
     }
 
-    async fn subscribe_news(&mut self, arguments: StreamGetNewsSubscribe) -> Result<Self::Stream<StreamGetNewsData>, Self::Error> {
+    async fn subscribe_news(&self, arguments: StreamGetNewsSubscribe) -> Result<Self::Stream<StreamGetNewsData>, Self::Error> {
         let stop_arguments = Self::convert_data_to_value(StreamGetNewsUnsubscribe::default())?;
         self.send_simple_stream_command(STREAM_NEWS_SUBSCRIBE, arguments, STREAM_NEWS_UNSUBSCRIBE, stop_arguments, STREAM_NEWS).await
     }
 
-    async fn subscribe_profits(&mut self, arguments: StreamGetProfitSubscribe) -> Result<Self::Stream<StreamGetProfitData>, Self::Error> {
+    async fn subscribe_profits(&self, arguments: StreamGetProfitSubscribe) -> Result<Self::Stream<StreamGetProfitData>, Self::Error> {
         let stop_arguments = Self::convert_data_to_value(StreamGetProfitUnsubscribe::default())?;
         self.send_simple_stream_command(STREAM_PROFITS_SUBSCRIBE, arguments, STREAM_PROFITS_UNSUBSCRIBE, stop_arguments, STREAM_PROFITS).await
     }
 
-    async fn subscribe_tick_prices(&mut self, arguments: StreamGetTickPricesSubscribe) -> Result<Self::Stream<StreamGetTickPricesData>, Self::Error> {
+    async fn subscribe_tick_prices(&self, arguments: StreamGetTickPricesSubscribe) -> Result<Self::Stream<StreamGetTickPricesData>, Self::Error> {
         let stop_arguments = Self::convert_data_to_value(StreamGetTickPricesUnsubscribe::default().with_symbol(&arguments.symbol))?;
         let symbol = arguments.symbol.clone();
         self.send_symbol_scoped_stream_command(STREAM_TICK_PRICES_SUBSCRIBE, arguments, STREAM_TICK_PRICES_UNSUBSCRIBE, stop_arguments, STREAM_TICK_PRICES, &symbol).await
     }
 
-    async fn subscribe_trades(&mut self, arguments: StreamGetTradesSubscribe) -> Result<Self::Stream<StreamGetTradesData>, Self::Error> {
+    async fn subscribe_trades(&self, arguments: StreamGetTradesSubscribe) -> Result<Self::Stream<StreamGetTradesData>, Self::Error> {
         let stop_arguments = Self::convert_data_to_value(StreamGetTradesUnsubscribe::default())?;
         self.send_simple_stream_command(STREAM_TRADES_SUBSCRIBE, arguments, STREAM_TRADES_UNSUBSCRIBE, stop_arguments, STREAM_TRADES).await
     }
 
-    async fn subscribe_trade_status(&mut self, arguments: StreamGetTradeStatusSubscribe) -> Result<Self::Stream<StreamGetTradeStatusData>, Self::Error> {
+    async fn subscribe_trade_status(&self, arguments: StreamGetTradeStatusSubscribe) -> Result<Self::Stream<StreamGetTradeStatusData>, Self::Error> {
         let stop_arguments = Self::convert_data_to_value(StreamGetTradeStatusUnsubscribe::default())?;
         self.send_simple_stream_command(STREAM_TRADE_STATUS_SUBSCRIBE, arguments, STREAM_TRADE_STATUS_UNSUBSCRIBE, stop_arguments, STREAM_TRADE_STATUS).await
     }
@@ -718,6 +1192,39 @@ pub enum XtbClientError {
     DeserializationFailed(serde_json::Error),
     #[error("Command failed and an error response was returned")]
     CommandFailed(ErrorResponse),
+    #[error("Connection to the server was closed (code: {code:?}, reason: {reason:?})")]
+    ConnectionClosed { code: Option<u16>, reason: Option<String> },
+    #[error("No response to the command '{command}' (tag '{tag}') was received in time")]
+    Timeout { command: String, tag: String },
+    #[error("The client was closed")]
+    ClientClosed,
+    #[error("The command '{command}' was rejected by an interceptor: {reason}")]
+    CommandRejected { command: String, reason: String },
+}
+
+
+impl XtbClientError {
+    /// Convert error returned from the request/response connection.
+    fn from_connection_error(err: BasicXtbConnectionError) -> Self {
+        match err {
+            BasicXtbConnectionError::SerializationError(err) => Self::SerializationFailed(err),
+            BasicXtbConnectionError::CannotSendRequest(err) => Self::CannotSendCommand(err),
+            BasicXtbConnectionError::ConnectionClosed { code, reason } => Self::ConnectionClosed { code, reason },
+            BasicXtbConnectionError::Rejected { command, reason } => Self::CommandRejected { command, reason },
+            err => {
+                error!("Unexpected error: {:?}", err);
+                Self::UnexpectedError
+            }
+        }
+    }
+
+    /// Convert error returned from the stream connection.
+    fn from_stream_connection_error(err: BasicXtbStreamConnectionError) -> Self {
+        match err {
+            BasicXtbStreamConnectionError::Rejected { command, reason } => Self::CommandRejected { command, reason },
+            err => Self::CannotSendStreamCommand(err),
+        }
+    }
 }
 
 
@@ -726,8 +1233,10 @@ pub enum XtbClientError {
 struct StreamManagerState {
     /// The stream connection
     connection: BasicXtbStreamConnection,
-    /// subscription counter
-    subscriptions: HashMap<String, usize>,
+    /// active subscriptions by subscription key
+    subscriptions: HashMap<String, Subscription>,
+    /// Set when all subscriptions were cancelled by the client close
+    closed: bool,
 }
 
 
@@ -737,6 +1246,41 @@ impl StreamManagerState {
         Self {
             connection,
             subscriptions: HashMap::new(),
+            closed: false,
+        }
+    }
+}
+
+
+/// Active subscription for a data stream.
+///
+/// The subscribe command and its arguments are kept, so the subscription can be restored after
+/// reconnection. The unsubscribe command and its arguments are kept, so the subscription can be
+/// cancelled when the client is closed.
+#[derive(Debug)]
+struct Subscription {
+    /// Number of data streams consuming the subscription
+    subscribers: usize,
+    /// The subscribe command (e.g. `getCandles`)
+    command: String,
+    /// Arguments of the subscribe command
+    arguments: Option<Value>,
+    /// The unsubscribe command (e.g. `stopCandles`)
+    unsubscribe_command: String,
+    /// Arguments of the unsubscribe command
+    unsubscribe_arguments: Option<Value>,
+}
+
+
+impl Subscription {
+    /// Create new subscription without any subscriber.
+    pub fn new(command: &str, arguments: Option<Value>, unsubscribe_command: &str, unsubscribe_arguments: Option<Value>) -> Self {
+        Self {
+            subscribers: 0,
+            command: command.to_owned(),
+            arguments,
+            unsubscribe_command: unsubscribe_command.to_owned(),
+            unsubscribe_arguments,
         }
     }
 }
@@ -769,28 +1313,45 @@ impl StreamManager {
     /// * `unsubscribe_command` - command name of the unsubscribe command (e.g. `stopCandles`)
     /// * `unsubscribe_arguments` - arguments for the unsubscribe command
     /// * `subscription_key` - key used to track number of subscribers of the data stream
-    /// * `filter` - the filter predicate for message routing.
+    /// * `stream` - the message stream made by the `make_message_stream` method.
     ///
     /// # Returns
     ///
     /// * `Ok<DataStream<T>>` - data stream with filter set to messages related to sent command
     /// * `Err<XtbClientError>` - unable to send command
-    pub async fn subscribe<T: for<'de> Deserialize<'de> + Send + Sync>(
-        &mut self,
+    pub async fn subscribe<T: StreamData>(
+        &self,
         subscribe_command: &str,
         subscribe_arguments: Option<Value>,
         unsubscribe_command: &str,
         unsubscribe_arguments: Option<Value>,
         subscription_key: &str,
-        filter: DataMessageFilter,
+        stream: BasicMessageStream,
     ) -> Result<DataStream<T>, XtbClientError> {
         let mut state = self.state.lock().await;
-        let stream = state.connection.make_message_stream(filter).await;
-        state.connection.subscribe(subscribe_command, subscribe_arguments).await.map_err(|err| XtbClientError::CannotSendStreamCommand(err))?;
-        *state.subscriptions.entry(subscription_key.to_owned()).or_default() += 1;
+        if state.closed {
+            return Err(XtbClientError::ClientClosed);
+        }
+        state.connection.subscribe(subscribe_command, subscribe_arguments.clone()).await.map_err(XtbClientError::from_stream_connection_error)?;
+        state.subscriptions
+            .entry(subscription_key.to_owned())
+            .or_insert_with(|| Subscription::new(subscribe_command, subscribe_arguments, unsubscribe_command, unsubscribe_arguments.clone()))
+            .subscribers += 1;
         Ok(DataStream::new(stream, self.clone(), subscription_key.to_owned(), unsubscribe_command.to_owned(), unsubscribe_arguments))
     }
 
+    /// Make message stream receiving messages matching the `filter`. If the `policy` is `None`, the
+    /// default delivery policy of the stream connection is used.
+    ///
+    /// The message stream is made before the subscribe command is sent, so no message is missed.
+    pub async fn make_message_stream(&self, filter: DataMessageFilter, policy: Option<DeliveryPolicy>) -> BasicMessageStream {
+        let mut state = self.state.lock().await;
+        match policy {
+            Some(policy) => state.connection.make_message_stream_with_policy(filter, policy),
+            None => state.connection.make_message_stream(filter).await,
+        }
+    }
+
     /// Unsubscribe from a stream.
     ///
     /// # Parameters
@@ -803,26 +1364,85 @@ impl StreamManager {
     ///
     /// * `Ok(())` - success
     /// * `Err(XtbClientError::CannotSendStreamCommand)` - fail
-    pub async fn unsubscribe(&mut self, subscription_key: &str, command: &str, arguments: Option<Value>) -> Result<(), XtbClientError> {
+    pub async fn unsubscribe(&self, subscription_key: &str, command: &str, arguments: Option<Value>) -> Result<(), XtbClientError> {
         let mut state = self.state.lock().await;
-        let mut entry = state.subscriptions.entry(subscription_key.to_owned()).or_default();
-        if *entry > 0 {
-            *entry -= 1;
+        if state.closed {
+            // all subscriptions were already cancelled
+            return Ok(());
         }
-        if *entry == 0 {
-            state.connection.unsubscribe(command, arguments).await.map_err(|err| XtbClientError::CannotSendStreamCommand(err))?;
+        let is_last = match state.subscriptions.get_mut(subscription_key) {
+            Some(subscription) => {
+                subscription.subscribers = subscription.subscribers.saturating_sub(1);
+                subscription.subscribers == 0
+            }
+            None => true,
+        };
+        if is_last {
+            state.subscriptions.remove(subscription_key);
+            state.connection.unsubscribe(command, arguments).await.map_err(XtbClientError::from_stream_connection_error)?;
         }
         Ok(())
     }
+
+    /// Connect the stream connection to the server again and restore all active subscriptions.
+    ///
+    /// # Parameters
+    ///
+    /// * `url` - url of the stream API server
+    /// * `stream_session_id` - the stream session id of the new session
+    /// * `options` - options used for opening the new socket
+    /// * `events` - sender of the connection events
+    pub async fn restore(&self, url: Url, stream_session_id: String, options: &ConnectOptions, events: &Sender<ConnectionEvent>) -> Result<(), BasicXtbStreamConnectionError> {
+        let mut guard = self.state.lock().await;
+        let state = &mut *guard;
+        state.connection.reconnect_with_options(url, stream_session_id, options).await?;
+        emit_event(events, ConnectionEvent::Connected { server: XtbServer::Stream });
+        for (key, subscription) in state.subscriptions.iter() {
+            debug!("Restoring subscription '{key}'");
+            state.connection.subscribe(&subscription.command, subscription.arguments.clone()).await?;
+            emit_event(events, ConnectionEvent::SubscriptionRestored { command: subscription.command.clone() });
+        }
+        Ok(())
+    }
+
+    /// Cancel all active subscriptions. No subscription can be made after this call.
+    ///
+    /// Failed unsubscribe commands are logged only.
+    pub async fn unsubscribe_all(&self) {
+        let mut guard = self.state.lock().await;
+        let state = &mut *guard;
+        state.closed = true;
+        for (key, subscription) in state.subscriptions.drain() {
+            debug!("Cancelling subscription '{key}'");
+            if let Err(err) = state.connection.unsubscribe(&subscription.unsubscribe_command, subscription.unsubscribe_arguments).await {
+                warn!("Cannot cancel subscription '{key}': {err:?}");
+            }
+        }
+    }
+
+    /// Close the stream connection by the close frame.
+    pub async fn close_connection(&self) {
+        if let Err(err) = self.state.lock().await.connection.close().await {
+            debug!("Cannot close the stream connection: {err:?}");
+        }
+    }
+
+    /// Return true if the stream connection is alive.
+    pub async fn is_connected(&self) -> bool {
+        self.state.lock().await.connection.is_connected()
+    }
 }
 
 
 /// Stream of messages delivered to a consumer.
 ///
-/// The message data is deserialized and typed to data type related to a command.
+/// The message data is decoded once, on the first use, to data type related to a command (see
+/// `StreamEvent` and `StreamData`) and shared by `Arc` with other data streams. The stream implements the
+/// `futures_util::Stream`, so all `StreamExt` combinators can be used. Note the inherent `next`
+/// method shadows the `StreamExt::next`.
 pub struct DataStream<T>
     where
-        T: for<'de> Deserialize<'de> + Send + Sync
+        T: StreamData
 {
     /// The message stream with raw messages
     message_stream: BasicMessageStream,
@@ -835,12 +1455,12 @@ pub struct DataStream<T>
     /// Unsubscribe command arguments
     unsubscribe_arguments: Option<Value>,
     /// Data type returned to a consumer
-    type_: PhantomData<T>,
+    type_: PhantomData<fn() -> T>,
 }
 
 impl<T> DataStream<T>
     where
-        T: for<'de> Deserialize<'de> + Send + Sync
+        T: StreamData
 {
     /// Create new instance of the stream.
     fn new(message_stream: BasicMessageStream, stream_manager: StreamManager, subscription_key: String, unsubscribe_command: String, unsubscribe_arguments: Option<Value>) -> Self {
@@ -850,7 +1470,7 @@ impl<T> DataStream<T>
             subscription_key,
             unsubscribe_command,
             unsubscribe_arguments,
-            type_: PhantomData::<T>,
+            type_: PhantomData,
         }
     }
 
@@ -858,29 +1478,39 @@ impl<T> DataStream<T>
     ///
     /// # Returns
     ///
-    /// * `Ok(Some(T))` - next message in stream.
+    /// * `Ok(Some(Arc<T>))` - next message in stream.
     /// * `Ok(None)` - there is no message left
-    /// * `Err(DataStreamError)` - message was recived but cannot be processed. A next message can be ok.
-    pub async fn next(&mut self) -> Result<Option<T>, DataStreamError> {
-        let message = self.message_stream.next().await;
-        match message {
-            Some(msg) => Self::process_message(msg).map(|r| Some(r)),
-            None => Ok(None),
-        }
+    /// * `Err(DataStreamError)` - message was recived but cannot be processed or messages were dropped
+    ///   because the consumer is too slow. A next message can be ok.
+    pub async fn next(&mut self) -> Result<Option<Arc<T>>, DataStreamError> {
+        StreamExt::next(self).await.transpose()
     }
 
-    /// Deserialize serialized data representation to actual type `T`.
-    fn process_message(msg: StreamDataMessage) -> Result<T, DataStreamError> {
-        from_value(msg.data).map_err(|err| DataStreamError::CannotDeserializeValue(err))
+    /// Take the shared data of the actual type `T` from the decoded message.
+    fn process_message(message: Arc<DecodedStreamMessage>) -> Result<Arc<T>, DataStreamError> {
+        T::from_message(&message).map_err(|err| DataStreamError::CannotDeserializeValue(err))
+    }
+}
+
+
+impl<T> Stream for DataStream<T>
+    where
+        T: StreamData
+{
+    type Item = Result<Arc<T>, DataStreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.message_stream.poll_next_decoded(cx));
+        Poll::Ready(item.map(|result| result.and_then(Self::process_message)))
     }
 }
 
 impl<T> Drop for DataStream<T>
     where
-        T: for<'de> Deserialize<'de> + Send + Sync
+        T: StreamData
 {
     fn drop(&mut self) {
-        let mut manager = self.stream_manager.clone();
+        let manager = self.stream_manager.clone();
         let unsubscribe_command = self.unsubscribe_command.clone();
         let unsubscribe_arguments = self.unsubscribe_arguments.take();
         let subscription_key = self.subscription_key.clone();
@@ -894,19 +1524,13 @@ impl<T> Drop for DataStream<T>
     }
 }
 
-#[derive(Debug, Error)]
-pub enum DataStreamError {
-    #[error("Cannot deserialize value: {0}")]
-    CannotDeserializeValue(serde_json::Error)
-}
-
-
 /// Spawn tokio green thread and to send ping periodically to sync connection
 ///
 /// # Arguments
 ///
 /// * conn - the stream connection
 /// * ping_secs - number of seconds between each ping
+/// * events - sender of the `PingFailed` events
 ///
 /// # Panics
 ///
@@ -915,26 +1539,34 @@ pub enum DataStreamError {
 /// # Returns
 ///
 /// `JoinHandle` of the green thread
-fn spawn_ping(conn: Arc<Mutex<BasicXtbConnection>>, ping_secs: u64) -> JoinHandle<()> {
+fn spawn_ping(conn: Arc<RwLock<BasicXtbConnection>>, ping_secs: u64, events: Sender<ConnectionEvent>) -> JoinHandle<()> {
     let ping_value = to_value(PingRequest::default()).expect("Cannot serialize ping message");
     spawn(async move {
         let mut idx = 1u64;
         loop {
             let response_promise = {
-                let mut conn = conn.lock().await;
+                let conn = conn.read().await;
                 debug!("Sending ping #{} to connection", idx);
                 match conn.send_command(COMMAND_PING, Some(ping_value.clone())).await {
                     Ok(resp) => Some(resp),
                     Err(err) => {
                         error!("Cannot send ping #{}: {:?}", idx, err);
+                        emit_event(&events, ConnectionEvent::PingFailed { server: XtbServer::RequestResponse, reason: err.to_string() });
                         None
                     }
                 }
             };
             if let Some(response_promise) = response_promise {
                 match response_promise.await {
-                    Ok(_) => (),
-                    Err(err) => error!("Cannot await the ping response #{}: {:?}", idx, err)
+                    Ok(ProcessedMessage::Response(_)) => (),
+                    Ok(ProcessedMessage::ErrorResponse(err)) => {
+                        error!("The ping #{} was rejected: {:?}", idx, err);
+                        emit_event(&events, ConnectionEvent::PingFailed { server: XtbServer::RequestResponse, reason: format!("{}: {}", err.error_code, err.error_descr.unwrap_or_default()) });
+                    }
+                    Err(err) => {
+                        error!("Cannot await the ping response #{}: {:?}", idx, err);
+                        emit_event(&events, ConnectionEvent::PingFailed { server: XtbServer::RequestResponse, reason: err.to_string() });
+                    }
                 }
             }
             idx += 1;
@@ -950,6 +1582,7 @@ fn spawn_ping(conn: Arc<Mutex<BasicXtbConnection>>, ping_secs: u64) -> JoinHandl
 ///
 /// * conn - the stream connection
 /// * ping_secs - number of seconds between each ping
+/// * events - sender of the `PingFailed` events
 ///
 /// # Panics
 ///
@@ -958,7 +1591,7 @@ fn spawn_ping(conn: Arc<Mutex<BasicXtbConnection>>, ping_secs: u64) -> JoinHandl
 /// # Returns
 ///
 /// `JoinHandle` of the green thread
-fn spawn_stream_ping(stream_manager: StreamManager, ping_secs: u64) -> JoinHandle<()> {
+fn spawn_stream_ping(stream_manager: StreamManager, ping_secs: u64, events: Sender<ConnectionEvent>) -> JoinHandle<()> {
     let ping_value = to_value(StreamPingSubscribe::default()).expect("Cannot serialize the stream ping message");
     spawn(async move {
         let mut idx = 1u64;
@@ -968,7 +1601,10 @@ fn spawn_stream_ping(stream_manager: StreamManager, ping_secs: u64) -> JoinHandl
                 let mut inner_state = stream_manager.state.lock().await;
                 match inner_state.connection.subscribe(STREAM_PING, Some(ping_value.clone())).await {
                     Ok(_) => (),
-                    Err(err) => error!("Cannot send ping #{}: {:?}", idx, err)
+                    Err(err) => {
+                        error!("Cannot send ping #{}: {:?}", idx, err);
+                        emit_event(&events, ConnectionEvent::PingFailed { server: XtbServer::Stream, reason: err.to_string() });
+                    }
                 }
             }
             idx += 1;
@@ -976,3 +1612,572 @@ fn spawn_stream_ping(stream_manager: StreamManager, ping_secs: u64) -> JoinHandl
         }
     })
 }
+
+
+/// Spawn tokio green thread watching the connections. When any connection is lost, the
+/// `Disconnected` event is emitted. If the `session` is set and the reconnection is enabled, both
+/// connections are made again, the user is logged in and stream subscriptions are restored.
+/// Otherwise, the worker stops. When all allowed reconnection attempts fail, the `ReconnectFailed`
+/// event is emitted and the worker stops.
+///
+/// # Arguments
+///
+/// * conn - the request/response connection
+/// * stream_manager - the stream manager holding the stream connection
+/// * session - data needed for the new session
+/// * events - sender of the connection events
+///
+/// # Returns
+///
+/// `JoinHandle` of the green thread
+fn spawn_connection_watchdog(conn: Arc<RwLock<BasicXtbConnection>>, stream_manager: StreamManager, session: Option<SessionConfig>, events: Sender<ConnectionEvent>) -> JoinHandle<()> {
+    spawn(async move {
+        let check_period = session.as_ref().map(|session| session.reconnect_policy.check_period).unwrap_or(ReconnectPolicy::default().check_period);
+        loop {
+            sleep(check_period).await;
+            let is_connected = conn.read().await.is_connected();
+            let is_stream_connected = stream_manager.is_connected().await;
+            let reason = match (is_connected, is_stream_connected) {
+                (true, true) => continue,
+                (false, true) => "The connection to the request/response server was lost",
+                (true, false) => "The connection to the stream server was lost",
+                (false, false) => "Connections to both servers were lost",
+            };
+            warn!("{reason}");
+            emit_event(&events, ConnectionEvent::Disconnected { reason: reason.to_owned() });
+
+            let Some(session) = session.as_ref().filter(|session| session.reconnect_policy.enabled) else {
+                return;
+            };
+            let policy = &session.reconnect_policy;
+            let mut attempt = 1u32;
+            loop {
+                if policy.max_attempts.is_some_and(|max_attempts| attempt > max_attempts) {
+                    error!("Cannot reconnect to the XTB server, giving up after {} attempts", attempt - 1);
+                    emit_event(&events, ConnectionEvent::ReconnectFailed { attempts: attempt - 1 });
+                    return;
+                }
+                sleep(policy.delay_for_attempt(attempt)).await;
+                info!("Reconnecting to the XTB server (attempt #{attempt})");
+                emit_event(&events, ConnectionEvent::Reconnecting { attempt });
+                match restore_session(&conn, &stream_manager, session, &events).await {
+                    Ok(_) => {
+                        info!("Connection to the XTB server was restored");
+                        break;
+                    }
+                    Err(err) => error!("Reconnection attempt #{attempt} failed: {err:?}"),
+                }
+                attempt += 1;
+            }
+        }
+    })
+}
+
+
+/// Perform the logout command on the `connection` and wait for the response at most for the `timeout`.
+///
+/// The server may close the connection right after the logout, so the closed connection is not
+/// considered as an error.
+async fn logout(connection: &BasicXtbConnection, timeout: Duration) -> Result<(), XtbClientError> {
+    let promise = connection.send_command(COMMAND_LOGOUT, None).await.map_err(XtbClientError::from_connection_error)?;
+    match promise.with_timeout(timeout).await {
+        Ok(ProcessedMessage::Response(_)) => Ok(()),
+        Ok(ProcessedMessage::ErrorResponse(err)) => Err(XtbClientError::CommandFailed(err)),
+        Err(BasicXtbConnectionError::ConnectionClosed { .. }) => Ok(()),
+        Err(BasicXtbConnectionError::Timeout { tag }) => Err(XtbClientError::Timeout { command: COMMAND_LOGOUT.to_owned(), tag }),
+        Err(err) => Err(XtbClientError::from_connection_error(err)),
+    }
+}
+
+
+/// Close both connections by the close frame. Errors are logged only (e.g. the server closed the
+/// connection after the logout).
+async fn close_connections(connection: &BasicXtbConnection, stream_manager: &StreamManager) {
+    if let Err(err) = connection.close().await {
+        debug!("Cannot close the connection: {err:?}");
+    }
+    stream_manager.close_connection().await;
+}
+
+
+/// Make new connections, log in and restore stream subscriptions.
+///
+/// The new request/response connection is made and logged in without locking the current one, so
+/// commands sent in the meantime fail fast on the closed connection. The lock is taken only to
+/// swap the connections.
+async fn restore_session(conn: &Arc<RwLock<BasicXtbConnection>>, stream_manager: &StreamManager, session: &SessionConfig, events: &Sender<ConnectionEvent>) -> Result<(), XtbClientBuilderError> {
+    let api_tap = session.recorder.as_ref().map(|recorder| recorder.tap(WireConnectionKind::Api));
+    let mut new_connection = BasicXtbConnection::connect_with_unroutable_sender(session.api_url.clone(), &session.connect_options, api_tap, session.unroutable_sender.clone()).await.map_err(XtbClientBuilderError::CannotMakeConnection)?;
+    emit_event(events, ConnectionEvent::Connected { server: XtbServer::RequestResponse });
+    new_connection.set_rate_limit(Some(session.rate_limit.clone()));
+    new_connection.set_interceptors(session.interceptors.clone());
+    let stream_session_id = login(&new_connection, &session.login_request).await?;
+    emit_event(events, ConnectionEvent::LoggedIn);
+    *conn.write().await = new_connection;
+    stream_manager.restore(session.stream_api_url.clone(), stream_session_id, &session.connect_options, events).await.map_err(XtbClientBuilderError::CannotMakeStreamConnection)
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod xtb_client {
+        use crate::XtbClient;
+
+        fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+
+        #[test]
+        fn client_is_shareable() {
+            assert_shareable::<XtbClient>();
+        }
+    }
+
+    mod events {
+        use std::time::{Duration, Instant};
+
+        use serde_json::json;
+        use tokio::sync::broadcast::Receiver;
+        use tokio::time::timeout;
+
+        use crate::{ConnectionEvent, Fault, FaultRule, MockFixtures, MockServer, ReconnectPolicy, RequestResponseApi, StreamApi, UnroutableReason, XtbServer};
+        use crate::schema::{COMMAND_GET_VERSION, COMMAND_LOGIN, COMMAND_PING, GetVersionRequest, StreamGetKeepAliveSubscribe, STREAM_KEEP_ALIVE_SUBSCRIBE, XtbErrorCode};
+
+        fn fast_policy() -> ReconnectPolicy {
+            ReconnectPolicy::default()
+                .with_initial_delay(Duration::from_millis(10))
+                .with_check_period(Duration::from_millis(10))
+        }
+
+        /// Receive events until the `predicate` matches. Return all received events.
+        async fn collect_until(events: &mut Receiver<ConnectionEvent>, predicate: impl Fn(&ConnectionEvent) -> bool) -> Vec<ConnectionEvent> {
+            let mut received = Vec::new();
+            timeout(Duration::from_secs(5), async {
+                loop {
+                    let event = events.recv().await.unwrap();
+                    let is_last = predicate(&event);
+                    received.push(event);
+                    if is_last {
+                        break;
+                    }
+                }
+            }).await.expect("The expected event was not received");
+            received
+        }
+
+        #[tokio::test]
+        async fn reconnection_events() {
+            let fixtures = MockFixtures::new()
+                .with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::DropConnection).times(1));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().with_reconnect_policy(fast_policy()).build("user", "password").await.unwrap();
+            let mut events = client.events();
+            let _stream = client.subscribe_keep_alive(StreamGetKeepAliveSubscribe).await.unwrap();
+
+            assert!(client.get_version(GetVersionRequest).await.is_err());
+            let received: Vec<_> = collect_until(&mut events, |event| matches!(event, ConnectionEvent::SubscriptionRestored { .. })).await
+                .into_iter()
+                // pings of the lost connection may fail in the meantime
+                .filter(|event| !matches!(event, ConnectionEvent::PingFailed { .. }))
+                .collect();
+
+            assert!(matches!(received[0], ConnectionEvent::Disconnected { .. }));
+            assert_eq!(received[1..], [
+                ConnectionEvent::Reconnecting { attempt: 1 },
+                ConnectionEvent::Connected { server: XtbServer::RequestResponse },
+                ConnectionEvent::LoggedIn,
+                ConnectionEvent::Connected { server: XtbServer::Stream },
+                ConnectionEvent::SubscriptionRestored { command: STREAM_KEEP_ALIVE_SUBSCRIBE.to_owned() },
+            ]);
+        }
+
+        #[tokio::test]
+        async fn reconnection_gives_up() {
+            let server = MockServer::start(MockFixtures::new()).await.unwrap();
+            let policy = fast_policy().with_max_attempts(2);
+            let client = server.client_builder().with_reconnect_policy(policy).build("user", "password").await.unwrap();
+            let mut events = client.events();
+
+            // the stopped server refuses all reconnection attempts
+            drop(server);
+            let received: Vec<_> = collect_until(&mut events, |event| matches!(event, ConnectionEvent::ReconnectFailed { .. })).await
+                .into_iter()
+                .filter(|event| !matches!(event, ConnectionEvent::PingFailed { .. }))
+                .collect();
+
+            assert!(matches!(received[0], ConnectionEvent::Disconnected { .. }));
+            assert_eq!(received[1..], [
+                ConnectionEvent::Reconnecting { attempt: 1 },
+                ConnectionEvent::Reconnecting { attempt: 2 },
+                ConnectionEvent::ReconnectFailed { attempts: 2 },
+            ]);
+        }
+
+        #[tokio::test]
+        async fn unroutable_messages_survive_reconnection() {
+            let fixtures = MockFixtures::new()
+                .with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}))
+                .with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::DropConnection).times(1))
+                .with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::Delay(Duration::from_millis(100))));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().with_reconnect_policy(fast_policy()).build("user", "password").await.unwrap();
+            let mut events = client.events();
+            let mut unroutable = client.unroutable_messages();
+
+            assert!(client.get_version(GetVersionRequest).await.is_err());
+            collect_until(&mut events, |event| *event == ConnectionEvent::Connected { server: XtbServer::Stream }).await;
+            // the response of the new connection comes after the timeout
+            assert!(client.with_request_timeout(Some(Duration::from_millis(10))).get_version(GetVersionRequest).await.is_err());
+
+            let message = timeout(Duration::from_secs(5), unroutable.recv()).await.unwrap().unwrap();
+            assert!(matches!(message.reason, UnroutableReason::NoConsumer { .. }));
+        }
+
+        #[tokio::test]
+        async fn commands_do_not_wait_for_reconnection() {
+            let fixtures = MockFixtures::new()
+                .with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}))
+                .with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::DropConnection).times(1))
+                .with_fault(FaultRule::on_command(COMMAND_LOGIN, Fault::Delay(Duration::from_millis(500))).times(2));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().with_reconnect_policy(fast_policy()).build("user", "password").await.unwrap();
+            let mut events = client.events();
+
+            assert!(client.get_version(GetVersionRequest).await.is_err());
+            collect_until(&mut events, |event| *event == ConnectionEvent::Connected { server: XtbServer::RequestResponse }).await;
+            // the login of the new connection is delayed, the old connection is closed
+            let started = Instant::now();
+            assert!(client.get_version(GetVersionRequest).await.is_err());
+            assert!(started.elapsed() < Duration::from_millis(250));
+
+            collect_until(&mut events, |event| *event == ConnectionEvent::LoggedIn).await;
+            assert!(client.get_version(GetVersionRequest).await.is_ok());
+        }
+
+        #[tokio::test]
+        async fn disconnected_without_reconnection() {
+            let fixtures = MockFixtures::new().with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::DropConnection));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let policy = ReconnectPolicy::disabled().with_check_period(Duration::from_millis(10));
+            let client = server.client_builder().with_reconnect_policy(policy).build("user", "password").await.unwrap();
+            let mut events = client.events();
+
+            assert!(client.get_version(GetVersionRequest).await.is_err());
+            let received = collect_until(&mut events, |event| matches!(event, ConnectionEvent::Disconnected { .. })).await;
+            assert_eq!(received.len(), 1);
+        }
+
+        #[tokio::test]
+        async fn failed_ping() {
+            let fixtures = MockFixtures::new().with_error(COMMAND_PING, XtbErrorCode::EX001, "Internal error");
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().with_ping_period(1u64).build("user", "password").await.unwrap();
+            let mut events = client.events();
+
+            let received = collect_until(&mut events, |event| matches!(event, ConnectionEvent::PingFailed { .. })).await;
+            assert!(matches!(received.last(), Some(ConnectionEvent::PingFailed { server: XtbServer::RequestResponse, reason }) if reason.contains("Internal error")));
+        }
+    }
+
+    mod close {
+        use std::time::Duration;
+
+        use serde_json::json;
+        use tokio::spawn;
+        use tokio::time::sleep;
+
+        use crate::{Fault, FaultRule, MockFixtures, MockServer, RequestResponseApi, StreamApi, XtbClientError};
+        use crate::schema::{COMMAND_GET_VERSION, COMMAND_LOGOUT, GetVersionRequest, STREAM_KEEP_ALIVE_UNSUBSCRIBE, StreamGetKeepAliveSubscribe};
+
+        #[tokio::test]
+        async fn close_waits_unsubscribes_and_logs_out() {
+            let fixtures = MockFixtures::new()
+                .with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}))
+                .with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::Delay(Duration::from_millis(200))));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+            let _stream = client.subscribe_keep_alive(StreamGetKeepAliveSubscribe).await.unwrap();
+
+            let pending_client = client.clone();
+            let pending = spawn(async move { pending_client.get_version(GetVersionRequest).await });
+            sleep(Duration::from_millis(50)).await;
+            let other_clone = client.clone();
+            client.close().await.unwrap();
+
+            assert_eq!(pending.await.unwrap().unwrap().version, "2.5.0");
+            assert!(matches!(other_clone.get_version(GetVersionRequest).await, Err(XtbClientError::ClientClosed)));
+            assert!(matches!(other_clone.subscribe_keep_alive(StreamGetKeepAliveSubscribe).await, Err(XtbClientError::ClientClosed)));
+
+            let commands: Vec<_> = server.received_commands().await.iter().map(|c| c["command"].as_str().unwrap().to_owned()).collect();
+            assert_eq!(commands.last().unwrap(), COMMAND_LOGOUT);
+            let stream_commands = server.received_stream_commands().await;
+            assert!(stream_commands.iter().any(|c| c["command"] == STREAM_KEEP_ALIVE_UNSUBSCRIBE));
+        }
+
+        #[tokio::test]
+        async fn close_gives_up_waiting_for_pending_requests() {
+            let fixtures = MockFixtures::new().with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::NoResponse));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            let pending_client = client.clone();
+            let _pending = spawn(async move { pending_client.get_version(GetVersionRequest).await });
+            sleep(Duration::from_millis(50)).await;
+            client.close_with_timeout(Duration::from_millis(100)).await.unwrap();
+
+            let commands = server.received_commands().await;
+            assert_eq!(commands.last().unwrap()["command"], COMMAND_LOGOUT);
+        }
+
+        #[tokio::test]
+        async fn drop_sends_logout() {
+            let server = MockServer::start(MockFixtures::new()).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            drop(client);
+            sleep(Duration::from_millis(500)).await;
+
+            let commands = server.received_commands().await;
+            assert!(commands.iter().any(|c| c["command"] == COMMAND_LOGOUT));
+        }
+    }
+
+    mod reconnect_policy {
+        use std::time::Duration;
+
+        use rstest::rstest;
+
+        use crate::ReconnectPolicy;
+
+        #[rstest]
+        #[case(1, 1)]
+        #[case(2, 2)]
+        #[case(3, 4)]
+        #[case(6, 32)]
+        #[case(7, 60)]
+        #[case(100, 60)]
+        fn delay_for_attempt(#[case] attempt: u32, #[case] expected_secs: u64) {
+            let policy = ReconnectPolicy::default();
+            assert_eq!(policy.delay_for_attempt(attempt), Duration::from_secs(expected_secs));
+        }
+
+        #[test]
+        fn delay_without_growth() {
+            let policy = ReconnectPolicy::default().with_multiplier(1u32).with_initial_delay(Duration::from_millis(500));
+            assert_eq!(policy.delay_for_attempt(1), Duration::from_millis(500));
+            assert_eq!(policy.delay_for_attempt(10), Duration::from_millis(500));
+        }
+    }
+
+    mod interceptors {
+        use std::sync::{Arc, Mutex};
+
+        use serde_json::json;
+
+        use crate::{Interceptor, MockFixtures, MockServer, ProcessedMessage, Rejection, RequestResponseApi, StreamApi, XtbClientError};
+        use crate::schema::{COMMAND_GET_VERSION, COMMAND_PING, COMMAND_TRADE_TRANSACTION, GetVersionRequest, Request, STREAM_TRADES_SUBSCRIBE, StreamGetTradesSubscribe, TradeTransactionRequest};
+
+        /// Block trading commands and record tags of requests and responses.
+        #[derive(Default)]
+        struct ReadOnlyAudit {
+            sent: Mutex<Vec<String>>,
+            received: Mutex<Vec<String>>,
+        }
+
+        impl Interceptor for Arc<ReadOnlyAudit> {
+            fn before_send(&self, request: &mut Request) -> Result<(), Rejection> {
+                if request.command == COMMAND_TRADE_TRANSACTION || request.command == STREAM_TRADES_SUBSCRIBE {
+                    return Err(Rejection::new("read-only mode"));
+                }
+                if request.command != COMMAND_PING {
+                    self.sent.lock().unwrap().push(request.custom_tag.clone().unwrap_or_default());
+                }
+                Ok(())
+            }
+
+            fn after_receive(&self, message: &ProcessedMessage) {
+                if let ProcessedMessage::Response(response) = message {
+                    self.received.lock().unwrap().push(response.custom_tag.clone().unwrap_or_default());
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn intercept_commands_and_subscriptions() {
+            let fixtures = MockFixtures::new().with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let audit = Arc::new(ReadOnlyAudit::default());
+            let client = server.client_builder().with_interceptor(audit.clone()).build("user", "password").await.unwrap();
+
+            client.get_version(GetVersionRequest).await.unwrap();
+            let trade = client.trade_transaction(TradeTransactionRequest::default()).await;
+            let subscription = client.subscribe_trades(StreamGetTradesSubscribe).await;
+
+            assert!(matches!(trade, Err(XtbClientError::CommandRejected { command, .. }) if command == COMMAND_TRADE_TRANSACTION));
+            assert!(matches!(subscription, Err(XtbClientError::CommandRejected { command, .. }) if command == STREAM_TRADES_SUBSCRIBE));
+            // the login and the getVersion
+            let sent = audit.sent.lock().unwrap().clone();
+            assert_eq!(sent.len(), 2);
+            assert!(sent.iter().all(|tag| audit.received.lock().unwrap().contains(tag)));
+            let commands = server.received_commands().await;
+            assert!(commands.iter().all(|c| c["command"] != COMMAND_TRADE_TRANSACTION));
+            assert!(server.received_stream_commands().await.iter().all(|c| c["command"] != STREAM_TRADES_SUBSCRIBE));
+        }
+    }
+
+    mod execute {
+        use serde::{Deserialize, Serialize};
+        use serde_json::json;
+
+        use crate::{MockFixtures, MockServer};
+        use crate::schema::{COMMAND_GET_VERSION, Command, GetVersionRequest};
+
+        /// Command not shipped by the crate.
+        #[derive(Serialize)]
+        struct GetFancyDataRequest {
+            symbol: String,
+        }
+
+        #[derive(Default, Deserialize)]
+        struct GetFancyDataResponse {
+            value: u32,
+        }
+
+        impl Command for GetFancyDataRequest {
+            const NAME: &'static str = "getFancyData";
+            type Response = GetFancyDataResponse;
+        }
+
+        #[tokio::test]
+        async fn shipped_and_custom_commands() {
+            let fixtures = MockFixtures::new()
+                .with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}))
+                .with_response(GetFancyDataRequest::NAME, json!({"value": 42}));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            assert_eq!(client.execute(GetVersionRequest).await.unwrap().version, "2.5.0");
+            let response = client.execute(GetFancyDataRequest { symbol: "EURUSD".to_owned() }).await.unwrap();
+            assert_eq!(response.value, 42);
+
+            let commands = server.received_commands().await;
+            let fancy = commands.iter().find(|c| c["command"] == "getFancyData").unwrap();
+            assert_eq!(fancy["arguments"], json!({"symbol": "EURUSD"}));
+        }
+    }
+
+    mod raw {
+        use std::time::Duration;
+
+        use serde_json::{json, Value};
+        use tokio::time::sleep;
+
+        use crate::{DataMessageFilter, MockFixtures, MockServer, StreamApi, XtbClientError};
+        use crate::schema::{STREAM_TICK_PRICES, STREAM_TICK_PRICES_SUBSCRIBE, STREAM_TICK_PRICES_UNSUBSCRIBE, StreamGetTickPricesSubscribe, XtbErrorCode};
+
+        #[tokio::test]
+        async fn send_raw() {
+            let fixtures = MockFixtures::new().with_response("getFancyData", json!({"value": 42}));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            let response = client.send_raw("getFancyData", json!({"symbol": "EURUSD"})).await.unwrap();
+            assert_eq!(response.return_data, Some(json!({"value": 42})));
+            match client.send_raw("getUnknownData", Value::Null).await {
+                Err(XtbClientError::CommandFailed(response)) => assert_eq!(response.error_code, XtbErrorCode::BE104),
+                other => panic!("Expected CommandFailed, but {:?}", other),
+            }
+        }
+
+        #[tokio::test]
+        async fn subscribe_raw_shares_subscription() {
+            let tick = |symbol: &str| json!({"symbol": symbol, "ask": 1.1, "bid": 1.0, "askVolume": 1, "bidVolume": 1, "high": 1.2, "low": 0.9, "level": 0, "quoteId": 1, "spreadRaw": 0.1, "spreadTable": 1.0, "timestamp": 1});
+            // the other symbol is sent first and it must not be delivered to the raw stream
+            let server = MockServer::start(MockFixtures::new().with_stream_data(STREAM_TICK_PRICES_SUBSCRIBE, vec![tick("GBPUSD"), tick("EURUSD")])).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            let typed = client.subscribe_tick_prices(StreamGetTickPricesSubscribe::default().with_symbol("EURUSD")).await.unwrap();
+            let mut raw = client.subscribe_raw(
+                STREAM_TICK_PRICES_SUBSCRIBE,
+                STREAM_TICK_PRICES_UNSUBSCRIBE,
+                STREAM_TICK_PRICES,
+                json!({"symbol": "EURUSD", "minArrivalTime": 100}),
+                DataMessageFilter::Always,
+            ).await.unwrap();
+            for _ in 0..2 {
+                let tick = raw.next().await.unwrap().unwrap();
+                assert_eq!(tick["symbol"], "EURUSD");
+            }
+
+            let is_unsubscribed = || async {
+                server.received_stream_commands().await.iter().any(|c| c["command"] == STREAM_TICK_PRICES_UNSUBSCRIBE && c["symbol"] == "EURUSD")
+            };
+            drop(raw);
+            sleep(Duration::from_millis(50)).await;
+            assert!(!is_unsubscribed().await);
+            drop(typed);
+            sleep(Duration::from_millis(50)).await;
+            assert!(is_unsubscribed().await);
+        }
+    }
+
+    mod data_stream {
+        use std::time::Duration;
+
+        use futures_util::StreamExt;
+        use serde_json::json;
+        use tokio::time::{sleep, timeout};
+
+        use crate::{DataStreamError, DeliveryPolicy, MockFixtures, MockServer, StreamApi};
+        use crate::schema::{STREAM_TICK_PRICES_SUBSCRIBE, StreamGetKeepAliveSubscribe, StreamGetTickPricesSubscribe};
+
+        #[tokio::test]
+        async fn stream_combinators() {
+            let ticks = (1..=3).map(|i| json!({"symbol": "EURUSD", "ask": i as f64, "bid": 1.0, "askVolume": 1, "bidVolume": 1, "high": 1.2, "low": 0.9, "level": 0, "quoteId": 1, "spreadRaw": 0.1, "spreadTable": 1.0, "timestamp": i})).collect();
+            let server = MockServer::start(MockFixtures::new().with_stream_data(STREAM_TICK_PRICES_SUBSCRIBE, ticks)).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            let stream = client.subscribe_tick_prices(StreamGetTickPricesSubscribe::default().with_symbol("EURUSD")).await.unwrap();
+            let asks: Vec<f64> = stream
+                .filter_map(|tick| async move { tick.ok().map(|tick| tick.ask) })
+                .take(3)
+                .collect()
+                .await;
+            assert_eq!(asks, vec![1.0, 2.0, 3.0]);
+        }
+
+        #[tokio::test]
+        async fn conflating_delivery_policy() {
+            let ticks = (1..=3).map(|i| json!({"symbol": "EURUSD", "ask": i as f64, "bid": 1.0, "askVolume": 1, "bidVolume": 1, "high": 1.2, "low": 0.9, "level": 0, "quoteId": 1, "spreadRaw": 0.1, "spreadTable": 1.0, "timestamp": i})).collect();
+            let server = MockServer::start(MockFixtures::new().with_stream_data(STREAM_TICK_PRICES_SUBSCRIBE, ticks)).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+            let client = client.with_delivery_policy(DeliveryPolicy::conflate_latest_by("symbol"));
+            assert_eq!(client.delivery_policy(), Some(&DeliveryPolicy::conflate_latest_by("symbol")));
+
+            let mut stream = client.subscribe_tick_prices(StreamGetTickPricesSubscribe::default().with_symbol("EURUSD")).await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+            assert_eq!(stream.next().await.unwrap().unwrap().ask, 3.0);
+        }
+
+        #[tokio::test]
+        async fn bounded_delivery_policy_cuts_off_slow_stream() {
+            let ticks = (1..=3).map(|i| json!({"symbol": "EURUSD", "ask": i as f64, "bid": 1.0, "askVolume": 1, "bidVolume": 1, "high": 1.2, "low": 0.9, "level": 0, "quoteId": 1, "spreadRaw": 0.1, "spreadTable": 1.0, "timestamp": i})).collect();
+            let fixtures = MockFixtures::new()
+                .with_stream_data(STREAM_TICK_PRICES_SUBSCRIBE, ticks)
+                .with_keep_alive_interval(Duration::from_millis(10));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+            let bounded = client.with_delivery_policy(DeliveryPolicy::Bounded(1));
+            assert_eq!(bounded.delivery_policy(), Some(&DeliveryPolicy::Bounded(1)));
+
+            let mut ticks = bounded.subscribe_tick_prices(StreamGetTickPricesSubscribe::default().with_symbol("EURUSD")).await.unwrap();
+            let mut keep_alive = client.subscribe_keep_alive(StreamGetKeepAliveSubscribe).await.unwrap();
+            // the slow consumer does not stall other data streams
+            for _ in 0..3 {
+                assert!(timeout(Duration::from_secs(1), keep_alive.next()).await.unwrap().unwrap().is_some());
+            }
+
+            assert_eq!(ticks.next().await.unwrap().unwrap().ask, 1.0);
+            assert!(matches!(ticks.next().await, Err(DataStreamError::Lagged(1))));
+            assert!(matches!(ticks.next().await, Ok(None)));
+        }
+    }
+}
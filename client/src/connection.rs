@@ -1,24 +1,34 @@
-use std::collections::HashMap;
 use std::future::Future;
-use std::pin::{Pin, pin};
-use std::sync::Arc;
-use std::task::{Context, Poll, Waker};
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
 
 use async_trait::async_trait;
-use futures_util::{SinkExt, StreamExt};
-use futures_util::stream::{SplitSink};
+use futures_util::SinkExt;
 use serde_json::Value;
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
+use tokio::sync::oneshot::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
-use tokio_tungstenite::{connect_async};
+use tokio::pin;
+use tokio::time::{Instant, timeout};
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tracing::{error, warn};
 use url::Url;
 
 use crate::schema::Request;
-use crate::listener::{listen_for_responses, ResponseHandler, Stream};
+use crate::listener::{listen_for_responses, ResponseHandler};
 use crate::message_processing::ProcessedMessage;
+use crate::connector::{connect_websocket, ConnectOptions};
+use crate::dispatcher::{Dispatcher, ResponseResult};
+use crate::interceptor::{InterceptorChain, SharedInterceptors};
+use crate::rate_limiter::{RateLimit, RateLimiter};
+use crate::recording::{WireDirection, WireTap};
+use crate::transport::{Transport, WebSocketTransport};
 
 /// Interface for XTB servers connectors.
 #[async_trait]
@@ -29,7 +39,9 @@ pub trait XtbConnection {
     type Response: Future<Output=Result<ProcessedMessage, BasicXtbConnectionError>>;
 
     /// Send standard command to the server.
-    async fn send_command(&mut self, command: &str, payload: Option<Value>) -> Result<Self::Response, Self::Error>;
+    ///
+    /// The method can be called concurrently. Responses are routed to their consumers by message tags.
+    async fn send_command(&self, command: &str, payload: Option<Value>) -> Result<Self::Response, Self::Error>;
 }
 
 
@@ -41,43 +53,227 @@ pub enum BasicXtbConnectionError {
     SerializationError(serde_json::Error),
     #[error("Cannot send request to the XTB server.")]
     CannotSendRequest(tokio_tungstenite::tungstenite::Error),
+    #[error("Connection to the XTB server was closed (code: {code:?}, reason: {reason:?})")]
+    ConnectionClosed { code: Option<u16>, reason: Option<String> },
+    #[error("No response for the message '{tag}' was received in time")]
+    Timeout { tag: String },
+    #[error("The command '{command}' was rejected by an interceptor: {reason}")]
+    Rejected { command: String, reason: String },
+}
+
+
+/// Number of unroutable messages kept for slow receivers.
+const UNROUTABLE_CHANNEL_CAPACITY: usize = 64;
+
+
+/// Reason why the message received from the server cannot be delivered to any `ResponsePromise`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UnroutableReason {
+    /// The message has no `customTag`
+    Untagged,
+    /// The tag was not made by this connection
+    UnknownTag { tag: String },
+    /// Nobody waits for the response anymore (e.g. the promise was dropped because of a timeout)
+    NoConsumer { tag: String },
+}
+
+
+/// Message received from the server which cannot be delivered to any `ResponsePromise`.
+#[derive(Clone, Debug)]
+pub struct UnroutableMessage {
+    /// The message
+    pub message: ProcessedMessage,
+    /// Why the message cannot be delivered
+    pub reason: UnroutableReason,
+}
+
+
+/// Code and reason of the connection close taken from the close frame.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectionClosedInfo {
+    /// The close code (if any close frame was received)
+    code: Option<u16>,
+    /// The close reason (if any close frame was received)
+    reason: Option<String>,
+}
+
+
+impl ConnectionClosedInfo {
+    /// Create new instance from the close frame received from the server.
+    fn from_frame(frame: Option<CloseFrame<'static>>) -> Self {
+        match frame {
+            Some(frame) => Self { code: Some(frame.code.into()), reason: Some(frame.reason.into_owned()) },
+            None => Self::default(),
+        }
+    }
+
+    /// Create the `BasicXtbConnectionError::ConnectionClosed` error.
+    pub(crate) fn to_error(&self) -> BasicXtbConnectionError {
+        BasicXtbConnectionError::ConnectionClosed { code: self.code, reason: self.reason.clone() }
+    }
 }
 
 
 /// Common implementation of the `XtbConnection` trait.
-pub struct BasicXtbConnection {
-    sink: SplitSink<Stream, Message>,
+///
+/// The connection is generic over the `Transport`. The default transport is the websocket
+/// connected by the `new` method.
+pub struct BasicXtbConnection<T: Transport = WebSocketTransport> {
+    /// Sink used for sending messages. Locked only for the time of sending.
+    sink: Mutex<T::Sink>,
     tag_maker: TagMaker,
-    promise_state_by_tag: Arc<Mutex<HashMap<String, Arc<Mutex<ResponsePromiseState>>>>>,
+    /// Router of responses shared with the listener
+    dispatcher: Arc<Dispatcher>,
     listener_join: JoinHandle<()>,
+    /// Pacing of outgoing requests. If `None`, requests are sent without any limit.
+    rate_limiter: Option<RateLimiter>,
+    /// Recording of the traffic. If `None`, nothing is recorded.
+    wire_tap: Option<WireTap>,
+    /// Sender of messages which cannot be routed to any promise
+    unroutable_sender: broadcast::Sender<UnroutableMessage>,
+    /// Hooks called for every request and response
+    interceptors: SharedInterceptors,
 }
 
 
 impl BasicXtbConnection {
     /// Create new instance from server url
     pub async fn new(url: Url) -> Result<Self, BasicXtbConnectionError> {
-        let host_clone = url.as_str().to_owned();
-        let (conn, _) = connect_async(url).await.map_err(|err| {
-            error!("Cannot connect to server {}: {:?}", host_clone, err);
-            BasicXtbConnectionError::CannotConnect(host_clone)
+        Self::new_with_tap(url, None).await
+    }
+
+    /// Create new instance from server url. If the `wire_tap` is set, the traffic is recorded.
+    pub async fn new_with_tap(url: Url, wire_tap: Option<WireTap>) -> Result<Self, BasicXtbConnectionError> {
+        Self::connect(url, &ConnectOptions::default(), wire_tap).await
+    }
+
+    /// Create new instance connected to the server url using the `options` (e.g. a proxy).
+    /// If the `wire_tap` is set, the traffic is recorded.
+    pub async fn connect(url: Url, options: &ConnectOptions, wire_tap: Option<WireTap>) -> Result<Self, BasicXtbConnectionError> {
+        Self::connect_with_unroutable_sender(url, options, wire_tap, broadcast::channel(UNROUTABLE_CHANNEL_CAPACITY).0).await
+    }
+
+    /// Create new instance connected to the server url using the `options`. Unroutable messages are
+    /// published by the `unroutable_sender`, so its receivers can outlive the connection.
+    pub async fn connect_with_unroutable_sender(url: Url, options: &ConnectOptions, wire_tap: Option<WireTap>, unroutable_sender: broadcast::Sender<UnroutableMessage>) -> Result<Self, BasicXtbConnectionError> {
+        let conn = connect_websocket(&url, options).await.map_err(|err| {
+            error!("Cannot connect to server {}: {:?}", url, err);
+            BasicXtbConnectionError::CannotConnect(url.to_string())
         })?;
+        Ok(Self::from_transport_with_unroutable_sender(conn, wire_tap, unroutable_sender))
+    }
+}
+
 
-        let (sink, stream) = conn.split();
-        let lookup = Arc::new(Mutex::new(HashMap::new()));
-        let listener_join = listen_for_responses(stream, BasicConnectionResponseHandler(lookup.clone()));
-        let instance = Self {
-            sink,
+impl<T: Transport> BasicXtbConnection<T> {
+    /// Create new instance communicating through the `transport`.
+    pub fn from_transport(transport: T) -> Self {
+        Self::from_transport_with_tap(transport, None)
+    }
+
+    /// Create new instance communicating through the `transport`. If the `wire_tap` is set,
+    /// the traffic is recorded.
+    pub fn from_transport_with_tap(transport: T, wire_tap: Option<WireTap>) -> Self {
+        Self::from_transport_with_unroutable_sender(transport, wire_tap, broadcast::channel(UNROUTABLE_CHANNEL_CAPACITY).0)
+    }
+
+    /// Create new instance communicating through the `transport`. Unroutable messages are
+    /// published by the `unroutable_sender`.
+    pub fn from_transport_with_unroutable_sender(transport: T, wire_tap: Option<WireTap>, unroutable_sender: broadcast::Sender<UnroutableMessage>) -> Self {
+        let (sink, stream) = transport.split();
+        let dispatcher = Arc::new(Dispatcher::default());
+        let interceptors = SharedInterceptors::default();
+        let handler = BasicConnectionResponseHandler { dispatcher: dispatcher.clone(), unroutable_sender: unroutable_sender.clone(), interceptors: interceptors.clone() };
+        let listener_join = listen_for_responses::<T>(stream, handler, wire_tap.clone());
+        Self {
+            sink: Mutex::new(sink),
             tag_maker: TagMaker::default(),
-            promise_state_by_tag: lookup,
-            listener_join
+            dispatcher,
+            listener_join,
+            rate_limiter: None,
+            wire_tap,
+            unroutable_sender,
+            interceptors,
+        }
+    }
+
+    /// Return true if the connection to the server is still alive.
+    ///
+    /// The connection is considered dead when the listening task was finished (e.g. the socket
+    /// was closed by the server or by a network failure).
+    pub fn is_connected(&self) -> bool {
+        !self.listener_join.is_finished()
+    }
+
+    /// Set the limit for outgoing requests. If the limit is reached, the `send_command` waits until
+    /// the request can be sent. The `None` value turns the limiter off.
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+        self.rate_limiter = rate_limit.map(RateLimiter::new);
+    }
+
+    /// Set the interceptors called for every request sent and every response received. The
+    /// previous chain is replaced.
+    pub fn set_interceptors(&self, interceptors: InterceptorChain) {
+        self.interceptors.set(interceptors);
+    }
+
+    /// Return number of requests waiting for their responses (including requests waiting for
+    /// the rate limiter).
+    pub fn pending_requests(&self) -> usize {
+        self.dispatcher.pending()
+    }
+
+    /// Return number of responses which were received when nobody waited for them (e.g. the
+    /// response promise was dropped because of a timeout).
+    pub fn late_responses(&self) -> u64 {
+        self.dispatcher.late_responses()
+    }
+
+    /// Return receiver of messages which cannot be routed to any `ResponsePromise` (e.g. untagged
+    /// messages or responses received after a timeout), together with the reason.
+    ///
+    /// The receiver gets only messages received after it was created. If the receiver is too slow,
+    /// the oldest messages are dropped and the receiver gets the `Lagged` error.
+    pub fn unroutable_messages(&self) -> broadcast::Receiver<UnroutableMessage> {
+        self.unroutable_sender.subscribe()
+    }
+
+    /// Return the sender publishing messages which cannot be routed to any `ResponsePromise`.
+    pub(crate) fn unroutable_sender(&self) -> &broadcast::Sender<UnroutableMessage> {
+        &self.unroutable_sender
+    }
+
+    /// Wait until all pending requests get their responses, but at most for the `duration`.
+    ///
+    /// Return true if no request is pending, false if the time limit was reached.
+    pub async fn wait_for_pending_requests(&self, duration: Duration) -> bool {
+        let all_done = async {
+            loop {
+                let decreased = self.dispatcher.pending_decreased();
+                pin!(decreased);
+                decreased.as_mut().enable();
+                if self.pending_requests() == 0 {
+                    break;
+                }
+                decreased.await;
+            }
         };
-        Ok(instance)
+        timeout(duration, all_done).await.is_ok()
+    }
+
+    /// Send the close frame with the normal close code to the server.
+    ///
+    /// The server is expected to close the connection. No command can be sent after this call.
+    pub async fn close(&self) -> Result<(), BasicXtbConnectionError> {
+        let message = Message::Close(Some(CloseFrame { code: CloseCode::Normal, reason: "".into() }));
+        self.sink.lock().await.send(message).await.map_err(BasicXtbConnectionError::CannotSendRequest)
     }
 
     /// Build a request from command and payload.
-    /// Return request and its tag.
-    fn build_request(&mut self, command: &str, mut payload: Option<Value>) -> (Request, String) {
-        let tag = self.tag_maker.next();
+    /// Return request, its tag number and its tag.
+    fn build_request(&self, command: &str, mut payload: Option<Value>) -> (Request, u64, String) {
+        let tag_number = self.tag_maker.next();
+        let tag = format_tag(tag_number);
 
         if let Some(p) = &payload {
             if p.is_null() {
@@ -89,34 +285,48 @@ impl BasicXtbConnection {
             .with_command(command)
             .with_maybe_arguments(payload)
             .with_custom_tag(&tag);
-        (r, tag)
+        (r, tag_number, tag)
     }
 }
 
 
 
 #[async_trait]
-impl XtbConnection for BasicXtbConnection {
+impl<T: Transport> XtbConnection for BasicXtbConnection<T> {
 
     type Error = BasicXtbConnectionError;
 
     type Response = ResponsePromise;
 
-    async fn send_command(&mut self, command: &str, payload: Option<Value>) -> Result<Self::Response, Self::Error> {
-        let (request, tag) = self.build_request(command, payload);
+    async fn send_command(&self, command: &str, payload: Option<Value>) -> Result<Self::Response, Self::Error> {
+        let (mut request, tag_number, tag) = self.build_request(command, payload);
+        self.interceptors.get().before_send(&mut request).map_err(|rejection| {
+            BasicXtbConnectionError::Rejected { command: command.to_owned(), reason: rejection.reason }
+        })?;
         let request_json = serde_json::to_string(&request).map_err(BasicXtbConnectionError::SerializationError)?;
         let message = Message::Text(request_json);
 
-        let (promise, state) = ResponsePromise::new();
-        self.promise_state_by_tag.lock().await.insert(tag, state);
-        self.sink.send(message).await.map_err(BasicXtbConnectionError::CannotSendRequest)?;
+        // The request is registered before it waits for the rate limiter, so requests on a closed
+        // connection fail without taking a token and waiting requests are counted as pending.
+        let receiver = self.dispatcher.register(tag_number).map_err(|info| info.to_error())?;
+        // the promise deregisters its tag when it is dropped (e.g. when the message cannot be sent)
+        let promise = ResponsePromise::registered(tag, tag_number, &self.dispatcher, receiver);
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+        // only requests really sent are recorded
+        let recorded = self.wire_tap.as_ref().map(|tap| (tap, message.clone(), Instant::now()));
+        self.sink.lock().await.send(message).await.map_err(BasicXtbConnectionError::CannotSendRequest)?;
+        if let Some((tap, message, sent_at)) = recorded {
+            tap.record_at(WireDirection::Outgoing, &message, sent_at);
+        }
 
         Ok(promise)
     }
 }
 
 
-impl Drop for BasicXtbConnection {
+impl<T: Transport> Drop for BasicXtbConnection<T> {
     fn drop(&mut self) {
         // Stop the listening task
         self.listener_join.abort();
@@ -124,57 +334,61 @@ impl Drop for BasicXtbConnection {
 }
 
 
-/// Internal state shared between the ResponsePromise and BasicXtbConnection instance.
-/// This state is used to deliver response to the consumer.
-#[derive(Default, Debug)]
-pub struct ResponsePromiseState {
-    /// The response.
-    ///
-    /// * `None` - the response is not ready yet.
-    /// * `Some(response)` - the response is ready to be delivered.
-    result: Option<Result<ProcessedMessage, BasicXtbConnectionError>>,
-    /// If the `ResponsePromise` was palled, the `Waker` is stored here.
-    /// When response is set and the waker is set, the waker is called.
-    waker: Option<Waker>,
+/// Handle messages delivered by XTB server
+struct BasicConnectionResponseHandler {
+    /// Router of responses to their promises
+    dispatcher: Arc<Dispatcher>,
+    /// Sender of messages which cannot be routed
+    unroutable_sender: broadcast::Sender<UnroutableMessage>,
+    /// Hooks called for every response
+    interceptors: SharedInterceptors,
 }
 
 
-impl ResponsePromiseState {
-    /// Set response. If a waker is set in the state, it is notified.
-    pub fn set_result(&mut self, result: Result<ProcessedMessage, BasicXtbConnectionError>) {
-        self.result = Some(result);
-        if let Some(waker) = self.waker.take() {
-            waker.wake();
-        }
+impl BasicConnectionResponseHandler {
+    /// Pass the message to receivers of unroutable messages.
+    fn report_unroutable(&self, message: ProcessedMessage, reason: UnroutableReason) {
+        warn!("Response cannot be routed ({:?}): {:?}", reason, message);
+        // error means no receiver is listening which is ok
+        let _ = self.unroutable_sender.send(UnroutableMessage { message, reason });
     }
 }
 
-
-/// Handle messages delivered by XTB server
-struct BasicConnectionResponseHandler(Arc<Mutex<HashMap<String, Arc<Mutex<ResponsePromiseState>>>>>);
-
 #[async_trait]
 impl ResponseHandler for BasicConnectionResponseHandler {
     async fn handle_response(&self, response: ProcessedMessage) {
+        self.interceptors.get().after_receive(&response);
+
         let maybe_tag = match &response {
             ProcessedMessage::Response(resp) => resp.custom_tag.as_ref(),
             ProcessedMessage::ErrorResponse(resp) => resp.custom_tag.as_ref(),
         };
 
-        // if there is no tag, continue (the message cannot be routed to consumer)
-        let tag = match maybe_tag {
-            Some(t) => t,
-            _ => {
-                warn!("Response has no tag and cannot be routed: {:?}", response);
-                return;
-            }
+        // if there is no tag made by this connection, the message cannot be routed to consumer
+        let tag_number = match maybe_tag {
+            None => return self.report_unroutable(response, UnroutableReason::Untagged),
+            Some(tag) => match parse_tag(tag) {
+                Some(number) => number,
+                None => {
+                    let reason = UnroutableReason::UnknownTag { tag: tag.clone() };
+                    return self.report_unroutable(response, reason);
+                }
+            },
         };
 
         // try to deliver message to its consumer
-        if let Some(state) = self.0.lock().await.remove(tag) {
-            state.lock().await.set_result(Ok(response));
+        if let Err(response) = self.dispatcher.dispatch(tag_number, response) {
+            self.report_unroutable(response, UnroutableReason::NoConsumer { tag: format_tag(tag_number) });
         }
     }
+
+    async fn handle_close(&self, frame: Option<CloseFrame<'static>>) {
+        let info = ConnectionClosedInfo::from_frame(frame);
+        warn!("Connection was closed (code: {:?}, reason: {:?})", info.code, info.reason);
+
+        // fail all pending promises, no response can be delivered anymore
+        self.dispatcher.close(info);
+    }
 }
 
 
@@ -182,10 +396,28 @@ impl ResponseHandler for BasicConnectionResponseHandler {
 ///
 /// Implements the `Future` trait and when the future is awaited, it is resolved by response
 /// returned from a server. The response is type of `Result<Response, ErrorResponse>`.
+///
+/// When the promise is dropped before the response is delivered (e.g. it is cancelled by the
+/// `tokio::select!`), its tag is removed from the connection and the response received later is
+/// counted as the late response.
 #[derive(Debug)]
 pub struct ResponsePromise {
-    /// Shared internal state. The second "point" is in the source connection.
-    state: Arc<Mutex<ResponsePromiseState>>,
+    /// Receiver of the response
+    receiver: Receiver<ResponseResult>,
+    /// Tag of the request the promise waits for (if the promise is registered in a connection).
+    tag: Option<String>,
+    /// Registration in the connection. It is `None` when the response was delivered.
+    registration: Option<PromiseRegistration>,
+}
+
+
+/// Registration of the `ResponsePromise` in the dispatcher of the connection.
+#[derive(Debug)]
+struct PromiseRegistration {
+    /// Numeric value of the tag
+    tag_number: u64,
+    /// The dispatcher the promise is registered in
+    dispatcher: Weak<Dispatcher>,
 }
 
 
@@ -193,11 +425,49 @@ impl ResponsePromise {
     /// Create new instance and return tuple:
     ///
     /// 1. instance of `Self`
-    /// 2. thread safe `ResponsePromiseState` for response delivery.
-    pub fn new() -> (Self, Arc<Mutex<ResponsePromiseState>>) {
-        let state = ResponsePromiseState::default();
-        let wrapped_state = Arc::new(Mutex::new(state));
-        (Self { state: wrapped_state.clone() }, wrapped_state)
+    /// 2. sender used for the response delivery.
+    pub fn new() -> (Self, Sender<Result<ProcessedMessage, BasicXtbConnectionError>>) {
+        let (sender, receiver) = channel();
+        (Self { receiver, tag: None, registration: None }, sender)
+    }
+
+    /// Create new instance waiting for response to the message tagged by `tag`. The response is
+    /// delivered through the `receiver` registered in the `dispatcher`.
+    fn registered(tag: String, tag_number: u64, dispatcher: &Arc<Dispatcher>, receiver: Receiver<ResponseResult>) -> Self {
+        let registration = PromiseRegistration { tag_number, dispatcher: Arc::downgrade(dispatcher) };
+        Self { receiver, tag: Some(tag), registration: Some(registration) }
+    }
+
+    /// Tag of the request the promise waits for.
+    ///
+    /// Return `None` if the promise was not created by a connection.
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    /// Wait for the response at most for the `duration`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ProcessedMessage)` - the response was delivered in time.
+    /// * `Err(BasicXtbConnectionError::Timeout)` - no response was delivered in time. The tag is
+    ///   removed from the connection, so a late response is discarded (and counted).
+    /// * `Err(BasicXtbConnectionError)` - any other error.
+    pub async fn with_timeout(self, duration: Duration) -> Result<ProcessedMessage, BasicXtbConnectionError> {
+        let tag = self.tag.clone().unwrap_or_default();
+        // the promise is dropped on timeout, so its tag is deregistered
+        timeout(duration, self).await.unwrap_or(Err(BasicXtbConnectionError::Timeout { tag }))
+    }
+}
+
+
+impl Drop for ResponsePromise {
+    /// Remove the tag from the connection, so the connection does not wait for the response forever.
+    fn drop(&mut self) {
+        let Some(registration) = self.registration.take() else { return };
+        if let Some(dispatcher) = registration.dispatcher.upgrade() {
+            dispatcher.deregister(registration.tag_number);
+        }
     }
 }
 
@@ -205,54 +475,64 @@ impl ResponsePromise {
 impl Future for ResponsePromise {
     type Output = Result<ProcessedMessage, BasicXtbConnectionError>;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        // Try to get the lock
-        if let Poll::Ready(mut guard) = pin!(self.state.lock()).poll(cx) {
-            // If response is set, return it as `Poll::Ready`
-            if let Some(response) = guard.result.take() {
-                return Poll::Ready(response);
-            }
-            // If response is not ready yet, register the waker.
-            guard.waker = Some(cx.waker().clone());
-        }
-        // Wait until response is ready
-        Poll::Pending
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = ready!(Pin::new(&mut self.receiver).poll(cx));
+        // the response was delivered and the tag was removed by the dispatcher
+        self.registration = None;
+        // the sender is dropped without any response only when the connection is dropped
+        Poll::Ready(result.unwrap_or(Err(BasicXtbConnectionError::ConnectionClosed { code: None, reason: None })))
     }
 }
 
 
+/// Prefix of message tags made by the `TagMaker`.
+const TAG_PREFIX: &str = "message_";
+
+
 /// Helper struct generating message tags.
 ///
-/// It generates unique tags with prefix "message_" followed by incremented positive integer number.
-/// The increment step is 1 and the first number is 1.
+/// It generates unique tag numbers. The increment step is 1 and the first number is 1. Tags sent to
+/// the server have prefix "message_" followed by the number (see `format_tag`).
 ///
 /// Example of series is: "message_1", "message_2", "message_3", ...
+///
+/// The maker can be shared between threads.
 #[derive(Default, Debug)]
-struct TagMaker(u64);
+struct TagMaker(AtomicU64);
 
 
 impl TagMaker {
-    fn next(&mut self) -> String {
-        self.0 += 1;
-        format!("message_{}", self.0)
+    fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
     }
 }
 
 
+/// Make the tag sent to the server from the tag number.
+fn format_tag(number: u64) -> String {
+    format!("{TAG_PREFIX}{number}")
+}
+
+
+/// Parse the tag number from the tag made by the `format_tag`. Return `None` for foreign tags.
+fn parse_tag(tag: &str) -> Option<u64> {
+    tag.strip_prefix(TAG_PREFIX)?.parse().ok()
+}
+
+
 #[cfg(test)]
 mod tests {
     mod response_promise {
-        use std::sync::Arc;
         use std::time::Duration;
 
         use rstest::*;
         use serde_json::to_value;
         use tokio::spawn;
-        use tokio::sync::Mutex;
+        use tokio::sync::oneshot::Sender;
         use tokio::time::sleep;
 
         use crate::schema::Response;
-        use crate::connection::ResponsePromiseState;
+        use crate::connection::BasicXtbConnectionError;
         use crate::message_processing::ProcessedMessage;
         use crate::ResponsePromise;
 
@@ -266,33 +546,247 @@ mod tests {
         async fn deliver_data(#[case] delay_ms: u64) {
             let (instance, target) = ResponsePromise::new();
             spawn(write_data(target, delay_ms));
-            let result = instance.await;
+            match instance.await {
+                Ok(ProcessedMessage::Response(response)) => assert_eq!(response.return_data, Some(to_value(42).unwrap())),
+                other => panic!("Expected response, but {:?}", other),
+            }
         }
 
-        async fn write_data(target: Arc<Mutex<ResponsePromiseState>>, delay: u64) {
+        async fn write_data(target: Sender<Result<ProcessedMessage, BasicXtbConnectionError>>, delay: u64) {
             if delay > 0 {
                 sleep(Duration::from_millis(delay)).await;
             }
-            let mut lock = target.lock().await;
             let mut response = Response::default();
             response.return_data = Some(to_value(42).unwrap());
-            lock.set_result(Ok(ProcessedMessage::Response(response)));
+            target.send(Ok(ProcessedMessage::Response(response))).unwrap();
+        }
+    }
+
+    mod response_handler {
+        use std::sync::Arc;
+
+        use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+        use crate::connection::{BasicConnectionResponseHandler, BasicXtbConnectionError};
+        use crate::dispatcher::Dispatcher;
+        use crate::listener::ResponseHandler;
+        use crate::ResponsePromise;
+
+        #[tokio::test]
+        async fn close_fails_pending_promises() {
+            let dispatcher = Arc::new(Dispatcher::default());
+            let (unroutable_sender, _) = tokio::sync::broadcast::channel(1);
+            let handler = BasicConnectionResponseHandler { dispatcher: dispatcher.clone(), unroutable_sender, interceptors: Default::default() };
+            let first = ResponsePromise::registered("message_1".to_owned(), 1, &dispatcher, dispatcher.register(1).unwrap());
+            let second = ResponsePromise::registered("message_2".to_owned(), 2, &dispatcher, dispatcher.register(2).unwrap());
+
+            let frame = CloseFrame { code: CloseCode::Away, reason: "bye".into() };
+            handler.handle_close(Some(frame)).await;
+
+            for promise in [first, second] {
+                match promise.await {
+                    Err(BasicXtbConnectionError::ConnectionClosed { code, reason }) => {
+                        assert_eq!(code, Some(1001));
+                        assert_eq!(reason.as_deref(), Some("bye"));
+                    }
+                    other => panic!("Expected ConnectionClosed, but {:?}", other),
+                }
+            }
+            assert_eq!(dispatcher.pending(), 0);
+            assert!(dispatcher.register(3).is_err());
+        }
+    }
+
+    mod response_promise_timeout {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use crate::connection::BasicXtbConnectionError;
+        use crate::dispatcher::Dispatcher;
+        use crate::message_processing::ProcessedMessage;
+        use crate::schema::Response;
+        use crate::ResponsePromise;
+
+        #[tokio::test]
+        async fn timeout_removes_tag() {
+            let dispatcher = Arc::new(Dispatcher::default());
+            let promise = ResponsePromise::registered("message_1".to_owned(), 1, &dispatcher, dispatcher.register(1).unwrap());
+
+            match promise.with_timeout(Duration::from_millis(10)).await {
+                Err(BasicXtbConnectionError::Timeout { tag }) => assert_eq!(tag, "message_1"),
+                other => panic!("Expected Timeout, but {:?}", other),
+            }
+            assert_eq!(dispatcher.pending(), 0);
+        }
+
+        #[tokio::test]
+        async fn response_in_time() {
+            let dispatcher = Arc::new(Dispatcher::default());
+            let promise = ResponsePromise::registered("message_1".to_owned(), 1, &dispatcher, dispatcher.register(1).unwrap());
+            dispatcher.dispatch(1, ProcessedMessage::Response(Response::default())).unwrap();
+
+            let result = promise.with_timeout(Duration::from_millis(100)).await;
+            assert!(result.is_ok());
+        }
+    }
+
+    mod channel_transport {
+        use std::time::Duration;
+
+        use serde_json::{from_str, json, Value};
+        use tokio::time::timeout;
+        use tokio_tungstenite::tungstenite::Message;
+
+        use crate::{BasicXtbConnection, BasicXtbConnectionError, ChannelTransport, RateLimit, UnroutableReason, XtbConnection};
+        use crate::message_processing::ProcessedMessage;
+
+        #[tokio::test]
+        async fn request_response_round_trip() {
+            let (client_end, mut server_end) = ChannelTransport::pair();
+            let connection = BasicXtbConnection::from_transport(client_end);
+
+            let promise = connection.send_command("getVersion", None).await.unwrap();
+            let request: Value = from_str(&server_end.recv().await.unwrap().to_string()).unwrap();
+            assert_eq!(request["command"], "getVersion");
+            assert_eq!(request["customTag"], "message_1");
+
+            let response = json!({"status": true, "returnData": {"version": "2.5.0"}, "customTag": "message_1"});
+            server_end.send(Message::text(response.to_string())).unwrap();
+            let response = promise.await.unwrap().unwrap();
+            assert_eq!(response.return_data, Some(json!({"version": "2.5.0"})));
+        }
+
+        #[tokio::test]
+        async fn dropped_promise_deregisters_tag() {
+            let (client_end, mut server_end) = ChannelTransport::pair();
+            let connection = BasicXtbConnection::from_transport(client_end);
+
+            let promise = connection.send_command("getVersion", None).await.unwrap();
+            assert_eq!(connection.pending_requests(), 1);
+            drop(promise);
+            assert_eq!(connection.pending_requests(), 0);
+
+            server_end.recv().await.unwrap();
+            let response = json!({"status": true, "returnData": {"version": "2.5.0"}, "customTag": "message_1"});
+            server_end.send(Message::text(response.to_string())).unwrap();
+            let pinged = connection.send_command("ping", None).await.unwrap();
+            server_end.recv().await.unwrap();
+            server_end.send(Message::text(json!({"status": true, "customTag": "message_2"}).to_string())).unwrap();
+            pinged.await.unwrap().unwrap();
+            assert_eq!(connection.late_responses(), 1);
+        }
+
+        #[tokio::test]
+        async fn wait_for_pending_requests() {
+            let (client_end, mut server_end) = ChannelTransport::pair();
+            let connection = BasicXtbConnection::from_transport(client_end);
+
+            let promise = connection.send_command("getVersion", None).await.unwrap();
+            server_end.recv().await.unwrap();
+            assert!(!connection.wait_for_pending_requests(std::time::Duration::from_millis(10)).await);
+
+            let response = json!({"status": true, "returnData": {"version": "2.5.0"}, "customTag": "message_1"});
+            let (done, _) = tokio::join!(
+                connection.wait_for_pending_requests(std::time::Duration::from_secs(5)),
+                async {
+                    server_end.send(Message::text(response.to_string())).unwrap();
+                    promise.await.unwrap().unwrap()
+                },
+            );
+            assert!(done);
+        }
+
+        #[tokio::test]
+        async fn cancelled_by_select() {
+            let (client_end, _server_end) = ChannelTransport::pair();
+            let connection = BasicXtbConnection::from_transport(client_end);
+
+            let promise = connection.send_command("getVersion", None).await.unwrap();
+            tokio::select! {
+                _ = promise => panic!("No response was sent"),
+                _ = tokio::time::sleep(std::time::Duration::from_millis(10)) => (),
+            }
+            assert_eq!(connection.pending_requests(), 0);
+        }
+
+        #[tokio::test]
+        async fn unroutable_messages() {
+            let (client_end, mut server_end) = ChannelTransport::pair();
+            let connection = BasicXtbConnection::from_transport(client_end);
+            let mut unroutable = connection.unroutable_messages();
+
+            drop(connection.send_command("tradeTransaction", None).await.unwrap());
+            server_end.recv().await.unwrap();
+            for tag in [json!(null), json!("foreign_1"), json!("message_1")] {
+                let response = json!({"status": true, "returnData": {"order": 42}, "customTag": tag});
+                server_end.send(Message::text(response.to_string())).unwrap();
+            }
+
+            let expected = [
+                UnroutableReason::Untagged,
+                UnroutableReason::UnknownTag { tag: "foreign_1".to_owned() },
+                UnroutableReason::NoConsumer { tag: "message_1".to_owned() },
+            ];
+            for reason in expected {
+                let message = unroutable.recv().await.unwrap();
+                assert_eq!(message.reason, reason);
+                match message.message {
+                    ProcessedMessage::Response(response) => assert_eq!(response.return_data, Some(json!({"order": 42}))),
+                    other => panic!("Expected response, but {:?}", other),
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn dropped_peer_closes_connection() {
+            let (client_end, server_end) = ChannelTransport::pair();
+            let connection = BasicXtbConnection::from_transport(client_end);
+
+            let promise = connection.send_command("getVersion", None).await.unwrap();
+            drop(server_end);
+
+            assert!(matches!(promise.await, Err(BasicXtbConnectionError::ConnectionClosed { .. })));
+            assert!(!connection.is_connected());
+        }
+
+        #[tokio::test]
+        async fn closed_connection_does_not_wait_for_rate_limiter() {
+            let (client_end, server_end) = ChannelTransport::pair();
+            let mut connection = BasicXtbConnection::from_transport(client_end);
+            let rate_limit = RateLimit::default().with_burst_size(1u32).with_min_interval(Duration::from_secs(10)).with_refill_interval(Duration::from_secs(10));
+            connection.set_rate_limit(Some(rate_limit));
+
+            // the only token is taken
+            let promise = connection.send_command("getVersion", None).await.unwrap();
+            drop(server_end);
+            assert!(matches!(promise.await, Err(BasicXtbConnectionError::ConnectionClosed { .. })));
+
+            let result = timeout(Duration::from_millis(100), connection.send_command("ping", None)).await;
+            assert!(matches!(result, Ok(Err(BasicXtbConnectionError::ConnectionClosed { .. }))));
         }
     }
 
     mod tag_maker {
-        use crate::connection::TagMaker;
+        use crate::connection::{format_tag, parse_tag, TagMaker};
 
         #[test]
         fn make_series() {
-            let mut maker = TagMaker::default();
+            let maker = TagMaker::default();
 
-            let tag = maker.next();
+            let tag = format_tag(maker.next());
             assert_eq!(tag, "message_1");
-            let tag = maker.next();
+            let tag = format_tag(maker.next());
             assert_eq!(tag, "message_2");
-            let tag = maker.next();
+            let tag = format_tag(maker.next());
             assert_eq!(tag, "message_3");
         }
+
+        #[test]
+        fn parse_tags() {
+            assert_eq!(parse_tag("message_42"), Some(42));
+            assert_eq!(parse_tag("message_"), None);
+            assert_eq!(parse_tag("foreign_1"), None);
+        }
     }
 }
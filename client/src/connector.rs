@@ -0,0 +1,415 @@
+use std::io;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_socks::tcp::Socks5Stream;
+use tokio_tungstenite::{client_async_tls_with_config, Connector};
+use tracing::debug;
+use url::Url;
+
+use crate::tls::{TlsError, TlsOptions};
+use crate::transport::{TransportError, WebSocketTransport};
+
+
+/// Kind of the proxy server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// HTTP proxy tunneling the connection by the `CONNECT` method
+    HttpConnect,
+    /// SOCKS5 proxy
+    Socks5,
+}
+
+
+/// Proxy server used for connections to the XTB servers.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Proxy {
+    /// Kind of the proxy
+    kind: ProxyKind,
+    /// Address of the proxy in form `host:port`
+    address: String,
+    /// User name and password. If `None`, no authentication is done.
+    credentials: Option<(String, String)>,
+}
+
+
+impl Proxy {
+    /// Create HTTP CONNECT proxy. The `address` is in form `host:port`.
+    pub fn http(address: &str) -> Self {
+        Self { kind: ProxyKind::HttpConnect, address: address.to_owned(), credentials: None }
+    }
+
+    /// Create SOCKS5 proxy. The `address` is in form `host:port`.
+    pub fn socks5(address: &str) -> Self {
+        Self { kind: ProxyKind::Socks5, address: address.to_owned(), credentials: None }
+    }
+
+    /// Authenticate to the proxy. The HTTP proxy uses the basic authentication, the SOCKS5 proxy
+    /// uses the username/password authentication.
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some((user.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Kind of the proxy.
+    pub fn kind(&self) -> ProxyKind {
+        self.kind
+    }
+
+    /// Address of the proxy.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Open the tunnel to the `target` (in form `host:port`) through the proxy.
+    async fn open_tunnel(&self, target: &str) -> Result<TcpStream, ConnectorError> {
+        debug!("Connecting to {} through the proxy {}", target, self.address);
+        match self.kind {
+            ProxyKind::HttpConnect => self.open_http_tunnel(target).await,
+            ProxyKind::Socks5 => self.open_socks5_tunnel(target).await,
+        }
+    }
+
+    /// Open the tunnel by the HTTP `CONNECT` method.
+    async fn open_http_tunnel(&self, target: &str) -> Result<TcpStream, ConnectorError> {
+        let mut stream = TcpStream::connect(&self.address).await?;
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((user, password)) = &self.credentials {
+            let token = BASE64.encode(format!("{user}:{password}"));
+            request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read the response head. The reader is not used after the head, so no tunneled data
+        // can be lost in its buffer (the server does not send anything before the handshake).
+        let mut reader = BufReader::new(&mut stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line).await?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 || line == "\r\n" || line == "\n" {
+                break;
+            }
+        }
+        // any 2xx reply means the tunnel is established (RFC 9110, section 9.3.6)
+        let status = status_line.split_whitespace().nth(1).and_then(|status| status.parse::<u16>().ok());
+        if !matches!(status, Some(200..=299)) {
+            return Err(ConnectorError::ProxyRejected(status_line.trim().to_owned()));
+        }
+        Ok(stream)
+    }
+
+    /// Open the tunnel by the SOCKS5 proxy.
+    async fn open_socks5_tunnel(&self, target: &str) -> Result<TcpStream, ConnectorError> {
+        let stream = match &self.credentials {
+            Some((user, password)) => Socks5Stream::connect_with_password(self.address.as_str(), target, user, password).await?,
+            None => Socks5Stream::connect(self.address.as_str(), target).await?,
+        };
+        Ok(stream.into_inner())
+    }
+}
+
+
+impl std::fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the password is not printed
+        f.debug_struct("Proxy")
+            .field("kind", &self.kind)
+            .field("address", &self.address)
+            .field("user", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+
+/// Default time limit for the handshake with the proxy
+pub const DEFAULT_PROXY_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// Options used for opening websocket connections to the XTB servers.
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// Proxy the connections are tunneled through. If `None`, connections are direct.
+    proxy: Option<Proxy>,
+    /// TLS configuration. If `None`, the native root certificates are used.
+    tls: Option<TlsOptions>,
+    /// Time limit for opening the tunnel through the proxy
+    proxy_handshake_timeout: Duration,
+}
+
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self { proxy: None, tls: None, proxy_handshake_timeout: DEFAULT_PROXY_HANDSHAKE_TIMEOUT }
+    }
+}
+
+
+impl ConnectOptions {
+    /// Tunnel connections through the proxy.
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// The proxy the connections are tunneled through.
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    /// Use the TLS configuration for secured connections.
+    pub fn with_tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// The TLS configuration for secured connections.
+    pub fn tls(&self) -> Option<&TlsOptions> {
+        self.tls.as_ref()
+    }
+
+    /// Limit the time of opening the tunnel through the proxy (the TCP connection to the proxy
+    /// and the `CONNECT` or SOCKS5 handshake).
+    pub fn with_proxy_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.proxy_handshake_timeout = timeout;
+        self
+    }
+
+    /// The time limit of opening the tunnel through the proxy.
+    pub fn proxy_handshake_timeout(&self) -> Duration {
+        self.proxy_handshake_timeout
+    }
+}
+
+
+#[derive(Debug, Error)]
+pub enum ConnectorError {
+    #[error("The url has no host or port: {0}")]
+    InvalidUrl(String),
+    #[error("Cannot open the socket: {0}")]
+    Io(#[from] io::Error),
+    #[error("The proxy rejected the connection: {0}")]
+    ProxyRejected(String),
+    #[error("The proxy did not open the tunnel within {0:?}")]
+    ProxyTimeout(Duration),
+    #[error("The SOCKS5 proxy failed: {0}")]
+    Socks(#[from] tokio_socks::Error),
+    #[error("Invalid TLS configuration: {0}")]
+    Tls(#[from] TlsError),
+    #[error("The websocket handshake failed: {0}")]
+    Handshake(#[from] TransportError),
+}
+
+
+/// Open the websocket connected to the `url` using the `options`.
+pub async fn connect_websocket(url: &Url, options: &ConnectOptions) -> Result<WebSocketTransport, ConnectorError> {
+    let host = url.host_str().ok_or_else(|| ConnectorError::InvalidUrl(url.to_string()))?;
+    let port = url.port_or_known_default().ok_or_else(|| ConnectorError::InvalidUrl(url.to_string()))?;
+    let target = format!("{host}:{port}");
+
+    let stream = match &options.proxy {
+        Some(proxy) => timeout(options.proxy_handshake_timeout, proxy.open_tunnel(&target))
+            .await
+            .map_err(|_| ConnectorError::ProxyTimeout(options.proxy_handshake_timeout))??,
+        None => TcpStream::connect(&target).await?,
+    };
+    let connector = match &options.tls {
+        Some(tls) => Some(Connector::Rustls(tls.client_config()?)),
+        None => None,
+    };
+    let (socket, _) = client_async_tls_with_config(url.as_str(), stream, None, connector).await?;
+    Ok(socket)
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod proxy {
+        use std::net::SocketAddr;
+        use std::time::Duration;
+
+        use rstest::rstest;
+        use serde_json::json;
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, copy_bidirectional};
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio::spawn;
+
+        use crate::{connect_websocket, ConnectOptions, ConnectorError, MockFixtures, MockServer, Proxy, ProxyKind, RequestResponseApi};
+        use crate::schema::{COMMAND_GET_VERSION, GetVersionRequest};
+
+        /// Start HTTP CONNECT proxy stand-in. Only the `expected_auth` header value is accepted.
+        async fn start_http_proxy(expected_auth: Option<&'static str>) -> SocketAddr {
+            start_http_proxy_with_reply(expected_auth, "HTTP/1.1 200 Connection established").await
+        }
+
+        /// Start HTTP CONNECT proxy stand-in answering the established tunnel by the `reply` status line.
+        async fn start_http_proxy_with_reply(expected_auth: Option<&'static str>, reply: &'static str) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            spawn(async move {
+                loop {
+                    let (mut client, _) = listener.accept().await.unwrap();
+                    spawn(async move {
+                        let mut reader = BufReader::new(&mut client);
+                        let mut request_line = String::new();
+                        reader.read_line(&mut request_line).await.unwrap();
+                        let mut auth = None;
+                        loop {
+                            let mut line = String::new();
+                            reader.read_line(&mut line).await.unwrap();
+                            if line == "\r\n" {
+                                break;
+                            }
+                            if let Some(value) = line.strip_prefix("Proxy-Authorization: ") {
+                                auth = Some(value.trim().to_owned());
+                            }
+                        }
+                        if auth.as_deref() != expected_auth {
+                            client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await.unwrap();
+                            return;
+                        }
+                        let target = request_line.split_whitespace().nth(1).unwrap().to_owned();
+                        let mut upstream = TcpStream::connect(target).await.unwrap();
+                        client.write_all(format!("{reply}\r\n\r\n").as_bytes()).await.unwrap();
+                        let _ = copy_bidirectional(&mut client, &mut upstream).await;
+                    });
+                }
+            });
+            addr
+        }
+
+        /// Start SOCKS5 proxy stand-in accepting only the user `user` with the password `password`.
+        async fn start_socks5_proxy() -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            spawn(async move {
+                loop {
+                    let (mut client, _) = listener.accept().await.unwrap();
+                    spawn(async move {
+                        // greeting - select the username/password method
+                        let mut head = [0u8; 2];
+                        client.read_exact(&mut head).await.unwrap();
+                        let mut methods = vec![0u8; head[1] as usize];
+                        client.read_exact(&mut methods).await.unwrap();
+                        client.write_all(&[5, 2]).await.unwrap();
+                        // authentication
+                        let mut version = [0u8; 2];
+                        client.read_exact(&mut version).await.unwrap();
+                        let mut user = vec![0u8; version[1] as usize];
+                        client.read_exact(&mut user).await.unwrap();
+                        let password_len = client.read_u8().await.unwrap();
+                        let mut password = vec![0u8; password_len as usize];
+                        client.read_exact(&mut password).await.unwrap();
+                        if user != b"user" || password != b"password" {
+                            client.write_all(&[1, 1]).await.unwrap();
+                            return;
+                        }
+                        client.write_all(&[1, 0]).await.unwrap();
+                        // connect request
+                        let mut request = [0u8; 4];
+                        client.read_exact(&mut request).await.unwrap();
+                        let target = match request[3] {
+                            1 => {
+                                let mut ip = [0u8; 4];
+                                client.read_exact(&mut ip).await.unwrap();
+                                std::net::Ipv4Addr::from(ip).to_string()
+                            }
+                            3 => {
+                                let len = client.read_u8().await.unwrap();
+                                let mut host = vec![0u8; len as usize];
+                                client.read_exact(&mut host).await.unwrap();
+                                String::from_utf8(host).unwrap()
+                            }
+                            _ => return,
+                        };
+                        let port = client.read_u16().await.unwrap();
+                        let mut upstream = TcpStream::connect((target.as_str(), port)).await.unwrap();
+                        client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await.unwrap();
+                        let _ = copy_bidirectional(&mut client, &mut upstream).await;
+                    });
+                }
+            });
+            addr
+        }
+
+        async fn mock_server() -> MockServer {
+            MockServer::start(MockFixtures::new().with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}))).await.unwrap()
+        }
+
+        #[tokio::test]
+        async fn http_connect_with_basic_auth() {
+            let server = mock_server().await;
+            // "user:password" in base64
+            let proxy_addr = start_http_proxy(Some("Basic dXNlcjpwYXNzd29yZA==")).await;
+            let proxy = Proxy::http(&proxy_addr.to_string()).with_credentials("user", "password");
+            let client = server.client_builder().with_proxy(proxy).build("user", "password").await.unwrap();
+
+            let version = client.get_version(GetVersionRequest).await.unwrap();
+            assert_eq!(version.version, "2.5.0");
+        }
+
+        #[tokio::test]
+        async fn http_connect_rejected() {
+            let server = mock_server().await;
+            let proxy_addr = start_http_proxy(Some("Basic dXNlcjpwYXNzd29yZA==")).await;
+            let url = url::Url::parse(&server.api_url()).unwrap();
+            let options = crate::ConnectOptions::default().with_proxy(Proxy::http(&proxy_addr.to_string()));
+
+            let result = crate::connect_websocket(&url, &options).await;
+            assert!(matches!(result, Err(ConnectorError::ProxyRejected(status)) if status.contains("407")));
+        }
+
+        #[tokio::test]
+        async fn http_connect_accepts_any_2xx() {
+            let server = mock_server().await;
+            let proxy_addr = start_http_proxy_with_reply(None, "HTTP/1.0 204 No Content").await;
+            let client = server.client_builder().with_proxy(Proxy::http(&proxy_addr.to_string())).build("user", "password").await.unwrap();
+
+            let version = client.get_version(GetVersionRequest).await.unwrap();
+            assert_eq!(version.version, "2.5.0");
+        }
+
+        #[rstest]
+        #[case(ProxyKind::HttpConnect)]
+        #[case(ProxyKind::Socks5)]
+        #[tokio::test]
+        async fn silent_proxy_times_out(#[case] kind: ProxyKind) {
+            // the proxy accepts the connection, but never answers
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap().to_string();
+            let proxy = match kind {
+                ProxyKind::HttpConnect => Proxy::http(&proxy_addr),
+                ProxyKind::Socks5 => Proxy::socks5(&proxy_addr),
+            };
+            let url = url::Url::parse("ws://127.0.0.1:1").unwrap();
+            let options = ConnectOptions::default().with_proxy(proxy).with_proxy_handshake_timeout(Duration::from_millis(100));
+
+            let result = connect_websocket(&url, &options).await;
+            assert!(matches!(result, Err(ConnectorError::ProxyTimeout(_))));
+            drop(listener);
+        }
+
+        #[tokio::test]
+        async fn socks5_with_password() {
+            let server = mock_server().await;
+            let proxy_addr = start_socks5_proxy().await;
+            let proxy = Proxy::socks5(&proxy_addr.to_string()).with_credentials("user", "password");
+            let client = server.client_builder().with_proxy(proxy).build("user", "password").await.unwrap();
+
+            let version = client.get_version(GetVersionRequest).await.unwrap();
+            assert_eq!(version.version, "2.5.0");
+        }
+
+        #[test]
+        fn debug_hides_password() {
+            let proxy = Proxy::http("proxy:3128").with_credentials("user", "secret");
+            assert!(!format!("{:?}", proxy).contains("secret"));
+        }
+    }
+}
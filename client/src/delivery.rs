@@ -0,0 +1,562 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use serde_json::Value;
+
+use crate::schema::DecodedStreamMessage;
+use crate::stream_connection::{DataMessageFilter, DataStreamError};
+
+
+/// Decoded message shared by all message streams it is delivered to.
+pub(crate) type SharedMessage = Arc<DecodedStreamMessage>;
+
+
+/// Policy of the message delivery to a single message stream.
+///
+/// Each message stream has its own queue, so a slow consumer does not affect other consumers.
+/// The stream connection never waits for a consumer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeliveryPolicy {
+    /// Keep up to `capacity` messages not received yet. When the queue is full, the message stream
+    /// is cut off: the consumer receives the waiting messages, then `DataStreamError::Lagged` and
+    /// the stream ends. So the consumer never misses a message without noticing it.
+    Bounded(usize),
+    /// Keep up to `capacity` messages not received yet. When the queue is full, the oldest message
+    /// is dropped and the consumer receives `DataStreamError::Lagged`.
+    DropOldest(usize),
+    /// Keep only the newest message for each value of the `key` field of the message data (e.g.
+    /// the `symbol`). Messages without the field share the same slot. Messages are received in
+    /// the order of arrival of the first message of each key.
+    ConflateLatest { key: String },
+}
+
+
+impl DeliveryPolicy {
+    /// Keep only the newest message for each value of the `key` field of the message data.
+    pub fn conflate_latest_by(key: &str) -> Self {
+        Self::ConflateLatest { key: key.to_owned() }
+    }
+}
+
+
+/// Messages waiting for the consumer.
+enum Queue {
+    /// Messages in order of arrival
+    Fifo(VecDeque<SharedMessage>),
+    /// The newest message by the conflation key
+    Conflated {
+        /// Keys in order of arrival
+        order: VecDeque<String>,
+        /// The newest message of each key
+        latest: HashMap<String, SharedMessage>,
+    },
+}
+
+
+impl Queue {
+    /// Take the oldest waiting message.
+    fn pop(&mut self) -> Option<SharedMessage> {
+        match self {
+            Self::Fifo(messages) => messages.pop_front(),
+            Self::Conflated { order, latest } => order.pop_front().and_then(|key| latest.remove(&key)),
+        }
+    }
+}
+
+
+/// Mutable state of the `Mailbox`.
+struct MailboxState {
+    /// Messages waiting for the consumer
+    queue: Queue,
+    /// Number of messages dropped since the last receive
+    dropped: u64,
+    /// Waker of the consumer waiting for a message
+    waker: Option<Waker>,
+    /// Set when no message can be delivered anymore
+    closed: bool,
+    /// Set when the consumer is gone or the `Bounded` queue overflowed
+    detached: bool,
+    /// Set when the `Bounded` queue overflowed. The `dropped` is reported after waiting messages.
+    overflowed: bool,
+}
+
+
+/// Queue of messages delivered to a single message stream.
+pub(crate) struct Mailbox {
+    /// The delivery policy
+    policy: DeliveryPolicy,
+    /// The queue and its flags
+    state: Mutex<MailboxState>,
+}
+
+
+impl Mailbox {
+    /// Create new empty mailbox.
+    pub fn new(policy: DeliveryPolicy) -> Self {
+        let queue = match policy {
+            DeliveryPolicy::ConflateLatest { .. } => Queue::Conflated { order: VecDeque::new(), latest: HashMap::new() },
+            _ => Queue::Fifo(VecDeque::new()),
+        };
+        Self {
+            policy,
+            state: Mutex::new(MailboxState { queue, dropped: 0, waker: None, closed: false, detached: false, overflowed: false }),
+        }
+    }
+
+    /// Deliver the `message` according to the delivery policy. The call never waits.
+    ///
+    /// Return false if the consumer is gone (or the `Bounded` queue overflowed) and the message
+    /// was not delivered.
+    pub fn deliver(&self, message: SharedMessage) -> bool {
+        let mut guard = self.lock();
+        let state = &mut *guard;
+        if state.detached {
+            return false;
+        }
+        match (&self.policy, &mut state.queue) {
+            (DeliveryPolicy::Bounded(capacity), Queue::Fifo(messages)) => {
+                if messages.len() >= (*capacity).max(1) {
+                    // the message stream is cut off, its consumer is not waited for
+                    state.dropped += 1;
+                    state.overflowed = true;
+                    state.detached = true;
+                    state.closed = true;
+                    Self::wake(state);
+                    return false;
+                }
+                messages.push_back(message);
+            }
+            (DeliveryPolicy::DropOldest(capacity), Queue::Fifo(messages)) => {
+                while messages.len() >= (*capacity).max(1) {
+                    messages.pop_front();
+                    state.dropped += 1;
+                }
+                messages.push_back(message);
+            }
+            (DeliveryPolicy::ConflateLatest { key }, Queue::Conflated { order, latest }) => {
+                let key = message.value().get(key).map(|value| value.to_string()).unwrap_or_default();
+                if latest.insert(key.clone(), message).is_none() {
+                    order.push_back(key);
+                }
+            }
+            _ => unreachable!("The queue is made for the policy"),
+        }
+        Self::wake(state);
+        true
+    }
+
+    /// Receive the next message.
+    ///
+    /// The `DataStreamError::Lagged` is returned first if some messages were dropped. When the
+    /// `Bounded` queue overflowed, it is returned after the waiting messages.
+    pub fn poll_receive(&self, cx: &mut Context<'_>) -> Poll<Option<Result<SharedMessage, DataStreamError>>> {
+        let mut state = self.lock();
+        if state.dropped > 0 && !state.overflowed {
+            let dropped = std::mem::take(&mut state.dropped);
+            return Poll::Ready(Some(Err(DataStreamError::Lagged(dropped))));
+        }
+        if let Some(message) = state.queue.pop() {
+            return Poll::Ready(Some(Ok(message)));
+        }
+        if state.dropped > 0 {
+            let dropped = std::mem::take(&mut state.dropped);
+            return Poll::Ready(Some(Err(DataStreamError::Lagged(dropped))));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Mark the mailbox as closed. Waiting messages can still be received.
+    pub fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        Self::wake(&mut state);
+    }
+
+    /// Mark the consumer as gone. Waiting messages are dropped.
+    pub fn detach(&self) {
+        let mut state = self.lock();
+        state.detached = true;
+        while state.queue.pop().is_some() {}
+    }
+
+    /// Return true if no message can be delivered anymore (the consumer is gone or the `Bounded`
+    /// queue overflowed).
+    pub fn is_detached(&self) -> bool {
+        self.lock().detached
+    }
+
+    /// Wake the consumer waiting for a message.
+    fn wake(state: &mut MailboxState) {
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Lock the state. The poisoned lock is recovered, the state cannot be left inconsistent.
+    fn lock(&self) -> MutexGuard<'_, MailboxState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+
+/// Message stream registered for the delivery.
+struct Subscriber {
+    /// Messages matching the filter are delivered
+    filter: DataMessageFilter,
+    /// Queue of the message stream
+    mailbox: Arc<Mailbox>,
+}
+
+
+/// Key of the message routing: the `command` of the message and the `symbol` field of its data.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Route {
+    /// The command of the data message (e.g. `tickPrices`)
+    command: String,
+    /// The symbol the messages are scoped to. If `None`, all messages of the command match.
+    symbol: Option<String>,
+}
+
+
+impl Route {
+    /// Return the route matched by the `filter` if the filter can be indexed.
+    ///
+    /// Indexed are the `Command` filters and the `All` filters containing single `Command` filter
+    /// and at most one `FieldValue` filter of the string `symbol` field (the `Always` filters are
+    /// ignored). Other filters are tested for each message.
+    fn of_filter(filter: &DataMessageFilter) -> Option<Self> {
+        match filter {
+            DataMessageFilter::Command(command) => Some(Self { command: command.clone(), symbol: None }),
+            DataMessageFilter::All(filters) => {
+                let mut command = None;
+                let mut symbol = None;
+                for filter in filters {
+                    match filter {
+                        DataMessageFilter::Always => (),
+                        DataMessageFilter::Command(value) if command.is_none() => command = Some(value.clone()),
+                        DataMessageFilter::FieldValue { name, value: Value::String(value) } if name == "symbol" && symbol.is_none() => symbol = Some(value.clone()),
+                        _ => return None,
+                    }
+                }
+                command.map(|command| Self { command, symbol })
+            }
+            _ => None,
+        }
+    }
+}
+
+
+/// Message streams with indexed filters of one command.
+#[derive(Default)]
+struct RouteBucket {
+    /// Message streams receiving all messages of the command
+    all: Vec<Arc<Mailbox>>,
+    /// Message streams scoped to a symbol by the symbol
+    by_symbol: HashMap<String, Vec<Arc<Mailbox>>>,
+}
+
+
+impl RouteBucket {
+    /// Deliver the `message` to message streams of the command and of the `symbol`. Message streams
+    /// whose consumers are gone are forgotten on the way.
+    ///
+    /// Return number of message streams the message was delivered to.
+    fn deliver(&mut self, message: &SharedMessage, symbol: Option<&str>) -> usize {
+        let mut delivered = deliver_all(&mut self.all, message);
+        if let Some(symbol) = symbol {
+            if let Some(mailboxes) = self.by_symbol.get_mut(symbol) {
+                delivered += deliver_all(mailboxes, message);
+                if mailboxes.is_empty() {
+                    self.by_symbol.remove(symbol);
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Forget message streams whose consumers are gone.
+    fn forget_detached(&mut self) {
+        self.all.retain(|mailbox| !mailbox.is_detached());
+        self.by_symbol.retain(|_, mailboxes| {
+            mailboxes.retain(|mailbox| !mailbox.is_detached());
+            !mailboxes.is_empty()
+        });
+    }
+
+    /// Return all registered mailboxes.
+    fn mailboxes(&self) -> impl Iterator<Item = &Arc<Mailbox>> {
+        self.all.iter().chain(self.by_symbol.values().flatten())
+    }
+
+    /// Return true if no message stream is registered.
+    fn is_empty(&self) -> bool {
+        self.all.is_empty() && self.by_symbol.is_empty()
+    }
+}
+
+
+/// Deliver the `message` to all `mailboxes`. Mailboxes which do not accept messages anymore are
+/// removed, so the remaining ones are those the message was delivered to.
+fn deliver_all(mailboxes: &mut Vec<Arc<Mailbox>>, message: &SharedMessage) -> usize {
+    mailboxes.retain(|mailbox| mailbox.deliver(message.clone()));
+    mailboxes.len()
+}
+
+
+/// Registered message streams.
+#[derive(Default)]
+struct Subscribers {
+    /// Message streams with indexed filters by the command of their routes
+    routed: HashMap<String, RouteBucket>,
+    /// Message streams with filters tested for each message
+    filtered: Vec<Subscriber>,
+}
+
+
+impl Subscribers {
+    /// Register the `mailbox` of a message stream with the `filter`.
+    fn register(&mut self, filter: DataMessageFilter, mailbox: Arc<Mailbox>) {
+        match Route::of_filter(&filter) {
+            Some(Route { command, symbol: None }) => self.routed.entry(command).or_default().all.push(mailbox),
+            Some(Route { command, symbol: Some(symbol) }) => self.routed.entry(command).or_default().by_symbol.entry(symbol).or_default().push(mailbox),
+            None => self.filtered.push(Subscriber { filter, mailbox }),
+        }
+    }
+
+    /// Forget message streams whose consumers are gone.
+    fn forget_detached(&mut self) {
+        self.routed.retain(|_, bucket| {
+            bucket.forget_detached();
+            !bucket.is_empty()
+        });
+        self.filtered.retain(|subscriber| !subscriber.mailbox.is_detached());
+    }
+
+    /// Deliver the `message` to message streams matching it. Nothing is allocated, message streams
+    /// whose consumers are gone are forgotten on the way.
+    ///
+    /// Return number of message streams the message was delivered to.
+    fn deliver(&mut self, message: &SharedMessage) -> usize {
+        let mut delivered = 0;
+        if let Some(bucket) = self.routed.get_mut(message.command()) {
+            delivered += bucket.deliver(message, message.symbol());
+            if bucket.is_empty() {
+                self.routed.remove(message.command());
+            }
+        }
+        self.filtered.retain(|subscriber| {
+            if !subscriber.filter.test_message(message.message()) {
+                return !subscriber.mailbox.is_detached();
+            }
+            let accepted = subscriber.mailbox.deliver(message.clone());
+            delivered += accepted as usize;
+            accepted
+        });
+        delivered
+    }
+
+    /// Return number of registered message streams.
+    fn len(&self) -> usize {
+        self.routed.values().map(|bucket| bucket.mailboxes().count()).sum::<usize>() + self.filtered.len()
+    }
+}
+
+
+/// Registry of message streams of a stream connection.
+///
+/// Message streams are indexed by the command and the symbol of their filters (see `Route`), so
+/// an incoming message is delivered to matching message streams without testing filters of all
+/// message streams. Filters which cannot be indexed (e.g. `Custom` or `Any`) are tested for each
+/// message.
+///
+/// Instances cloned from the same origin share the registered message streams.
+#[derive(Clone, Default)]
+pub(crate) struct SubscriberRegistry {
+    /// Registered message streams
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+
+impl SubscriberRegistry {
+    /// Register new message stream. Return the mailbox the matching messages are delivered to.
+    pub fn register(&self, filter: DataMessageFilter, policy: DeliveryPolicy) -> Arc<Mailbox> {
+        let mailbox = Arc::new(Mailbox::new(policy));
+        let mut subscribers = self.lock();
+        subscribers.forget_detached();
+        subscribers.register(filter, mailbox.clone());
+        mailbox
+    }
+
+    /// Deliver the `message` to all message streams with the matching filter. The delivery never
+    /// waits, so the message is delivered while the registry is locked.
+    ///
+    /// Return number of message streams the message was delivered to.
+    pub fn dispatch(&self, message: DecodedStreamMessage) -> usize {
+        let message = Arc::new(message);
+        self.lock().deliver(&message)
+    }
+
+    /// Close all registered message streams and forget them.
+    pub fn close(&self) {
+        let mut subscribers = self.lock();
+        for (_, bucket) in subscribers.routed.drain() {
+            bucket.mailboxes().for_each(|mailbox| mailbox.close());
+        }
+        for subscriber in subscribers.filtered.drain(..) {
+            subscriber.mailbox.close();
+        }
+    }
+
+    /// Return number of registered message streams.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Lock the subscribers. The poisoned lock is recovered.
+    fn lock(&self) -> MutexGuard<'_, Subscribers> {
+        self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod mailbox {
+        use std::future::poll_fn;
+        use std::sync::Arc;
+
+        use serde_json::json;
+
+        use crate::{DataStreamError, DeliveryPolicy};
+        use crate::delivery::Mailbox;
+        use crate::delivery::SharedMessage;
+        use crate::schema::{DecodedStreamMessage, StreamDataMessage};
+
+        fn message(symbol: &str, ask: i64) -> SharedMessage {
+            Arc::new(DecodedStreamMessage::from_message(StreamDataMessage { command: "tickPrices".to_owned(), data: json!({"symbol": symbol, "ask": ask}) }))
+        }
+
+        async fn receive(mailbox: &Mailbox) -> Option<Result<SharedMessage, DataStreamError>> {
+            poll_fn(|cx| mailbox.poll_receive(cx)).await
+        }
+
+        #[tokio::test]
+        async fn drop_oldest() {
+            let mailbox = Mailbox::new(DeliveryPolicy::DropOldest(2));
+            for ask in 1..=4 {
+                assert!(mailbox.deliver(message("EURUSD", ask)));
+            }
+
+            assert!(matches!(receive(&mailbox).await, Some(Err(DataStreamError::Lagged(2)))));
+            assert_eq!(receive(&mailbox).await.unwrap().unwrap().message().data["ask"], 3);
+            assert_eq!(receive(&mailbox).await.unwrap().unwrap().message().data["ask"], 4);
+        }
+
+        #[tokio::test]
+        async fn conflate_latest() {
+            let mailbox = Mailbox::new(DeliveryPolicy::conflate_latest_by("symbol"));
+            for (symbol, ask) in [("EURUSD", 1), ("USDJPY", 2), ("EURUSD", 3), ("EURUSD", 4)] {
+                assert!(mailbox.deliver(message(symbol, ask)));
+            }
+            mailbox.close();
+
+            assert_eq!(receive(&mailbox).await.unwrap().unwrap().message().data, json!({"symbol": "EURUSD", "ask": 4}));
+            assert_eq!(receive(&mailbox).await.unwrap().unwrap().message().data, json!({"symbol": "USDJPY", "ask": 2}));
+            assert!(receive(&mailbox).await.is_none());
+        }
+
+        #[tokio::test]
+        async fn bounded_overflow_cuts_off_stream() {
+            let mailbox = Mailbox::new(DeliveryPolicy::Bounded(2));
+            assert!(mailbox.deliver(message("EURUSD", 1)));
+            assert!(mailbox.deliver(message("EURUSD", 2)));
+            assert!(!mailbox.deliver(message("EURUSD", 3)));
+            assert!(!mailbox.deliver(message("EURUSD", 4)));
+            assert!(mailbox.is_detached());
+
+            assert_eq!(receive(&mailbox).await.unwrap().unwrap().message().data["ask"], 1);
+            assert_eq!(receive(&mailbox).await.unwrap().unwrap().message().data["ask"], 2);
+            assert!(matches!(receive(&mailbox).await, Some(Err(DataStreamError::Lagged(1)))));
+            assert!(receive(&mailbox).await.is_none());
+        }
+
+        #[tokio::test]
+        async fn bounded_keeps_all_messages_of_consumer_keeping_up() {
+            let mailbox = Mailbox::new(DeliveryPolicy::Bounded(1));
+            for ask in 1..=3 {
+                assert!(mailbox.deliver(message("EURUSD", ask)));
+                assert_eq!(receive(&mailbox).await.unwrap().unwrap().message().data["ask"], ask);
+            }
+            assert!(!mailbox.is_detached());
+        }
+    }
+
+    mod subscriber_registry {
+        use serde_json::json;
+
+        use crate::{DataMessageFilter, DeliveryPolicy};
+        use crate::delivery::SubscriberRegistry;
+        use crate::schema::{DecodedStreamMessage, StreamDataMessage};
+
+        #[test]
+        fn dispatch_to_matching_and_forget_detached() {
+            let registry = SubscriberRegistry::default();
+            let keep_alive = registry.register(DataMessageFilter::Command("keepAlive".to_owned()), DeliveryPolicy::DropOldest(8));
+            let _balance = registry.register(DataMessageFilter::Command("balance".to_owned()), DeliveryPolicy::DropOldest(8));
+            let message = || DecodedStreamMessage::from_message(StreamDataMessage { command: "keepAlive".to_owned(), data: json!({"timestamp": 1}) });
+
+            assert_eq!(registry.dispatch(message()), 1);
+            keep_alive.detach();
+            assert_eq!(registry.dispatch(message()), 0);
+            assert_eq!(registry.len(), 1);
+        }
+
+        #[test]
+        fn dispatch_by_symbol_and_custom_filter() {
+            let registry = SubscriberRegistry::default();
+            let symbol_filter = |symbol: &str| DataMessageFilter::All(vec![
+                DataMessageFilter::Command("tickPrices".to_owned()),
+                DataMessageFilter::FieldValue { name: "symbol".to_owned(), value: json!(symbol) },
+            ]);
+            let _eurusd = registry.register(symbol_filter("EURUSD"), DeliveryPolicy::DropOldest(8));
+            let _usdjpy = registry.register(symbol_filter("USDJPY"), DeliveryPolicy::DropOldest(8));
+            let _all_ticks = registry.register(DataMessageFilter::Command("tickPrices".to_owned()), DeliveryPolicy::DropOldest(8));
+            let _custom = registry.register(DataMessageFilter::Custom(Box::new(|msg| msg.data["ask"] == 2)), DeliveryPolicy::DropOldest(8));
+
+            let tick = |symbol: &str, ask: i64| DecodedStreamMessage::from_message(StreamDataMessage { command: "tickPrices".to_owned(), data: json!({"symbol": symbol, "ask": ask}) });
+            assert_eq!(registry.dispatch(tick("EURUSD", 1)), 2);
+            assert_eq!(registry.dispatch(tick("USDJPY", 2)), 3);
+            assert_eq!(registry.dispatch(tick("GBPUSD", 1)), 1);
+        }
+    }
+
+    mod route {
+        use rstest::rstest;
+        use serde_json::json;
+
+        use crate::DataMessageFilter;
+        use crate::delivery::Route;
+
+        fn route(command: &str, symbol: Option<&str>) -> Option<Route> {
+            Some(Route { command: command.to_owned(), symbol: symbol.map(str::to_owned) })
+        }
+
+        #[rstest]
+        #[case(DataMessageFilter::Command("candle".to_owned()), route("candle", None))]
+        #[case(DataMessageFilter::All(vec ! [DataMessageFilter::Command("candle".to_owned()), DataMessageFilter::Always]), route("candle", None))]
+        #[case(DataMessageFilter::All(vec ! [DataMessageFilter::Command("candle".to_owned()), DataMessageFilter::FieldValue { name: "symbol".to_owned(), value: json ! ("EURUSD") }]), route("candle", Some("EURUSD")))]
+        #[case(DataMessageFilter::All(vec ! [DataMessageFilter::Command("candle".to_owned()), DataMessageFilter::FieldValue { name: "order".to_owned(), value: json ! ("EURUSD") }]), None)]
+        #[case(DataMessageFilter::All(vec ! [DataMessageFilter::Command("candle".to_owned()), DataMessageFilter::FieldValue { name: "symbol".to_owned(), value: json ! (1) }]), None)]
+        #[case(DataMessageFilter::All(vec ! [DataMessageFilter::FieldValue { name: "symbol".to_owned(), value: json ! ("EURUSD") }]), None)]
+        #[case(DataMessageFilter::Always, None)]
+        #[case(DataMessageFilter::Any(vec ! [DataMessageFilter::Command("candle".to_owned())]), None)]
+        fn of_filter(#[case] filter: DataMessageFilter, #[case] expected: Option<Route>) {
+            assert_eq!(Route::of_filter(&filter), expected);
+        }
+    }
+}
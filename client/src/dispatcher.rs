@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::Notify;
+use tokio::sync::futures::Notified;
+use tokio::sync::oneshot::{channel, Receiver, Sender};
+
+use crate::connection::{BasicXtbConnectionError, ConnectionClosedInfo};
+use crate::message_processing::ProcessedMessage;
+
+
+/// Number of shards of the pending requests map.
+const SHARD_COUNT: usize = 16;
+
+
+/// Result delivered to the `ResponsePromise`.
+pub(crate) type ResponseResult = Result<ProcessedMessage, BasicXtbConnectionError>;
+
+
+/// Pending requests by numeric message tags in one shard.
+type Shard = Mutex<HashMap<u64, Sender<ResponseResult>>>;
+
+
+/// Router of responses to their consumers.
+///
+/// Pending requests are stored in a map sharded by the numeric message tag, so concurrent
+/// registrations and deliveries rarely wait for each other. Locks are held only for the insert or
+/// the remove of the map entry and responses are delivered by oneshot channels.
+#[derive(Debug, Default)]
+pub(crate) struct Dispatcher {
+    /// Senders of responses by message tags
+    shards: [Shard; SHARD_COUNT],
+    /// Set when the connection was closed
+    closed: OnceLock<ConnectionClosedInfo>,
+    /// Number of responses nobody waited for
+    late_responses: AtomicU64,
+    /// Notified when a request stops being pending
+    pending_decreased: Notify,
+}
+
+
+impl Dispatcher {
+    /// Register the request tagged by the `tag`. Return the receiver of the response.
+    ///
+    /// Return the close info if the connection was already closed.
+    pub fn register(&self, tag: u64) -> Result<Receiver<ResponseResult>, ConnectionClosedInfo> {
+        let (sender, receiver) = channel();
+        // The shard lock is held during the check, so the close cannot drain the shard before
+        // the sender is inserted.
+        let mut shard = self.shard(tag);
+        if let Some(info) = self.closed.get() {
+            return Err(info.clone());
+        }
+        shard.insert(tag, sender);
+        Ok(receiver)
+    }
+
+    /// Forget the request tagged by the `tag`. A response received later is counted as late.
+    pub fn deregister(&self, tag: u64) {
+        if self.shard(tag).remove(&tag).is_some() {
+            self.notify_pending_decreased();
+        }
+    }
+
+    /// Deliver the `response` to the consumer waiting for the `tag`.
+    ///
+    /// Return the response back if nobody waits for it. Such response is counted as late.
+    pub fn dispatch(&self, tag: u64, response: ProcessedMessage) -> Result<(), ProcessedMessage> {
+        let maybe_sender = self.shard(tag).remove(&tag);
+        if maybe_sender.is_some() {
+            self.notify_pending_decreased();
+        }
+        let result = match maybe_sender {
+            Some(sender) => sender.send(Ok(response)).map_err(|result| match result {
+                Ok(response) => response,
+                Err(_) => unreachable!("Only responses are dispatched"),
+            }),
+            None => Err(response),
+        };
+        if result.is_err() {
+            self.late_responses.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Mark the connection as closed and fail all pending requests.
+    pub fn close(&self, info: ConnectionClosedInfo) {
+        // the first close wins
+        let _ = self.closed.set(info);
+        let info = self.closed.get().expect("The close info was just set");
+        for shard in &self.shards {
+            for (_, sender) in lock(shard).drain() {
+                let _ = sender.send(Err(info.to_error()));
+            }
+        }
+        self.notify_pending_decreased();
+    }
+
+    /// Return number of pending requests.
+    pub fn pending(&self) -> usize {
+        self.shards.iter().map(|shard| lock(shard).len()).sum()
+    }
+
+    /// Wake all tasks waiting for the `pending_decreased` future.
+    pub fn notify_pending_decreased(&self) {
+        self.pending_decreased.notify_waiters();
+    }
+
+    /// Return future completed when the number of pending requests may have decreased.
+    ///
+    /// The future must be enabled (or polled) before the number is checked, so no notification
+    /// is missed.
+    pub fn pending_decreased(&self) -> Notified<'_> {
+        self.pending_decreased.notified()
+    }
+
+    /// Return number of responses nobody waited for.
+    pub fn late_responses(&self) -> u64 {
+        self.late_responses.load(Ordering::Relaxed)
+    }
+
+    /// Lock the shard containing the `tag`.
+    fn shard(&self, tag: u64) -> MutexGuard<'_, HashMap<u64, Sender<ResponseResult>>> {
+        lock(&self.shards[tag as usize % SHARD_COUNT])
+    }
+}
+
+
+/// Lock the shard. The poisoned lock is recovered, the map cannot be left in inconsistent state.
+fn lock(shard: &Shard) -> MutexGuard<'_, HashMap<u64, Sender<ResponseResult>>> {
+    shard.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod dispatcher {
+        use crate::connection::{BasicXtbConnectionError, ConnectionClosedInfo};
+        use crate::dispatcher::Dispatcher;
+        use crate::message_processing::ProcessedMessage;
+        use crate::schema::Response;
+
+        #[tokio::test]
+        async fn dispatch_to_registered_tag() {
+            let dispatcher = Dispatcher::default();
+            let receiver = dispatcher.register(1).unwrap();
+
+            assert!(dispatcher.dispatch(1, ProcessedMessage::Response(Response::default())).is_ok());
+            assert!(matches!(receiver.await, Ok(Ok(ProcessedMessage::Response(_)))));
+            assert_eq!(dispatcher.pending(), 0);
+            assert_eq!(dispatcher.late_responses(), 0);
+        }
+
+        #[test]
+        fn unknown_and_deregistered_tags_are_late() {
+            let dispatcher = Dispatcher::default();
+            let _receiver = dispatcher.register(1).unwrap();
+            dispatcher.deregister(1);
+
+            assert!(dispatcher.dispatch(1, ProcessedMessage::Response(Response::default())).is_err());
+            assert!(dispatcher.dispatch(2, ProcessedMessage::Response(Response::default())).is_err());
+            assert_eq!(dispatcher.late_responses(), 2);
+        }
+
+        #[test]
+        fn dropped_receiver_is_late() {
+            let dispatcher = Dispatcher::default();
+            drop(dispatcher.register(1).unwrap());
+
+            assert!(dispatcher.dispatch(1, ProcessedMessage::Response(Response::default())).is_err());
+            assert_eq!(dispatcher.late_responses(), 1);
+        }
+
+        #[tokio::test]
+        async fn close_fails_pending_and_new_requests() {
+            let dispatcher = Dispatcher::default();
+            let pending: Vec<_> = (1..=40).map(|tag| dispatcher.register(tag).unwrap()).collect();
+            assert_eq!(dispatcher.pending(), 40);
+
+            dispatcher.close(ConnectionClosedInfo::default());
+
+            for receiver in pending {
+                assert!(matches!(receiver.await, Ok(Err(BasicXtbConnectionError::ConnectionClosed { .. }))));
+            }
+            assert_eq!(dispatcher.pending(), 0);
+            assert!(dispatcher.register(41).is_err());
+        }
+    }
+}
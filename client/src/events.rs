@@ -0,0 +1,44 @@
+use tokio::sync::broadcast::Sender;
+use tracing::debug;
+
+
+/// Identification of the XTB server the event is related to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XtbServer {
+    /// The request/response API server
+    RequestResponse,
+    /// The stream API server
+    Stream,
+}
+
+
+/// Events describing health of the connections of the `XtbClient`.
+///
+/// Events are broadcast to all receivers returned by the `XtbClient::events` method. Receivers get
+/// only events emitted after they were created.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The socket to the server was opened
+    Connected { server: XtbServer },
+    /// The user was logged in and new stream session id was obtained
+    LoggedIn,
+    /// The ping command was not sent or it failed
+    PingFailed { server: XtbServer, reason: String },
+    /// The connection to the server was lost
+    Disconnected { reason: String },
+    /// The reconnection attempt is going to be made. The first attempt has number 1.
+    Reconnecting { attempt: u32 },
+    /// The stream subscription was made again after the reconnection
+    SubscriptionRestored { command: String },
+    /// All reconnection attempts allowed by the `ReconnectPolicy` failed. The client does not try
+    /// to reconnect anymore.
+    ReconnectFailed { attempts: u32 },
+}
+
+
+/// Broadcast the `event` to all receivers. Nothing happens if there is no receiver.
+pub(crate) fn emit_event(sender: &Sender<ConnectionEvent>, event: ConnectionEvent) {
+    debug!("Connection event: {event:?}");
+    // error means no receiver is listening which is ok
+    let _ = sender.send(event);
+}
@@ -0,0 +1,168 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, RwLock};
+
+use thiserror::Error;
+
+use crate::message_processing::ProcessedMessage;
+use crate::schema::Request;
+
+
+/// Hook called for every command sent by connections and for every response received.
+///
+/// Interceptors can be used for audit logging, metrics, blocking of commands (e.g. in a read-only
+/// mode) or rewriting of payloads in tests. The request can be matched with its response by the
+/// `custom_tag` field.
+///
+/// Stream `subscribe`/`unsubscribe` commands are passed to the `before_send` too. Their requests
+/// have no custom tag and the stream session id is not part of the arguments.
+///
+/// Note: the `login` command is intercepted too and its arguments contain the password.
+pub trait Interceptor: Send + Sync {
+    /// Called before the `request` is sent. The request can be modified, but its `custom_tag` must
+    /// be kept, otherwise the response cannot be routed.
+    ///
+    /// Return `Err(Rejection)` to block the request. The request is not sent and the caller gets
+    /// an error.
+    fn before_send(&self, request: &mut Request) -> Result<(), Rejection> {
+        let _ = request;
+        Ok(())
+    }
+
+    /// Called for every response received from the request/response server (including responses
+    /// nobody waits for).
+    fn after_receive(&self, message: &ProcessedMessage) {
+        let _ = message;
+    }
+}
+
+
+/// The request was blocked by an interceptor.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("The request was rejected: {reason}")]
+pub struct Rejection {
+    /// Why the request was rejected
+    pub reason: String,
+}
+
+
+impl Rejection {
+    /// Create new instance with the `reason`.
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self { reason: reason.into() }
+    }
+}
+
+
+/// Ordered list of interceptors.
+///
+/// The `before_send` hooks are called in the order the interceptors were added and the first
+/// rejection stops the chain. The `after_receive` hooks are called in the same order.
+#[derive(Clone, Default)]
+pub struct InterceptorChain {
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+
+impl InterceptorChain {
+    /// Append the interceptor to the end of the chain.
+    pub fn with_interceptor(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Append the shared interceptor to the end of the chain.
+    pub fn with_shared_interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Return true if there is no interceptor in the chain.
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// Pass the `request` to all interceptors.
+    pub(crate) fn before_send(&self, request: &mut Request) -> Result<(), Rejection> {
+        self.interceptors.iter().try_for_each(|interceptor| interceptor.before_send(request))
+    }
+
+    /// Pass the `message` to all interceptors.
+    pub(crate) fn after_receive(&self, message: &ProcessedMessage) {
+        self.interceptors.iter().for_each(|interceptor| interceptor.after_receive(message));
+    }
+}
+
+
+impl Debug for InterceptorChain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InterceptorChain")
+            .field("interceptors", &self.interceptors.len())
+            .finish()
+    }
+}
+
+
+/// Interceptor chain shared by a connection and its listener. The chain can be replaced while
+/// the connection is running.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SharedInterceptors(Arc<RwLock<Arc<InterceptorChain>>>);
+
+
+impl SharedInterceptors {
+    /// Replace the chain.
+    pub fn set(&self, chain: InterceptorChain) {
+        *self.0.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(chain);
+    }
+
+    /// Return the current chain.
+    pub fn get(&self) -> Arc<InterceptorChain> {
+        self.0.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod interceptor_chain {
+        use std::sync::{Arc, Mutex};
+
+        use crate::{Interceptor, InterceptorChain, Rejection};
+        use crate::schema::Request;
+
+        /// Record names of intercepted commands.
+        struct Recorder(Arc<Mutex<Vec<String>>>, &'static str);
+
+        impl Interceptor for Recorder {
+            fn before_send(&self, request: &mut Request) -> Result<(), Rejection> {
+                self.0.lock().unwrap().push(format!("{}:{}", self.1, request.command));
+                Ok(())
+            }
+        }
+
+        struct Blocker;
+
+        impl Interceptor for Blocker {
+            fn before_send(&self, request: &mut Request) -> Result<(), Rejection> {
+                match request.command.as_str() {
+                    "tradeTransaction" => Err(Rejection::new("read-only mode")),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        #[test]
+        fn called_in_order_until_rejection() {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let chain = InterceptorChain::default()
+                .with_interceptor(Recorder(log.clone(), "first"))
+                .with_interceptor(Blocker)
+                .with_interceptor(Recorder(log.clone(), "second"));
+
+            assert!(chain.before_send(&mut Request::default().with_command("getVersion")).is_ok());
+            let result = chain.before_send(&mut Request::default().with_command("tradeTransaction"));
+
+            assert_eq!(result, Err(Rejection::new("read-only mode")));
+            assert_eq!(*log.lock().unwrap(), vec!["first:getVersion", "second:getVersion", "first:tradeTransaction"]);
+        }
+    }
+}
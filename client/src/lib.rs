@@ -4,10 +4,36 @@ use rstest_reuse;
 pub use client::*;
 pub use connection::*;
 pub use stream_connection::*;
+pub use rate_limiter::*;
+pub use transport::*;
+pub use recording::*;
+pub use connector::*;
+pub use events::*;
+pub use tls::*;
+pub use interceptor::*;
+pub use delivery::DeliveryPolicy;
+pub use message_processing::ProcessedMessage;
+#[cfg(any(test, feature = "mock-server"))]
+pub use mock_server::*;
+#[cfg(any(test, feature = "tower"))]
+pub use service::*;
 
 pub mod schema;
 mod connection;
+mod dispatcher;
 mod message_processing;
 mod listener;
 mod stream_connection;
 mod client;
+mod rate_limiter;
+mod transport;
+mod recording;
+mod connector;
+mod events;
+mod tls;
+mod interceptor;
+mod delivery;
+#[cfg(any(test, feature = "mock-server"))]
+mod mock_server;
+#[cfg(any(test, feature = "tower"))]
+mod service;
@@ -1,18 +1,15 @@
 use async_trait::async_trait;
-use futures_util::stream::SplitStream;
 use futures_util::StreamExt;
-use tokio::net::TcpStream;
 use tokio::spawn;
 use tokio::task::JoinHandle;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tracing::{debug, error};
-use crate::schema::StreamDataMessage;
+use crate::schema::DecodedStreamMessage;
 use crate::message_processing;
 use crate::message_processing::ProcessedMessage;
-
-
-/// Helper type making variable and field declaration shorter.
-pub type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+use crate::recording::{WireDirection, WireTap};
+use crate::transport::Transport;
 
 
 /// Handler trait used to avoid using async callbacks
@@ -22,12 +19,21 @@ pub trait ResponseHandler: Send + Sync + 'static {
     ///
     /// The logic must be "safe" - it should not panic
     async fn handle_response(&self, response: ProcessedMessage);
+
+    /// Process closing of the connection. The `frame` is set if the server sent the close frame.
+    ///
+    /// This method is called once when no more message can be received.
+    async fn handle_close(&self, frame: Option<CloseFrame<'static>>);
 }
 
 
 /// Spawn listener for command responses. Responses are handled by `response_handler`
-pub fn listen_for_responses(mut stream: SplitStream<Stream>, response_handler: impl ResponseHandler) -> JoinHandle<()> {
+///
+/// When the stream is finished, the `handle_close` method of the `response_handler` is called.
+/// If the `wire_tap` is set, all received messages are recorded.
+pub fn listen_for_responses<T: Transport>(mut stream: T::Stream, response_handler: impl ResponseHandler, wire_tap: Option<WireTap>) -> JoinHandle<()> {
     spawn(async move {
+        let mut close_frame = None;
         // Read messages until some is delivered
         while let Some(message_result) = stream.next().await {
             let message = match message_result {
@@ -38,6 +44,13 @@ pub fn listen_for_responses(mut stream: SplitStream<Stream>, response_handler: i
                 }
             };
             debug!("{:?}", message);
+            if let Some(tap) = &wire_tap {
+                tap.record(WireDirection::Incoming, &message);
+            }
+            if let Message::Close(frame) = message {
+                close_frame = frame.map(|f| f.into_owned());
+                continue;
+            }
             // process message
             let response = match message_processing::process_message(message) {
                 Ok(response) => response,
@@ -48,6 +61,7 @@ pub fn listen_for_responses(mut stream: SplitStream<Stream>, response_handler: i
             };
             response_handler.handle_response(response).await;
         }
+        response_handler.handle_close(close_frame).await;
     })
 }
 
@@ -56,17 +70,30 @@ pub fn listen_for_responses(mut stream: SplitStream<Stream>, response_handler: i
 #[async_trait]
 pub trait StreamDataMessageHandler: Send + Sync + 'static {
     /// Do logic for handled message
-    async fn handle_message(&self, message: StreamDataMessage);
+    async fn handle_message(&self, message: DecodedStreamMessage);
 }
 
 
-/// Listen for stream data messages
-pub fn listen_for_stream_data(mut stream: SplitStream<Stream>, response_handler: impl StreamDataMessageHandler) -> JoinHandle<()> {
+/// Listen for stream data messages. If the `wire_tap` is set, all received messages are recorded.
+///
+/// Only the command of each message is parsed here. The data are kept raw and decoded lazily by
+/// the consumers of the message (see `DecodedStreamMessage`).
+pub fn listen_for_stream_data<T: Transport>(mut stream: T::Stream, response_handler: impl StreamDataMessageHandler, wire_tap: Option<WireTap>) -> JoinHandle<()> {
     spawn(async move {
         while let Some(result) = stream.next().await {
             match result {
                 Ok(message) => {
-                    let parsed_message: Result<StreamDataMessage, _> = serde_json::from_str(&message.to_string());
+                    if let Some(tap) = &wire_tap {
+                        tap.record(WireDirection::Incoming, &message);
+                    }
+                    let parsed_message = match &message {
+                        Message::Text(text) => DecodedStreamMessage::parse(text.as_bytes()),
+                        Message::Binary(bytes) => DecodedStreamMessage::parse(bytes),
+                        _ => {
+                            debug!("Skipping control message {:?}", message);
+                            continue;
+                        }
+                    };
                     match parsed_message {
                         Ok(parsed) => {
                             response_handler.handle_message(parsed).await;
@@ -0,0 +1,947 @@
+use std::collections::HashMap;
+use std::future::pending;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::{Sink, SinkExt, StreamExt};
+use serde_json::{from_str, json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::spawn;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{interval, Interval, sleep};
+use tokio_tungstenite::{accept_async, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+use tracing::{debug, error, warn};
+
+use crate::schema::*;
+use crate::XtbClientBuilder;
+
+
+/// Stream session id returned by the mock server when no other id is configured.
+const DEFAULT_STREAM_SESSION_ID: &str = "mock_stream_session";
+
+
+/// Default interval of the `keepAlive` stream messages (the real server sends them every 3 seconds).
+const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(3);
+
+
+/// Payload sent instead of a message by the `Fault::MalformedJson`.
+const MALFORMED_JSON: &str = r#"{"status": true, "returnData": {"#;
+
+
+/// Misbehaviour of the mock server injected by a `FaultRule`.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Drop the socket without any response and without the close frame.
+    DropConnection,
+    /// Send the first half of the response in an unfinished websocket frame and drop the socket.
+    /// Stream data are sent except the last message, which is cut the same way.
+    DropMidResponse,
+    /// Send the response (or stream data) after the delay. Other requests are not delayed.
+    Delay(Duration),
+    /// Hold the response (or stream data) and send it after the response to the next request.
+    Reorder,
+    /// Answer by the error response instead of the scripted one. The stream API sends no
+    /// responses, so this fault cannot be injected into subscriptions.
+    Error(XtbErrorCode, String),
+    /// Send malformed JSON instead of the response (or stream data).
+    MalformedJson,
+    /// Do not send any response (or stream data).
+    NoResponse,
+}
+
+
+/// Request matched by a `FaultRule`.
+#[derive(Clone, Debug)]
+pub enum FaultTrigger {
+    /// Command sent to the request/response API
+    Command(String),
+    /// Request sent to the request/response API with the custom tag
+    Tag(String),
+    /// Command sent to the stream API (e.g. `getTickPrices`)
+    Subscription(String),
+}
+
+
+impl FaultTrigger {
+    /// Return true if the trigger matches the request received by the endpoint.
+    fn test_request(&self, endpoint: Endpoint, request: &Value) -> bool {
+        match (self, endpoint) {
+            (Self::Command(command), Endpoint::Api) => request["command"] == *command,
+            (Self::Tag(tag), Endpoint::Api) => request["customTag"] == *tag,
+            (Self::Subscription(command), Endpoint::Stream) => request["command"] == *command,
+            _ => false,
+        }
+    }
+}
+
+
+/// Rule injecting the `Fault` when a request matching the `FaultTrigger` is received.
+///
+/// Rules are evaluated in the order they were added and the first matching rule is applied.
+/// By default, the rule is applied to all matching requests. The `times` method limits number of
+/// its applications (e.g. drop the connection only once).
+///
+/// ```ignore
+/// let fixtures = MockFixtures::new()
+///     .with_fault(FaultRule::on_command("getVersion", Fault::DropConnection).times(1))
+///     .with_fault(FaultRule::on_tag("message_3", Fault::Delay(Duration::from_secs(1))));
+/// ```
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    /// Requests the rule applies to
+    trigger: FaultTrigger,
+    /// The injected fault
+    fault: Fault,
+    /// Number of remaining applications. If `None`, the rule is applied forever.
+    remaining: Option<usize>,
+}
+
+
+impl FaultRule {
+    /// Create rule matching requests by the trigger.
+    pub fn new(trigger: FaultTrigger, fault: Fault) -> Self {
+        Self { trigger, fault, remaining: None }
+    }
+
+    /// Create rule matching commands sent to the request/response API.
+    pub fn on_command(command: &str, fault: Fault) -> Self {
+        Self::new(FaultTrigger::Command(command.to_owned()), fault)
+    }
+
+    /// Create rule matching the request with the custom tag (e.g. `message_3`).
+    pub fn on_tag(tag: &str, fault: Fault) -> Self {
+        Self::new(FaultTrigger::Tag(tag.to_owned()), fault)
+    }
+
+    /// Create rule matching commands sent to the stream API.
+    pub fn on_subscription(command: &str, fault: Fault) -> Self {
+        Self::new(FaultTrigger::Subscription(command.to_owned()), fault)
+    }
+
+    /// Apply the rule only `times` times.
+    pub fn times(mut self, times: usize) -> Self {
+        self.remaining = Some(times);
+        self
+    }
+}
+
+
+/// Scripted behaviour of the `MockServer`.
+///
+/// Commands without a fixture are answered by the `BE104` (method does not exist) error response.
+/// The `ping` and `logout` commands are always answered by an empty successful response and
+/// the `login` command returns the stream session id.
+#[derive(Clone, Debug)]
+pub struct MockFixtures {
+    /// Credentials (user id and password) accepted by the `login` command. If `None`, any
+    /// credentials are accepted.
+    credentials: Option<(String, String)>,
+    /// Stream session id returned by the `login` command
+    stream_session_id: String,
+    /// Return data of successful responses by command names
+    responses: HashMap<String, Value>,
+    /// Error responses (error code and description) by command names
+    errors: HashMap<String, (XtbErrorCode, String)>,
+    /// Data messages sent after a subscription by subscribe command names
+    stream_data: HashMap<String, Vec<Value>>,
+    /// Interval between two `keepAlive` messages
+    keep_alive_interval: Duration,
+    /// Number of `keepAlive` messages sent by a connection. If `None`, messages are sent forever.
+    keep_alive_limit: Option<usize>,
+    /// Injected faults
+    faults: Vec<FaultRule>,
+}
+
+
+impl MockFixtures {
+    /// Create fixtures accepting any credentials and without any scripted response.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept only given credentials by the `login` command.
+    pub fn with_credentials(mut self, user_id: &str, password: &str) -> Self {
+        self.credentials = Some((user_id.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Set the stream session id returned by the `login` command.
+    pub fn with_stream_session_id(mut self, stream_session_id: &str) -> Self {
+        self.stream_session_id = stream_session_id.to_owned();
+        self
+    }
+
+    /// Answer the `command` by successful response carrying the `return_data`.
+    pub fn with_response(mut self, command: &str, return_data: Value) -> Self {
+        self.errors.remove(command);
+        self.responses.insert(command.to_owned(), return_data);
+        self
+    }
+
+    /// Answer the `command` by the error response.
+    pub fn with_error(mut self, command: &str, code: XtbErrorCode, description: &str) -> Self {
+        self.responses.remove(command);
+        self.errors.insert(command.to_owned(), (code, description.to_owned()));
+        self
+    }
+
+    /// Send data messages after the subscribe command (e.g. `getTickPrices`) is received.
+    ///
+    /// The `command` field of messages is derived from the subscribe command (e.g. `tickPrices`).
+    pub fn with_stream_data(mut self, subscribe_command: &str, data: Vec<Value>) -> Self {
+        self.stream_data.insert(subscribe_command.to_owned(), data);
+        self
+    }
+
+    /// Set interval between two `keepAlive` messages.
+    pub fn with_keep_alive_interval(mut self, keep_alive_interval: Duration) -> Self {
+        self.keep_alive_interval = keep_alive_interval;
+        self
+    }
+
+    /// Stop sending `keepAlive` messages after `limit` messages were sent by a connection.
+    pub fn with_keep_alive_limit(mut self, limit: usize) -> Self {
+        self.keep_alive_limit = Some(limit);
+        self
+    }
+
+    /// Add the fault injection rule.
+    ///
+    /// # Panics
+    ///
+    /// The `Fault::Error` is injected into a subscription.
+    pub fn with_fault(mut self, rule: FaultRule) -> Self {
+        assert!(
+            !matches!((&rule.trigger, &rule.fault), (FaultTrigger::Subscription(_), Fault::Error(..))),
+            "The stream API sends no responses, the error cannot be injected into the subscription: {rule:?}",
+        );
+        self.faults.push(rule);
+        self
+    }
+
+    /// Build response to the command request.
+    ///
+    /// The `logged_in` flag holds the login state of the connection the request was received by.
+    fn respond(&self, request: &Value, logged_in: &mut bool) -> Value {
+        let command = request["command"].as_str().unwrap_or_default();
+        let tag = request.get("customTag").cloned().unwrap_or(Value::Null);
+
+        if let Some((code, description)) = self.errors.get(command) {
+            return error_response(code, description, tag);
+        }
+        if command == COMMAND_LOGIN {
+            return self.login(&request["arguments"], tag, logged_in);
+        }
+        if !*logged_in {
+            return error_response(&XtbErrorCode::BE103, "User is not logged", tag);
+        }
+        match self.responses.get(command) {
+            Some(return_data) => json!({"status": true, "returnData": return_data, "customTag": tag}),
+            None if command == COMMAND_PING || command == COMMAND_LOGOUT => json!({"status": true, "customTag": tag}),
+            None => error_response(&XtbErrorCode::BE104, "Method does not exist", tag),
+        }
+    }
+
+    /// Check credentials and build response to the `login` command.
+    fn login(&self, arguments: &Value, tag: Value, logged_in: &mut bool) -> Value {
+        if let Some((user_id, password)) = &self.credentials {
+            if arguments["userId"] != *user_id || arguments["password"] != *password {
+                return error_response(&XtbErrorCode::BE005, "userPasswordCheck: Invalid login or password", tag);
+            }
+        }
+        *logged_in = true;
+        json!({"status": true, "streamSessionId": self.stream_session_id, "customTag": tag})
+    }
+}
+
+
+impl Default for MockFixtures {
+    fn default() -> Self {
+        Self {
+            credentials: None,
+            stream_session_id: DEFAULT_STREAM_SESSION_ID.to_owned(),
+            responses: HashMap::new(),
+            errors: HashMap::new(),
+            stream_data: HashMap::new(),
+            keep_alive_interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            keep_alive_limit: None,
+            faults: Vec::new(),
+        }
+    }
+}
+
+
+/// Shared state of the mock server and its connections.
+#[derive(Debug)]
+struct MockServerState {
+    /// The scripted behaviour
+    fixtures: MockFixtures,
+    /// Requests received by the request/response server
+    received_commands: Mutex<Vec<Value>>,
+    /// Requests received by the stream server
+    received_stream_commands: Mutex<Vec<Value>>,
+    /// Fault injection rules with remaining numbers of applications
+    faults: Mutex<Vec<FaultRule>>,
+}
+
+
+impl MockServerState {
+    /// Find the first rule matching the request and return its fault.
+    async fn take_fault(&self, endpoint: Endpoint, request: &Value) -> Option<Fault> {
+        let mut faults = self.faults.lock().await;
+        let rule = faults.iter_mut()
+            .filter(|rule| rule.remaining != Some(0))
+            .find(|rule| rule.trigger.test_request(endpoint, request))?;
+        if let Some(remaining) = rule.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        debug!("Mock server injects fault {:?}", rule.fault);
+        Some(rule.fault.clone())
+    }
+}
+
+
+/// Endpoints served by the mock server.
+#[derive(Clone, Copy, Debug)]
+enum Endpoint {
+    /// The request/response API
+    Api,
+    /// The stream API
+    Stream,
+}
+
+
+/// Local stand-in for the XTB servers.
+///
+/// The server listens on two local ports - one for the request/response API and one for
+/// the stream API. Responses are given by the `MockFixtures`. The server is stopped (including all
+/// open connections) when the instance is dropped.
+#[derive(Debug)]
+pub struct MockServer {
+    /// Address of the request/response API
+    api_addr: SocketAddr,
+    /// Address of the stream API
+    stream_addr: SocketAddr,
+    /// Shared state
+    state: Arc<MockServerState>,
+    /// Task accepting request/response connections
+    api_join: JoinHandle<()>,
+    /// Task accepting stream connections
+    stream_join: JoinHandle<()>,
+}
+
+
+impl MockServer {
+    /// Start the server on random local ports.
+    pub async fn start(fixtures: MockFixtures) -> io::Result<Self> {
+        let api_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let stream_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let state = Arc::new(MockServerState {
+            faults: Mutex::new(fixtures.faults.clone()),
+            fixtures,
+            received_commands: Mutex::new(Vec::new()),
+            received_stream_commands: Mutex::new(Vec::new()),
+        });
+        Ok(Self {
+            api_addr: api_listener.local_addr()?,
+            stream_addr: stream_listener.local_addr()?,
+            api_join: spawn_acceptor(api_listener, state.clone(), Endpoint::Api),
+            stream_join: spawn_acceptor(stream_listener, state.clone(), Endpoint::Stream),
+            state,
+        })
+    }
+
+    /// Url of the request/response API (e.g. `ws://127.0.0.1:1234`).
+    pub fn api_url(&self) -> String {
+        format!("ws://{}", self.api_addr)
+    }
+
+    /// Url of the stream API (e.g. `ws://127.0.0.1:1235`).
+    pub fn stream_api_url(&self) -> String {
+        format!("ws://{}", self.stream_addr)
+    }
+
+    /// Create the client builder connecting to this server.
+    pub fn client_builder(&self) -> XtbClientBuilder {
+        XtbClientBuilder::new(&self.api_url(), &self.stream_api_url())
+    }
+
+    /// Return all requests received by the request/response API in order of arrival.
+    pub async fn received_commands(&self) -> Vec<Value> {
+        self.state.received_commands.lock().await.clone()
+    }
+
+    /// Return all requests received by the stream API in order of arrival.
+    pub async fn received_stream_commands(&self) -> Vec<Value> {
+        self.state.received_stream_commands.lock().await.clone()
+    }
+}
+
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.api_join.abort();
+        self.stream_join.abort();
+    }
+}
+
+
+/// Spawn task accepting connections to the endpoint.
+///
+/// Connections are served by tasks owned by the acceptor, so they are stopped together with it.
+fn spawn_acceptor(listener: TcpListener, state: Arc<MockServerState>, endpoint: Endpoint) -> JoinHandle<()> {
+    spawn(async move {
+        let mut connections = JoinSet::new();
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(err) => {
+                    error!("Mock server cannot accept connection: {:?}", err);
+                    continue;
+                }
+            };
+            let state = state.clone();
+            connections.spawn(async move {
+                let socket = match accept_async(socket).await {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        error!("Mock server websocket handshake failed: {:?}", err);
+                        return;
+                    }
+                };
+                match endpoint {
+                    Endpoint::Api => serve_commands(socket, state).await,
+                    Endpoint::Stream => serve_stream(socket, state).await,
+                }
+            });
+            // forget finished connections
+            while connections.try_join_next().is_some() {}
+        }
+    })
+}
+
+
+/// Serve one connection to the request/response API.
+async fn serve_commands(socket: WebSocketStream<TcpStream>, state: Arc<MockServerState>) {
+    let (sink, mut stream) = socket.split();
+    let (sender, receiver) = unbounded_channel();
+    // Tasks are aborted when the connection is dropped
+    let mut forwarder = JoinSet::new();
+    forwarder.spawn(forward_messages(receiver, sink));
+    let mut tasks = JoinSet::new();
+    let mut logged_in = false;
+    let mut held = Vec::new();
+
+    while let Some(Ok(message)) = stream.next().await {
+        let request = match parse_request(message) {
+            Some(request) => request,
+            None => continue,
+        };
+        debug!("Mock server received command: {:?}", request);
+        state.received_commands.lock().await.push(request.clone());
+
+        let fault = state.take_fault(Endpoint::Api, &request).await;
+        let response = match &fault {
+            Some(Fault::Error(code, description)) => error_response(code, description, request["customTag"].clone()),
+            _ => state.fixtures.respond(&request, &mut logged_in),
+        };
+        let message = Message::text(response.to_string());
+        match fault {
+            Some(Fault::DropConnection) => return,
+            Some(Fault::DropMidResponse) => {
+                let _ = sender.send(partial_frame(&response.to_string()));
+                forwarder.join_next().await;
+                return;
+            }
+            Some(Fault::NoResponse) => continue,
+            Some(Fault::Reorder) => {
+                held.push(message);
+                continue;
+            }
+            Some(Fault::Delay(delay)) => {
+                let sender = sender.clone();
+                tasks.spawn(async move {
+                    sleep(delay).await;
+                    let _ = sender.send(message);
+                });
+            }
+            Some(Fault::MalformedJson) => { let _ = sender.send(Message::text(MALFORMED_JSON)); }
+            _ => { let _ = sender.send(message); }
+        }
+        for message in held.drain(..) {
+            let _ = sender.send(message);
+        }
+
+        if request["command"] == COMMAND_LOGOUT {
+            let _ = sender.send(Message::Close(None));
+            // wait for delayed responses and for the close frame
+            while tasks.join_next().await.is_some() {}
+            forwarder.join_next().await;
+            break;
+        }
+    }
+}
+
+
+/// Send messages from the channel to the websocket. The task is finished by the close message or
+/// by the raw (unfinished) frame, after which nothing can be sent.
+async fn forward_messages(mut receiver: UnboundedReceiver<Message>, mut sink: impl Sink<Message> + Unpin) {
+    while let Some(message) = receiver.recv().await {
+        let is_last = message.is_close() || matches!(message, Message::Frame(_));
+        if sink.send(message).await.is_err() || is_last {
+            break;
+        }
+    }
+}
+
+
+/// Make the unfinished text frame carrying the first half of the `message`.
+fn partial_frame(message: &str) -> Message {
+    let half = message.as_bytes()[..message.len() / 2].to_vec();
+    Message::Frame(Frame::message(half, OpCode::Data(Data::Text), false))
+}
+
+
+/// Serve one connection to the stream API.
+async fn serve_stream(socket: WebSocketStream<TcpStream>, state: Arc<MockServerState>) {
+    let (sink, mut stream) = socket.split();
+    let (sender, receiver) = unbounded_channel();
+    // Tasks are aborted when the connection is dropped
+    let mut forwarder = JoinSet::new();
+    forwarder.spawn(forward_messages(receiver, sink));
+    let mut tasks = JoinSet::new();
+    let fixtures = &state.fixtures;
+    let mut keep_alive: Option<Interval> = None;
+    let mut keep_alive_sent = 0usize;
+    let mut held = Vec::new();
+    loop {
+        let message = select! {
+            message = stream.next() => match message {
+                Some(Ok(message)) => message,
+                _ => break,
+            },
+            _ = tick(&mut keep_alive) => {
+                keep_alive_sent += 1;
+                if fixtures.keep_alive_limit.is_some_and(|limit| keep_alive_sent >= limit) {
+                    keep_alive = None;
+                }
+                let message = json!({"command": STREAM_KEEP_ALIVE, "data": {"timestamp": now_ms()}});
+                if sender.send(Message::text(message.to_string())).is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let request = match parse_request(message) {
+            Some(request) => request,
+            None => continue,
+        };
+        debug!("Mock server received stream command: {:?}", request);
+        state.received_stream_commands.lock().await.push(request.clone());
+
+        if let Some(session_id) = request.get("streamSessionId") {
+            if *session_id != fixtures.stream_session_id {
+                warn!("Mock server received invalid stream session id: {:?}", session_id);
+                continue;
+            }
+        }
+
+        let fault = state.take_fault(Endpoint::Stream, &request).await;
+        match fault {
+            Some(Fault::DropConnection) => return,
+            Some(Fault::NoResponse) => continue,
+            _ => (),
+        }
+
+        let command = request["command"].as_str().unwrap_or_default();
+        match command {
+            STREAM_KEEP_ALIVE_SUBSCRIBE if fixtures.keep_alive_limit != Some(0) => {
+                keep_alive = Some(interval(fixtures.keep_alive_interval));
+            }
+            STREAM_KEEP_ALIVE_UNSUBSCRIBE => keep_alive = None,
+            _ => (),
+        }
+
+        let mut messages: Vec<String> = match (fixtures.stream_data.get(command), stream_data_command(command)) {
+            (Some(data), Some(data_command)) => data.iter().map(|item| match fault {
+                Some(Fault::MalformedJson) => MALFORMED_JSON.to_owned(),
+                _ => json!({"command": data_command, "data": item}).to_string(),
+            }).collect(),
+            _ => Vec::new(),
+        };
+        match fault {
+            Some(Fault::DropMidResponse) => {
+                let last = messages.pop();
+                for message in messages {
+                    let _ = sender.send(Message::text(message));
+                }
+                if let Some(last) = last {
+                    let _ = sender.send(partial_frame(&last));
+                }
+                forwarder.join_next().await;
+                return;
+            }
+            Some(Fault::Reorder) => {
+                held.extend(messages);
+                continue;
+            }
+            Some(Fault::Delay(delay)) => {
+                let sender = sender.clone();
+                tasks.spawn(async move {
+                    sleep(delay).await;
+                    for message in messages {
+                        let _ = sender.send(Message::text(message));
+                    }
+                });
+            }
+            _ => {
+                for message in messages {
+                    let _ = sender.send(Message::text(message));
+                }
+            }
+        }
+        for message in held.drain(..) {
+            let _ = sender.send(Message::text(message));
+        }
+    }
+}
+
+
+/// Wait for the next tick of the interval. If there is no interval, wait forever.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => { interval.tick().await; }
+        None => pending().await,
+    }
+}
+
+
+/// Parse JSON request from the websocket message. Non-text and malformed messages are skipped.
+fn parse_request(message: Message) -> Option<Value> {
+    let text = match message {
+        Message::Text(text) => text,
+        _ => return None,
+    };
+    match from_str(&text) {
+        Ok(request) => Some(request),
+        Err(err) => {
+            warn!("Mock server received malformed request {:?}: {:?}", text, err);
+            None
+        }
+    }
+}
+
+
+/// Build the error response.
+fn error_response(code: &XtbErrorCode, description: &str, tag: Value) -> Value {
+    json!({"status": false, "errorCode": code.to_string(), "errorDescr": description, "customTag": tag})
+}
+
+
+/// Return name of data messages streamed after the subscribe command.
+fn stream_data_command(subscribe_command: &str) -> Option<&'static str> {
+    match subscribe_command {
+        STREAM_BALANCE_SUBSCRIBE => Some(STREAM_BALANCE),
+        STREAM_CANDLES_SUBSCRIBE => Some(STREAM_CANDLES),
+        STREAM_KEEP_ALIVE_SUBSCRIBE => Some(STREAM_KEEP_ALIVE),
+        STREAM_NEWS_SUBSCRIBE => Some(STREAM_NEWS),
+        STREAM_PROFITS_SUBSCRIBE => Some(STREAM_PROFITS),
+        STREAM_TICK_PRICES_SUBSCRIBE => Some(STREAM_TICK_PRICES),
+        STREAM_TRADES_SUBSCRIBE => Some(STREAM_TRADES),
+        STREAM_TRADE_STATUS_SUBSCRIBE => Some(STREAM_TRADE_STATUS),
+        _ => None,
+    }
+}
+
+
+/// Current UNIX timestamp in milliseconds.
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod mock_server {
+        use std::time::Duration;
+
+        use serde_json::json;
+
+        use crate::{MockFixtures, MockServer, RequestResponseApi, StreamApi, XtbClientBuilderError, XtbClientError};
+        use crate::schema::*;
+
+        fn fixtures() -> MockFixtures {
+            MockFixtures::new()
+                .with_credentials("user", "password")
+                .with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}))
+                .with_error(COMMAND_GET_SYMBOL, XtbErrorCode::BE115, "Symbol does not exist")
+                .with_keep_alive_interval(Duration::from_millis(10))
+        }
+
+        #[tokio::test]
+        async fn client_end_to_end() {
+            let server = MockServer::start(fixtures()).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            let version = client.get_version(GetVersionRequest).await.unwrap();
+            assert_eq!(version.version, "2.5.0");
+
+            let mut stream = client.subscribe_keep_alive(StreamGetKeepAliveSubscribe).await.unwrap();
+            assert!(stream.next().await.unwrap().is_some());
+
+            let commands = server.received_commands().await;
+            assert_eq!(commands[0]["command"], COMMAND_LOGIN);
+            assert_eq!(commands[0]["arguments"]["userId"], "user");
+            let stream_commands = server.received_stream_commands().await;
+            assert!(stream_commands.iter().any(|c| c["command"] == STREAM_KEEP_ALIVE_SUBSCRIBE));
+        }
+
+        #[tokio::test]
+        async fn invalid_credentials() {
+            let server = MockServer::start(fixtures()).await.unwrap();
+            let result = server.client_builder().build("user", "wrong").await;
+            assert!(matches!(result, Err(XtbClientBuilderError::LoginFailed { .. })));
+        }
+
+        #[tokio::test]
+        async fn scripted_error_response() {
+            let server = MockServer::start(fixtures()).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            match client.get_symbol(GetSymbolRequest::default().with_symbol("FOO")).await {
+                Err(XtbClientError::CommandFailed(response)) => assert_eq!(response.error_code, XtbErrorCode::BE115),
+                other => panic!("Expected CommandFailed, but {:?}", other),
+            }
+            match client.get_server_time(GetServerTimeRequest).await {
+                Err(XtbClientError::CommandFailed(response)) => assert_eq!(response.error_code, XtbErrorCode::BE104),
+                other => panic!("Expected CommandFailed, but {:?}", other),
+            }
+        }
+
+        #[tokio::test]
+        async fn scripted_stream_data() {
+            let data = json!({"symbol": "EURUSD", "ask": 1.1, "bid": 1.0, "askVolume": 1, "bidVolume": 1, "high": 1.2, "low": 0.9, "level": 0, "quoteId": 1, "spreadRaw": 0.1, "spreadTable": 1.0, "timestamp": 1});
+            let server = MockServer::start(fixtures().with_stream_data(STREAM_TICK_PRICES_SUBSCRIBE, vec![data])).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            let mut stream = client.subscribe_tick_prices(StreamGetTickPricesSubscribe::default().with_symbol("EURUSD")).await.unwrap();
+            let tick = stream.next().await.unwrap().unwrap();
+            assert_eq!(tick.symbol, "EURUSD");
+        }
+    }
+
+    mod faults {
+        use std::time::Duration;
+
+        use serde_json::{json, Value};
+        use tokio::time::{sleep, timeout};
+        use url::Url;
+
+        use crate::{BasicMessageStream, BasicXtbConnection, BasicXtbConnectionError, BasicXtbStreamConnection, DataMessageFilter, Fault, FaultRule, MessageStream, MockFixtures, MockServer, ReconnectPolicy, RequestResponseApi, XtbClientError, XtbConnection, XtbStreamConnection};
+        use crate::schema::*;
+
+        fn fixtures() -> MockFixtures {
+            MockFixtures::new()
+                .with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}))
+                .with_response(COMMAND_GET_SERVER_TIME, json!({"time": 1, "timeString": "now"}))
+                .with_keep_alive_interval(Duration::from_millis(10))
+        }
+
+        async fn connect(server: &MockServer) -> BasicXtbConnection {
+            let connection = BasicXtbConnection::new(Url::parse(&server.api_url()).unwrap()).await.unwrap();
+            connection.send_command(COMMAND_LOGIN, Some(json!({"userId": "user", "password": "password"}))).await.unwrap().await.unwrap();
+            connection
+        }
+
+        #[tokio::test]
+        async fn drop_connection_fails_pending_request() {
+            let fixtures = fixtures().with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::DropConnection));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder()
+                .with_reconnect_policy(ReconnectPolicy::disabled())
+                .build("user", "password").await.unwrap();
+
+            let result = client.get_version(GetVersionRequest).await;
+            assert!(matches!(result, Err(XtbClientError::ConnectionClosed { .. })));
+        }
+
+        #[tokio::test]
+        async fn client_recovers_from_dropped_connection() {
+            let fixtures = fixtures().with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::DropConnection).times(1));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let policy = ReconnectPolicy::default()
+                .with_initial_delay(Duration::from_millis(10))
+                .with_check_period(Duration::from_millis(10));
+            let client = server.client_builder().with_reconnect_policy(policy).build("user", "password").await.unwrap();
+
+            assert!(client.get_version(GetVersionRequest).await.is_err());
+            let mut recovered = false;
+            for _ in 0..100 {
+                if client.get_version(GetVersionRequest).await.is_ok() {
+                    recovered = true;
+                    break;
+                }
+                sleep(Duration::from_millis(20)).await;
+            }
+            assert!(recovered);
+            let logins = server.received_commands().await.iter().filter(|c| c["command"] == COMMAND_LOGIN).count();
+            assert_eq!(logins, 2);
+        }
+
+        #[tokio::test]
+        async fn delayed_tag_does_not_block_others() {
+            let fixtures = fixtures().with_fault(FaultRule::on_tag("message_2", Fault::Delay(Duration::from_secs(10))));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let connection = connect(&server).await;
+
+            let delayed = connection.send_command(COMMAND_GET_VERSION, None).await.unwrap();
+            let other = connection.send_command(COMMAND_GET_VERSION, None).await.unwrap();
+            assert!(other.with_timeout(Duration::from_secs(1)).await.is_ok());
+            match delayed.with_timeout(Duration::from_millis(50)).await {
+                Err(BasicXtbConnectionError::Timeout { tag }) => assert_eq!(tag, "message_2"),
+                other => panic!("Expected Timeout, but {:?}", other),
+            }
+        }
+
+        #[tokio::test]
+        async fn reordered_responses_are_routed_by_tags() {
+            let fixtures = fixtures().with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::Reorder));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let connection = connect(&server).await;
+
+            let version = connection.send_command(COMMAND_GET_VERSION, None).await.unwrap();
+            let time = connection.send_command(COMMAND_GET_SERVER_TIME, None).await.unwrap();
+            let version = version.with_timeout(Duration::from_secs(1)).await.unwrap().unwrap();
+            let time = time.with_timeout(Duration::from_secs(1)).await.unwrap().unwrap();
+            assert_eq!(version.return_data, Some(json!({"version": "2.5.0"})));
+            assert_eq!(time.return_data, Some(json!({"time": 1, "timeString": "now"})));
+        }
+
+        #[tokio::test]
+        async fn injected_error_response() {
+            let fault = Fault::Error(XtbErrorCode::BE200, "Session timed out".to_owned());
+            let fixtures = fixtures().with_fault(FaultRule::on_command(COMMAND_GET_VERSION, fault).times(1));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            match client.get_version(GetVersionRequest).await {
+                Err(XtbClientError::CommandFailed(response)) => assert_eq!(response.error_code, XtbErrorCode::BE200),
+                other => panic!("Expected CommandFailed, but {:?}", other),
+            }
+            assert!(client.get_version(GetVersionRequest).await.is_ok());
+        }
+
+        #[tokio::test]
+        async fn malformed_response_is_skipped() {
+            let fixtures = fixtures().with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::MalformedJson).times(1));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let connection = connect(&server).await;
+
+            let malformed = connection.send_command(COMMAND_GET_VERSION, None).await.unwrap();
+            assert!(malformed.with_timeout(Duration::from_millis(50)).await.is_err());
+            let valid = connection.send_command(COMMAND_GET_VERSION, None).await.unwrap();
+            assert!(valid.with_timeout(Duration::from_secs(1)).await.is_ok());
+            assert!(connection.is_connected());
+        }
+
+        /// Open the stream connection to the `server` and make stream of all messages.
+        async fn connect_stream(server: &MockServer) -> (BasicXtbStreamConnection, BasicMessageStream) {
+            let url = Url::parse(&server.stream_api_url()).unwrap();
+            let mut connection = BasicXtbStreamConnection::new(url, "mock_stream_session".to_owned()).await.unwrap();
+            let stream = connection.make_message_stream(DataMessageFilter::Always).await;
+            (connection, stream)
+        }
+
+        fn tick(symbol: &str) -> Value {
+            json!({"symbol": symbol, "ask": 1.1, "bid": 1.0})
+        }
+
+        #[tokio::test]
+        async fn drop_mid_response_fails_pending_request() {
+            let fixtures = fixtures().with_fault(FaultRule::on_command(COMMAND_GET_VERSION, Fault::DropMidResponse));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let connection = connect(&server).await;
+
+            let version = connection.send_command(COMMAND_GET_VERSION, None).await.unwrap();
+            let result = version.with_timeout(Duration::from_secs(1)).await;
+            assert!(matches!(result, Err(BasicXtbConnectionError::ConnectionClosed { .. })));
+            sleep(Duration::from_millis(50)).await;
+            assert!(!connection.is_connected());
+        }
+
+        #[tokio::test]
+        async fn drop_mid_stream_data() {
+            let fixtures = fixtures()
+                .with_stream_data(STREAM_TICK_PRICES_SUBSCRIBE, vec![tick("EURUSD"), tick("GBPUSD")])
+                .with_fault(FaultRule::on_subscription(STREAM_TICK_PRICES_SUBSCRIBE, Fault::DropMidResponse));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let (mut connection, mut stream) = connect_stream(&server).await;
+
+            connection.subscribe(STREAM_TICK_PRICES_SUBSCRIBE, Some(json!({"symbol": "EURUSD"}))).await.unwrap();
+            let first = timeout(Duration::from_secs(1), stream.next()).await.unwrap().unwrap();
+            assert_eq!(first.data["symbol"], "EURUSD");
+            sleep(Duration::from_millis(50)).await;
+            assert!(!connection.is_connected());
+        }
+
+        #[tokio::test]
+        async fn delayed_subscription_does_not_block_others() {
+            let fixtures = fixtures()
+                .with_stream_data(STREAM_TICK_PRICES_SUBSCRIBE, vec![tick("EURUSD")])
+                .with_fault(FaultRule::on_subscription(STREAM_TICK_PRICES_SUBSCRIBE, Fault::Delay(Duration::from_secs(10))));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let (mut connection, mut stream) = connect_stream(&server).await;
+
+            connection.subscribe(STREAM_TICK_PRICES_SUBSCRIBE, Some(json!({"symbol": "EURUSD"}))).await.unwrap();
+            connection.subscribe(STREAM_KEEP_ALIVE_SUBSCRIBE, None).await.unwrap();
+            let message = timeout(Duration::from_secs(1), stream.next()).await.unwrap().unwrap();
+            assert_eq!(message.command, STREAM_KEEP_ALIVE);
+        }
+
+        #[tokio::test]
+        async fn reordered_stream_data() {
+            let fixtures = fixtures()
+                .with_stream_data(STREAM_TICK_PRICES_SUBSCRIBE, vec![tick("EURUSD")])
+                .with_stream_data(STREAM_TRADES_SUBSCRIBE, vec![json!({"order": 1})])
+                .with_fault(FaultRule::on_subscription(STREAM_TICK_PRICES_SUBSCRIBE, Fault::Reorder));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let (mut connection, mut stream) = connect_stream(&server).await;
+
+            connection.subscribe(STREAM_TICK_PRICES_SUBSCRIBE, Some(json!({"symbol": "EURUSD"}))).await.unwrap();
+            connection.subscribe(STREAM_TRADES_SUBSCRIBE, None).await.unwrap();
+            let first = timeout(Duration::from_secs(1), stream.next()).await.unwrap().unwrap();
+            let second = timeout(Duration::from_secs(1), stream.next()).await.unwrap().unwrap();
+            assert_eq!(first.command, STREAM_TRADES);
+            assert_eq!(second.command, STREAM_TICK_PRICES);
+        }
+
+        #[test]
+        #[should_panic(expected = "the error cannot be injected into the subscription")]
+        fn error_cannot_be_injected_into_subscription() {
+            let fault = Fault::Error(XtbErrorCode::BE200, "Session timed out".to_owned());
+            let _ = fixtures().with_fault(FaultRule::on_subscription(STREAM_TICK_PRICES_SUBSCRIBE, fault));
+        }
+
+        #[tokio::test]
+        async fn keep_alive_stops() {
+            let server = MockServer::start(fixtures().with_keep_alive_limit(2)).await.unwrap();
+            let url = Url::parse(&server.stream_api_url()).unwrap();
+            let mut connection = BasicXtbStreamConnection::new(url, "mock_stream_session".to_owned()).await.unwrap();
+            let mut stream = connection.make_message_stream(DataMessageFilter::Command(STREAM_KEEP_ALIVE.to_owned())).await;
+
+            connection.subscribe(STREAM_KEEP_ALIVE_SUBSCRIBE, None).await.unwrap();
+            for _ in 0..2 {
+                assert!(stream.next().await.is_some());
+            }
+            assert!(timeout(Duration::from_millis(100), stream.next()).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use derive_setters::Setters;
+use tokio::sync::Mutex;
+use tokio::time::{Instant, sleep};
+use tracing::debug;
+
+
+/// Configuration of the request rate limiter.
+///
+/// The XTB server rejects requests sent too frequently (`BE014` error code) and drops connections
+/// exceeding the request limits. The limiter combines two rules:
+///
+/// * `min_interval` - minimal interval between two consecutive requests.
+/// * token bucket - at most `burst_size` requests can be sent at once. One token is returned
+///   to the bucket every `refill_interval`.
+///
+/// Default values follow the official documentation: the minimal interval is 200ms and no more
+/// than 5 requests can be sent within 200ms.
+#[derive(Clone, Debug, Setters)]
+#[setters(into, prefix = "with_")]
+pub struct RateLimit {
+    /// Minimal interval between two consecutive requests
+    min_interval: Duration,
+    /// Capacity of the token bucket
+    burst_size: u32,
+    /// Interval in which one token is returned to the bucket
+    refill_interval: Duration,
+}
+
+
+impl RateLimit {
+    /// Create configuration without any limit.
+    pub fn unlimited() -> Self {
+        Self {
+            min_interval: Duration::ZERO,
+            burst_size: 1,
+            refill_interval: Duration::ZERO,
+        }
+    }
+}
+
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_millis(200),
+            burst_size: 5,
+            refill_interval: Duration::from_millis(200),
+        }
+    }
+}
+
+
+/// Inner state of the `RateLimiter`
+#[derive(Debug)]
+struct RateLimiterState {
+    /// Number of tokens available in the bucket
+    tokens: u32,
+    /// Time of the last token refill
+    last_refill: Instant,
+    /// Time when the last request was sent
+    last_sent: Option<Instant>,
+}
+
+
+/// Pace requests according to the `RateLimit`.
+///
+/// Callers of the `acquire` method are queued in FIFO order and each of them waits until
+/// the request can be sent.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Limiter configuration
+    config: RateLimit,
+    /// Token bucket state. The lock is held while a caller is waiting, so other callers are queued.
+    state: Mutex<RateLimiterState>,
+}
+
+
+impl RateLimiter {
+    /// Create new instance with full token bucket.
+    pub fn new(config: RateLimit) -> Self {
+        let state = RateLimiterState {
+            tokens: config.burst_size,
+            last_refill: Instant::now(),
+            last_sent: None,
+        };
+        Self {
+            config,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Wait until a request can be sent and take a token from the bucket.
+    pub async fn acquire(&self) {
+        let mut state = self.state.lock().await;
+        loop {
+            let now = Instant::now();
+            self.refill(&mut state, now);
+            let wait = self.time_to_wait(&state, now);
+            if wait.is_zero() {
+                state.tokens = state.tokens.saturating_sub(1);
+                state.last_sent = Some(now);
+                return;
+            }
+            debug!("Request rate limit reached, waiting {:?}", wait);
+            sleep(wait).await;
+        }
+    }
+
+    /// Return tokens to the bucket for the time elapsed from the last refill.
+    fn refill(&self, state: &mut RateLimiterState, now: Instant) {
+        if self.config.refill_interval.is_zero() {
+            state.tokens = self.config.burst_size;
+            state.last_refill = now;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        let new_tokens = (elapsed.as_nanos() / self.config.refill_interval.as_nanos()) as u32;
+        if new_tokens > 0 {
+            state.tokens = state.tokens.saturating_add(new_tokens).min(self.config.burst_size);
+            state.last_refill += self.config.refill_interval * new_tokens;
+        }
+        if state.tokens == self.config.burst_size {
+            state.last_refill = now;
+        }
+    }
+
+    /// Compute how long a caller has to wait before the next request can be sent.
+    fn time_to_wait(&self, state: &RateLimiterState, now: Instant) -> Duration {
+        let interval_wait = match state.last_sent {
+            Some(last_sent) => (last_sent + self.config.min_interval).saturating_duration_since(now),
+            None => Duration::ZERO,
+        };
+        let token_wait = if state.tokens > 0 || self.config.burst_size == 0 {
+            Duration::ZERO
+        } else {
+            (state.last_refill + self.config.refill_interval).saturating_duration_since(now)
+        };
+        interval_wait.max(token_wait)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod rate_limiter {
+        use std::time::Duration;
+
+        use rstest::rstest;
+        use tokio::time::Instant;
+
+        use crate::{RateLimit, RateLimiter};
+
+        #[rstest]
+        #[case(Duration::from_millis(200), 5, Duration::from_millis(200), 5, Duration::from_millis(800))]
+        #[case(Duration::ZERO, 5, Duration::from_millis(200), 5, Duration::ZERO)]
+        #[case(Duration::ZERO, 5, Duration::from_millis(200), 7, Duration::from_millis(400))]
+        #[case(Duration::from_millis(100), 1, Duration::from_millis(300), 3, Duration::from_millis(600))]
+        #[tokio::test(start_paused = true)]
+        async fn pacing(
+            #[case] min_interval: Duration,
+            #[case] burst_size: u32,
+            #[case] refill_interval: Duration,
+            #[case] requests: usize,
+            #[case] expected_duration: Duration,
+        ) {
+            let config = RateLimit::default()
+                .with_min_interval(min_interval)
+                .with_burst_size(burst_size)
+                .with_refill_interval(refill_interval);
+            let limiter = RateLimiter::new(config);
+            let start = Instant::now();
+            for _ in 0..requests {
+                limiter.acquire().await;
+            }
+            assert_eq!(start.elapsed(), expected_duration);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn bucket_is_refilled() {
+            let config = RateLimit::default().with_min_interval(Duration::ZERO);
+            let limiter = RateLimiter::new(config);
+            for _ in 0..5 {
+                limiter.acquire().await;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            let start = Instant::now();
+            for _ in 0..5 {
+                limiter.acquire().await;
+            }
+            assert_eq!(start.elapsed(), Duration::ZERO);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn unlimited() {
+            let limiter = RateLimiter::new(RateLimit::unlimited());
+            let start = Instant::now();
+            for _ in 0..100 {
+                limiter.acquire().await;
+            }
+            assert_eq!(start.elapsed(), Duration::ZERO);
+        }
+    }
+}
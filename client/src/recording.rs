@@ -0,0 +1,717 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::future::ready;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, sync_channel, SyncSender, TrySendError};
+use std::task::{Context, Poll};
+use std::thread::Builder;
+use std::time::Duration;
+
+use futures_util::{Sink, StreamExt};
+use futures_util::stream::{BoxStream, iter};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string, Value};
+use tokio::sync::{oneshot, watch};
+use tokio::task::spawn_blocking;
+use tokio::time::{Instant, sleep};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::error;
+
+use crate::schema::COMMAND_LOGIN;
+use crate::transport::{Transport, TransportError};
+
+
+/// Replacement of redacted values (e.g. the login password).
+const REDACTED: &str = "********";
+
+
+/// Interval between flushes of the recording file.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+
+/// Default number of captured messages waiting for the writer thread of the `SessionRecorder`
+pub const DEFAULT_RECORDER_CAPACITY: usize = 4096;
+
+
+/// Direction of a recorded message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireDirection {
+    /// Message sent to the server
+    Outgoing,
+    /// Message received from the server
+    Incoming,
+}
+
+
+/// Kind of the recorded connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireConnectionKind {
+    /// The request/response API connection
+    Api,
+    /// The stream API connection
+    Stream,
+}
+
+
+/// One line of the recording.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WireRecord {
+    /// Microseconds elapsed since the recorder was created (monotonic clock)
+    pub timestamp_us: u64,
+    /// Identifier of the connection unique within the recording. Every socket (including
+    /// the reconnected one) has its own identifier.
+    pub connection_id: u64,
+    /// Kind of the connection
+    pub connection_kind: WireConnectionKind,
+    /// Direction of the message
+    pub direction: WireDirection,
+    /// The message content. JSON messages are stored as JSON values, other text messages are
+    /// stored as strings.
+    pub message: Value,
+}
+
+
+impl WireRecord {
+    /// Convert the recorded content back into the websocket message.
+    pub fn to_message(&self) -> Message {
+        match &self.message {
+            Value::String(text) => Message::text(text.clone()),
+            value => Message::text(value.to_string()),
+        }
+    }
+}
+
+
+/// Request to the writer thread of the `SessionRecorder`.
+#[derive(Debug)]
+enum WriterCommand {
+    /// Write the message captured by the tap
+    Record(CapturedMessage),
+    /// Flush the file and confirm it by the sender
+    Flush(oneshot::Sender<io::Result<()>>),
+}
+
+
+/// Message captured by the `WireTap`. It is converted into the `WireRecord` by the writer thread.
+#[derive(Debug)]
+struct CapturedMessage {
+    /// Microseconds elapsed since the recorder was created
+    timestamp_us: u64,
+    /// Identifier of the tapped connection
+    connection_id: u64,
+    /// Kind of the tapped connection
+    connection_kind: WireConnectionKind,
+    /// Direction of the message
+    direction: WireDirection,
+    /// Text of the message
+    text: String,
+}
+
+
+impl CapturedMessage {
+    /// Parse the message content and build the record. The login password is redacted.
+    fn into_record(self) -> WireRecord {
+        let mut content = from_str(&self.text).unwrap_or(Value::String(self.text));
+        if self.direction == WireDirection::Outgoing {
+            redact(&mut content);
+        }
+        WireRecord {
+            timestamp_us: self.timestamp_us,
+            connection_id: self.connection_id,
+            connection_kind: self.connection_kind,
+            direction: self.direction,
+            message: content,
+        }
+    }
+}
+
+
+/// Inner state of the `SessionRecorder`
+///
+/// The writer thread is not joined when the state is dropped. It writes the remaining records,
+/// flushes the file and stops when the channel is closed.
+#[derive(Debug)]
+struct SessionRecorderState {
+    /// Sender of commands to the writer thread
+    commands: SyncSender<WriterCommand>,
+    /// Number of records dropped because the writer thread did not keep up
+    dropped_records: AtomicU64,
+    /// Time the recording started
+    started: Instant,
+    /// Last used connection identifier
+    last_connection_id: AtomicU64,
+}
+
+
+/// Wire-tap of the websocket traffic.
+///
+/// The recorder writes every text message sent or received by tapped connections into a JSONL file
+/// (one `WireRecord` per line). The password of the `login` command is redacted.
+///
+/// Records are serialized and written by a background thread, so tapped connections never wait
+/// for the file. At most `capacity` records wait for the thread, further records are dropped and
+/// counted (see `dropped_records`). The file is flushed every 100 ms, by the `flush` method and
+/// when the last clone of the recorder (including its taps) is dropped.
+///
+/// The recorder can be cloned and all clones write into the same file.
+#[derive(Clone, Debug)]
+pub struct SessionRecorder {
+    /// The shared state
+    state: Arc<SessionRecorderState>,
+}
+
+
+impl SessionRecorder {
+    /// Create the recording file. If the file exists, new records are appended.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::create_with_capacity(path, DEFAULT_RECORDER_CAPACITY)
+    }
+
+    /// Same as the `create`, but up to `capacity` records wait for the writer thread.
+    pub fn create_with_capacity(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Self::start(BufWriter::new(file), capacity)
+    }
+
+    /// Start the writer thread writing records into the `writer`.
+    fn start(writer: impl Write + Send + 'static, capacity: usize) -> io::Result<Self> {
+        let (commands, receiver) = sync_channel(capacity);
+        Builder::new()
+            .name("xtb-session-recorder".to_owned())
+            .spawn(move || write_records(writer, receiver))?;
+        let state = SessionRecorderState {
+            commands,
+            dropped_records: AtomicU64::new(0),
+            started: Instant::now(),
+            last_connection_id: AtomicU64::new(0),
+        };
+        Ok(Self { state: Arc::new(state) })
+    }
+
+    /// Create the tap for a new connection.
+    pub fn tap(&self, kind: WireConnectionKind) -> WireTap {
+        let connection_id = self.state.last_connection_id.fetch_add(1, Ordering::Relaxed) + 1;
+        WireTap { recorder: self.clone(), connection_id, kind }
+    }
+
+    /// Return number of records dropped because the writer thread did not keep up (e.g. because
+    /// of a slow disk).
+    pub fn dropped_records(&self) -> u64 {
+        self.state.dropped_records.load(Ordering::Relaxed)
+    }
+
+    /// Write all records captured so far into the file and flush it.
+    ///
+    /// The flush waits until the writer thread takes all records queued before it.
+    pub async fn flush(&self) -> io::Result<()> {
+        let (confirm, confirmed) = oneshot::channel();
+        let commands = self.state.commands.clone();
+        let stopped = || io::Error::other("The recorder writer is stopped");
+        spawn_blocking(move || commands.send(WriterCommand::Flush(confirm)))
+            .await
+            .map_err(io::Error::other)?
+            .map_err(|_| stopped())?;
+        confirmed.await.unwrap_or_else(|_| Err(stopped()))
+    }
+
+    /// Pass the record to the writer thread. The record is dropped if the writer does not keep up.
+    fn send_record(&self, message: CapturedMessage) {
+        match self.state.commands.try_send(WriterCommand::Record(message)) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => {
+                self.state.dropped_records.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => error!("Cannot pass the wire record to the writer, the writer is stopped"),
+        }
+    }
+}
+
+
+/// Write records received from the `receiver` into the `writer`. The writer is flushed
+/// periodically and when the channel is closed.
+fn write_records(mut writer: impl Write, receiver: Receiver<WriterCommand>) {
+    let mut dirty = false;
+    loop {
+        match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(WriterCommand::Record(message)) => {
+                if let Err(err) = write_record(&mut writer, &message.into_record()) {
+                    error!("Cannot write the wire record: {:?}", err);
+                }
+                dirty = true;
+            }
+            Ok(WriterCommand::Flush(confirm)) => {
+                let _ = confirm.send(writer.flush());
+                dirty = false;
+            }
+            Err(RecvTimeoutError::Timeout) if dirty => {
+                if let Err(err) = writer.flush() {
+                    error!("Cannot flush the recording: {:?}", err);
+                }
+                dirty = false;
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    if let Err(err) = writer.flush() {
+        error!("Cannot flush the recording: {:?}", err);
+    }
+}
+
+
+/// Write the record as one line.
+fn write_record(writer: &mut impl Write, record: &WireRecord) -> io::Result<()> {
+    let line = to_string(record)?;
+    writeln!(writer, "{}", line)
+}
+
+
+/// Tap of one connection made by the `SessionRecorder`.
+#[derive(Clone, Debug)]
+pub struct WireTap {
+    /// The recorder records are written to
+    recorder: SessionRecorder,
+    /// Identifier of the tapped connection
+    connection_id: u64,
+    /// Kind of the tapped connection
+    kind: WireConnectionKind,
+}
+
+
+impl WireTap {
+    /// Identifier of the tapped connection.
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// Create the tap of the same kind for a new (e.g. reconnected) connection.
+    pub fn renew(&self) -> Self {
+        self.recorder.tap(self.kind)
+    }
+
+    /// Record the message. Non-text messages are ignored.
+    ///
+    /// The message is only passed to the writer thread of the recorder, the call never waits for
+    /// the file.
+    pub fn record(&self, direction: WireDirection, message: &Message) {
+        self.record_at(direction, message, Instant::now());
+    }
+
+    /// Record the message which was sent or received at the `time`.
+    ///
+    /// Outgoing messages are recorded after they are sent, but with the time before the send. So
+    /// they are ordered before responses received in the meantime.
+    pub(crate) fn record_at(&self, direction: WireDirection, message: &Message, time: Instant) {
+        let text = match message {
+            Message::Text(text) => text,
+            _ => return,
+        };
+        let message = CapturedMessage {
+            timestamp_us: time.saturating_duration_since(self.recorder.state.started).as_micros() as u64,
+            connection_id: self.connection_id,
+            connection_kind: self.kind,
+            direction,
+            text: text.clone(),
+        };
+        self.recorder.send_record(message);
+    }
+}
+
+
+/// Hide secrets in the outgoing message.
+fn redact(message: &mut Value) {
+    if message["command"] != COMMAND_LOGIN {
+        return;
+    }
+    if let Some(password) = message.get_mut("arguments").and_then(|arguments| arguments.get_mut("password")) {
+        *password = Value::String(REDACTED.to_owned());
+    }
+}
+
+
+/// Read all records from the recording file.
+pub fn read_recording(path: impl AsRef<Path>) -> io::Result<Vec<WireRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(from_str(&line)?);
+    }
+    Ok(records)
+}
+
+
+/// Transport replaying incoming messages of one recorded connection.
+///
+/// The stream of the transport yields recorded incoming messages and then it is finished (as if
+/// the server closed the connection). Outgoing messages are discarded.
+///
+/// In the lockstep mode (default), an incoming message is yielded only when the same number of
+/// outgoing messages was sent through the transport as was recorded before it. So responses are
+/// not delivered before the requests they answer are sent. If the timing is kept, messages are
+/// also delayed by the recorded intervals.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    /// Incoming messages to replay
+    messages: Vec<ReplayedMessage>,
+    /// Wait for outgoing messages
+    lockstep: bool,
+    /// Keep the recorded intervals between messages
+    keep_timing: bool,
+}
+
+
+/// Incoming message replayed by the `ReplayTransport`.
+#[derive(Debug)]
+struct ReplayedMessage {
+    /// Recorded interval from the previous incoming message
+    delay: Duration,
+    /// Number of outgoing messages recorded before the message
+    sent_before: usize,
+    /// The message
+    message: Message,
+}
+
+
+impl ReplayTransport {
+    /// Create transport replaying incoming messages of the connection from records.
+    ///
+    /// Records are replayed in order of their timestamps (a request may be written into the file
+    /// after its response).
+    pub fn from_records(records: &[WireRecord], connection_id: u64) -> Self {
+        let mut records: Vec<_> = records.iter().filter(|record| record.connection_id == connection_id).collect();
+        records.sort_by_key(|record| record.timestamp_us);
+        let mut last_timestamp = None;
+        let mut sent_before = 0usize;
+        let mut messages = Vec::new();
+        for record in records {
+            if record.direction == WireDirection::Outgoing {
+                sent_before += 1;
+                continue;
+            }
+            let delay = last_timestamp.map_or(0, |last| record.timestamp_us.saturating_sub(last));
+            last_timestamp = Some(record.timestamp_us);
+            messages.push(ReplayedMessage { delay: Duration::from_micros(delay), sent_before, message: record.to_message() });
+        }
+        Self { messages, lockstep: true, keep_timing: false }
+    }
+
+    /// Create transport replaying incoming messages of the connection from the recording file.
+    pub fn from_file(path: impl AsRef<Path>, connection_id: u64) -> io::Result<Self> {
+        Ok(Self::from_records(&read_recording(path)?, connection_id))
+    }
+
+    /// Turn the lockstep mode on or off.
+    pub fn with_lockstep(mut self, lockstep: bool) -> Self {
+        self.lockstep = lockstep;
+        self
+    }
+
+    /// Keep recorded intervals between messages. Messages are replayed immediately by default.
+    pub fn with_timing(mut self, keep_timing: bool) -> Self {
+        self.keep_timing = keep_timing;
+        self
+    }
+}
+
+
+impl Transport for ReplayTransport {
+    type Sink = ReplaySink;
+
+    type Stream = BoxStream<'static, Result<Message, TransportError>>;
+
+    fn split(self) -> (Self::Sink, Self::Stream) {
+        let (sent_sender, sent_receiver) = watch::channel(0usize);
+        let lockstep = self.lockstep;
+        let keep_timing = self.keep_timing;
+        let stream = iter(self.messages)
+            .then(move |replayed| {
+                let mut sent_receiver = sent_receiver.clone();
+                async move {
+                    if lockstep && sent_receiver.wait_for(|sent| *sent >= replayed.sent_before).await.is_err() {
+                        return None;
+                    }
+                    if keep_timing && !replayed.delay.is_zero() {
+                        sleep(replayed.delay).await;
+                    }
+                    Some(Ok(replayed.message))
+                }
+            })
+            .take_while(|item| ready(item.is_some()))
+            .filter_map(ready)
+            .boxed();
+        (ReplaySink(sent_sender), stream)
+    }
+}
+
+
+/// Sink part of the `ReplayTransport`. Messages are counted and discarded.
+#[derive(Debug)]
+pub struct ReplaySink(watch::Sender<usize>);
+
+
+impl Sink<Message> for ReplaySink {
+    type Error = TransportError;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        // only text messages are recorded
+        if item.is_text() {
+            self.0.send_modify(|sent| *sent += 1);
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod recording {
+        use std::env::temp_dir;
+        use std::io::Write;
+        use std::path::PathBuf;
+        use std::process;
+        use std::sync::{Arc, Mutex};
+        use std::sync::mpsc::{channel, Receiver};
+        use std::thread::sleep;
+        use std::time::{Duration, Instant};
+
+        use serde_json::json;
+        use tokio_tungstenite::tungstenite::Message;
+
+        use crate::{read_recording, SessionRecorder, WireConnectionKind, WireDirection};
+
+        fn recording_path(name: &str) -> PathBuf {
+            let path = temp_dir().join(format!("xtb_client_{}_{}.jsonl", name, process::id()));
+            let _ = std::fs::remove_file(&path);
+            path
+        }
+
+        #[tokio::test]
+        async fn record_and_redact_password() {
+            let path = recording_path("redact");
+            let recorder = SessionRecorder::create(&path).unwrap();
+            let api = recorder.tap(WireConnectionKind::Api);
+            let stream = recorder.tap(WireConnectionKind::Stream);
+
+            let login = json!({"command": "login", "arguments": {"userId": "user", "password": "secret"}, "customTag": "message_1"});
+            api.record(WireDirection::Outgoing, &Message::text(login.to_string()));
+            api.record(WireDirection::Incoming, &Message::text("not a json"));
+            api.record(WireDirection::Incoming, &Message::Ping(vec![]));
+            stream.record(WireDirection::Incoming, &Message::text(r#"{"command": "keepAlive"}"#));
+            recorder.flush().await.unwrap();
+
+            let records = read_recording(&path).unwrap();
+            assert_eq!(records.len(), 3);
+            assert_eq!(records[0].message["arguments"]["password"], "********");
+            assert_eq!(records[0].message["arguments"]["userId"], "user");
+            assert_eq!(records[1].message, json!("not a json"));
+            assert_eq!(records[1].to_message(), Message::text("not a json"));
+            assert_eq!(records[2].connection_kind, WireConnectionKind::Stream);
+            assert_ne!(records[0].connection_id, records[2].connection_id);
+            assert!(records.windows(2).all(|pair| pair[0].timestamp_us <= pair[1].timestamp_us));
+            assert_ne!(api.renew().connection_id(), api.connection_id());
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn records_are_flushed_when_recorder_is_dropped() {
+            let path = recording_path("drop");
+            let recorder = SessionRecorder::create(&path).unwrap();
+            let tap = recorder.tap(WireConnectionKind::Api);
+            tap.record(WireDirection::Incoming, &Message::text(r#"{"status": true}"#));
+
+            // the tap keeps the recorder alive
+            drop(recorder);
+            drop(tap);
+            // the writer thread is not joined, it flushes the file in the background
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while read_recording(&path).unwrap().is_empty() && Instant::now() < deadline {
+                sleep(Duration::from_millis(10));
+            }
+            assert_eq!(read_recording(&path).unwrap().len(), 1);
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[tokio::test]
+        async fn records_are_dropped_when_writer_does_not_keep_up() {
+            let (open, gate) = channel::<()>();
+            let written = Arc::new(Mutex::new(Vec::new()));
+            let recorder = SessionRecorder::start(GatedWriter { gate, written: written.clone() }, 1).unwrap();
+            let tap = recorder.tap(WireConnectionKind::Stream);
+
+            for _ in 0..4 {
+                tap.record(WireDirection::Incoming, &Message::text(r#"{"command": "keepAlive"}"#));
+            }
+            // one record is written (or queued) and one is queued at most
+            assert!(recorder.dropped_records() >= 2);
+
+            drop(open);
+            recorder.flush().await.unwrap();
+            let lines = written.lock().unwrap().iter().filter(|byte| **byte == b'\n').count() as u64;
+            assert_eq!(lines + recorder.dropped_records(), 4);
+        }
+
+        /// Writer blocked until the gate is closed.
+        struct GatedWriter {
+            gate: Receiver<()>,
+            written: Arc<Mutex<Vec<u8>>>,
+        }
+
+        impl Write for GatedWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let _ = self.gate.recv();
+                self.written.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+    }
+
+    mod replay_transport {
+        use std::time::Duration;
+
+        use futures_util::{SinkExt, StreamExt};
+        use serde_json::json;
+        use tokio::time::{Instant, timeout};
+        use tokio_tungstenite::tungstenite::Message;
+
+        use crate::{ReplayTransport, Transport, WireConnectionKind, WireDirection, WireRecord};
+
+        fn record(timestamp_us: u64, connection_id: u64, direction: WireDirection, tag: &str) -> WireRecord {
+            WireRecord {
+                timestamp_us,
+                connection_id,
+                connection_kind: WireConnectionKind::Api,
+                direction,
+                message: json!({"status": true, "customTag": tag}),
+            }
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn replay_incoming_messages_of_connection() {
+            let records = vec![
+                record(0, 1, WireDirection::Outgoing, "message_1"),
+                record(100, 1, WireDirection::Incoming, "message_1"),
+                record(200, 2, WireDirection::Incoming, "other"),
+                record(50_100, 1, WireDirection::Incoming, "message_2"),
+            ];
+            let (_, mut stream) = ReplayTransport::from_records(&records, 1)
+                .with_lockstep(false)
+                .with_timing(true)
+                .split();
+
+            let start = Instant::now();
+            let mut tags = Vec::new();
+            while let Some(message) = stream.next().await {
+                let value: serde_json::Value = serde_json::from_str(&message.unwrap().to_string()).unwrap();
+                tags.push(value["customTag"].as_str().unwrap().to_owned());
+            }
+            assert_eq!(tags, vec!["message_1", "message_2"]);
+            assert_eq!(start.elapsed(), Duration::from_millis(50));
+        }
+
+        #[tokio::test]
+        async fn lockstep_waits_for_requests() {
+            let records = vec![
+                record(0, 1, WireDirection::Outgoing, "message_1"),
+                record(100, 1, WireDirection::Incoming, "message_1"),
+            ];
+            let (mut sink, mut stream) = ReplayTransport::from_records(&records, 1).split();
+
+            assert!(timeout(Duration::from_millis(20), stream.next()).await.is_err());
+            sink.send(Message::text("request")).await.unwrap();
+            assert!(stream.next().await.unwrap().is_ok());
+            assert!(stream.next().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn records_are_replayed_by_timestamps() {
+            // the request was written after its response
+            let records = vec![
+                record(100, 1, WireDirection::Incoming, "message_1"),
+                record(0, 1, WireDirection::Outgoing, "message_1"),
+            ];
+            let (mut sink, mut stream) = ReplayTransport::from_records(&records, 1).split();
+
+            assert!(timeout(Duration::from_millis(20), stream.next()).await.is_err());
+            sink.send(Message::text("request")).await.unwrap();
+            assert!(stream.next().await.unwrap().is_ok());
+        }
+    }
+
+    mod session_replay {
+        use std::env::temp_dir;
+        use std::process;
+        use std::time::Duration;
+
+        use serde_json::json;
+
+        use crate::{BasicXtbConnection, MockFixtures, MockServer, read_recording, ReplayTransport, RequestResponseApi, SessionRecorder, WireConnectionKind, WireDirection, XtbConnection};
+        use crate::schema::{COMMAND_GET_VERSION, COMMAND_LOGIN, GetVersionRequest};
+
+        #[tokio::test]
+        async fn replay_recorded_session() {
+            let path = temp_dir().join(format!("xtb_client_session_{}.jsonl", process::id()));
+            let _ = std::fs::remove_file(&path);
+            let fixtures = MockFixtures::new().with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let recorder = SessionRecorder::create(&path).unwrap();
+            let client = server.client_builder()
+                .with_recorder(recorder.clone())
+                .build("user", "secret").await.unwrap();
+            client.get_version(GetVersionRequest).await.unwrap();
+            drop(client);
+            recorder.flush().await.unwrap();
+
+            let records = read_recording(&path).unwrap();
+            let login = records.iter().find(|r| r.message["command"] == COMMAND_LOGIN).unwrap();
+            assert_eq!(login.message["arguments"]["password"], "********");
+            assert!(records.iter().any(|r| r.connection_kind == WireConnectionKind::Stream));
+            assert!(!records.iter().any(|r| r.to_message().to_string().contains("secret")));
+
+            // replay the request/response connection; tags are deterministic, so responses are routed
+            let api = records.iter().find(|r| r.connection_kind == WireConnectionKind::Api).unwrap().connection_id;
+            let outgoing: Vec<_> = records.iter()
+                .filter(|r| r.connection_id == api && r.direction == WireDirection::Outgoing)
+                .map(|r| r.message["command"].as_str().unwrap().to_owned())
+                .collect();
+            let connection = BasicXtbConnection::from_transport(ReplayTransport::from_file(&path, api).unwrap());
+            let mut version = None;
+            for command in outgoing {
+                let promise = connection.send_command(&command, None).await.unwrap();
+                if command == COMMAND_GET_VERSION {
+                    version = Some(promise);
+                }
+            }
+            let version = version.unwrap().with_timeout(Duration::from_secs(1)).await.unwrap().unwrap();
+            assert_eq!(version.return_data, Some(json!({"version": "2.5.0"})));
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+
@@ -1,3 +1,8 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::schema::{GetAllSymbolsRequest, GetAllSymbolsResponse, GetCalendarRequest, GetCalendarResponse, GetChartLastRequestRequest, GetChartLastRequestResponse, GetChartRangeRequestRequest, GetChartRangeRequestResponse, GetCommissionDefRequest, GetCommissionDefResponse, GetCurrentUserDataRequest, GetCurrentUserDataResponse, GetIbsHistoryRequest, GetIbsHistoryResponse, GetMarginLevelRequest, GetMarginLevelResponse, GetMarginTradeRequest, GetMarginTradeResponse, GetNewsRequest, GetNewsResponse, GetProfitCalculationRequest, GetProfitCalculationResponse, GetServerTimeRequest, GetServerTimeResponse, GetStepRulesRequest, GetStepRulesResponse, GetSymbolRequest, GetSymbolResponse, GetTickPricesRequest, GetTickPricesResponse, GetTradeRecordsRequest, GetTradeRecordsResponse, GetTradesHistoryRequest, GetTradesHistoryResponse, GetTradesRequest, GetTradesResponse, GetTradingHoursRequest, GetTradingHoursResponse, GetVersionRequest, GetVersionResponse, PingRequest, PingResponse, TradeTransactionRequest, TradeTransactionResponse, TradeTransactionStatusRequest, TradeTransactionStatusResponse};
+
 
 pub const STREAM_BALANCE_SUBSCRIBE: &'static str = "getBalance";
 pub const STREAM_BALANCE: &'static str = "balance";
@@ -59,3 +64,157 @@ pub const COMMAND_GET_VERSION: &'static str = "getVersion";
 pub const COMMAND_PING: &'static str = "ping";
 pub const COMMAND_TRADE_TRANSACTION: &'static str = "tradeTransaction";
 pub const COMMAND_TRADE_TRANSACTION_STATUS: &'static str = "tradeTransactionStatus";
+
+
+/// Command of the request/response API.
+///
+/// The command is implemented by the request struct (the command arguments). It binds the request
+/// with the command name and with type of the response data. Commands can be executed by the
+/// `XtbClient::execute` method. Commands not shipped by the crate can be defined by implementing
+/// this trait for a custom request struct.
+///
+/// If the server returns no data, the default value of the `Response` is returned.
+pub trait Command: Serialize + Send {
+    /// Name of the command (e.g. `getVersion`)
+    const NAME: &'static str;
+    /// Type of the data returned by the server
+    type Response: DeserializeOwned + Default + Send;
+}
+
+
+impl Command for GetAllSymbolsRequest {
+    const NAME: &'static str = COMMAND_GET_ALL_SYMBOLS;
+    type Response = GetAllSymbolsResponse;
+}
+
+
+impl Command for GetCalendarRequest {
+    const NAME: &'static str = COMMAND_GET_CALENDAR;
+    type Response = GetCalendarResponse;
+}
+
+
+impl Command for GetChartLastRequestRequest {
+    const NAME: &'static str = COMMAND_GET_CHART_LAST_REQUEST;
+    type Response = GetChartLastRequestResponse;
+}
+
+
+impl Command for GetChartRangeRequestRequest {
+    const NAME: &'static str = COMMAND_GET_CHART_RANGE_REQUEST;
+    type Response = GetChartRangeRequestResponse;
+}
+
+
+impl Command for GetCommissionDefRequest {
+    const NAME: &'static str = COMMAND_GET_COMMISSION_DEF;
+    type Response = GetCommissionDefResponse;
+}
+
+
+impl Command for GetCurrentUserDataRequest {
+    const NAME: &'static str = COMMAND_GET_CURRENT_USER_DATA;
+    type Response = GetCurrentUserDataResponse;
+}
+
+
+impl Command for GetIbsHistoryRequest {
+    const NAME: &'static str = COMMAND_GET_IBS_HISTORY;
+    type Response = GetIbsHistoryResponse;
+}
+
+
+impl Command for GetMarginLevelRequest {
+    const NAME: &'static str = COMMAND_GET_MARGIN_LEVEL;
+    type Response = GetMarginLevelResponse;
+}
+
+
+impl Command for GetMarginTradeRequest {
+    const NAME: &'static str = COMMAND_GET_MARGIN_TRADE;
+    type Response = GetMarginTradeResponse;
+}
+
+
+impl Command for GetNewsRequest {
+    const NAME: &'static str = COMMAND_GET_NEWS;
+    type Response = GetNewsResponse;
+}
+
+
+impl Command for GetProfitCalculationRequest {
+    const NAME: &'static str = COMMAND_GET_PROFIT_CALCULATION;
+    type Response = GetProfitCalculationResponse;
+}
+
+
+impl Command for GetServerTimeRequest {
+    const NAME: &'static str = COMMAND_GET_SERVER_TIME;
+    type Response = GetServerTimeResponse;
+}
+
+
+impl Command for GetStepRulesRequest {
+    const NAME: &'static str = COMMAND_GET_STEP_RULES;
+    type Response = GetStepRulesResponse;
+}
+
+
+impl Command for GetSymbolRequest {
+    const NAME: &'static str = COMMAND_GET_SYMBOL;
+    type Response = GetSymbolResponse;
+}
+
+
+impl Command for GetTickPricesRequest {
+    const NAME: &'static str = COMMAND_GET_TICK_PRICES;
+    type Response = GetTickPricesResponse;
+}
+
+
+impl Command for GetTradeRecordsRequest {
+    const NAME: &'static str = COMMAND_GET_TRADE_RECORDS;
+    type Response = GetTradeRecordsResponse;
+}
+
+
+impl Command for GetTradesRequest {
+    const NAME: &'static str = COMMAND_GET_TRADES;
+    type Response = GetTradesResponse;
+}
+
+
+impl Command for GetTradesHistoryRequest {
+    const NAME: &'static str = COMMAND_GET_TRADES_HISTORY;
+    type Response = GetTradesHistoryResponse;
+}
+
+
+impl Command for GetTradingHoursRequest {
+    const NAME: &'static str = COMMAND_GET_TRADING_HOURS;
+    type Response = GetTradingHoursResponse;
+}
+
+
+impl Command for GetVersionRequest {
+    const NAME: &'static str = COMMAND_GET_VERSION;
+    type Response = GetVersionResponse;
+}
+
+
+impl Command for TradeTransactionRequest {
+    const NAME: &'static str = COMMAND_TRADE_TRANSACTION;
+    type Response = TradeTransactionResponse;
+}
+
+
+impl Command for TradeTransactionStatusRequest {
+    const NAME: &'static str = COMMAND_TRADE_TRANSACTION_STATUS;
+    type Response = TradeTransactionStatusResponse;
+}
+
+
+impl Command for PingRequest {
+    const NAME: &'static str = COMMAND_PING;
+    type Response = PingResponse;
+}
@@ -3,6 +3,7 @@ pub use data::*;
 pub use enums::*;
 pub use messages::*;
 pub use commands::*;
+pub use stream_events::*;
 
 mod api_errors;
 mod data;
@@ -12,3 +13,4 @@ mod messages;
 #[cfg(test)]
 mod test_payloads;
 mod commands;
+mod stream_events;
@@ -0,0 +1,323 @@
+use std::sync::{Arc, OnceLock};
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{from_slice, from_str, Value};
+use serde_json::value::{RawValue, to_raw_value};
+
+use crate::schema::{STREAM_BALANCE, STREAM_CANDLES, STREAM_KEEP_ALIVE, STREAM_NEWS, STREAM_PROFITS, STREAM_TICK_PRICES, STREAM_TRADE_STATUS, STREAM_TRADES, StreamDataMessage, StreamGetBalanceData, StreamGetCandlesData, StreamGetKeepAliveData, StreamGetNewsData, StreamGetProfitData, StreamGetTickPricesData, StreamGetTradesData, StreamGetTradeStatusData};
+
+
+/// Typed data of a stream data message.
+///
+/// The data are shared by `Arc`, so cloning the event does not copy them.
+#[derive(Clone, PartialEq, Debug)]
+pub enum StreamEvent {
+    Balance(Arc<StreamGetBalanceData>),
+    Candle(Arc<StreamGetCandlesData>),
+    KeepAlive(Arc<StreamGetKeepAliveData>),
+    News(Arc<StreamGetNewsData>),
+    Profit(Arc<StreamGetProfitData>),
+    TickPrices(Arc<StreamGetTickPricesData>),
+    Trade(Arc<StreamGetTradesData>),
+    TradeStatus(Arc<StreamGetTradeStatusData>),
+}
+
+
+impl StreamEvent {
+    /// Decode the JSON encoded `data` of the message with the `command`.
+    ///
+    /// # Returns
+    ///
+    /// * `None` - the command is not known
+    /// * `Some(Ok(StreamEvent))` - the data was decoded
+    /// * `Some(Err(serde_json::Error))` - the data does not match the command
+    pub fn decode(command: &str, data: &str) -> Option<Result<Self, serde_json::Error>> {
+        let event = match command {
+            STREAM_BALANCE => from_str(data).map(|data| Self::Balance(Arc::new(data))),
+            STREAM_CANDLES => from_str(data).map(|data| Self::Candle(Arc::new(data))),
+            STREAM_KEEP_ALIVE => from_str(data).map(|data| Self::KeepAlive(Arc::new(data))),
+            STREAM_NEWS => from_str(data).map(|data| Self::News(Arc::new(data))),
+            STREAM_PROFITS => from_str(data).map(|data| Self::Profit(Arc::new(data))),
+            STREAM_TICK_PRICES => from_str(data).map(|data| Self::TickPrices(Arc::new(data))),
+            STREAM_TRADES => from_str(data).map(|data| Self::Trade(Arc::new(data))),
+            STREAM_TRADE_STATUS => from_str(data).map(|data| Self::TradeStatus(Arc::new(data))),
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+
+/// Stream data message as received by the stream connection.
+#[derive(Deserialize)]
+struct RawStreamMessage {
+    /// Source command
+    command: String,
+    /// Payload not parsed yet
+    data: Box<RawValue>,
+}
+
+
+/// The `symbol` field of the message data.
+#[derive(Deserialize)]
+struct DataSymbol {
+    #[serde(default)]
+    symbol: Option<String>,
+}
+
+
+/// Stream data message parsed once by the stream connection and shared by all message streams it
+/// is delivered to.
+///
+/// Only the command is parsed when the message is received. Other forms of the data are decoded
+/// from the raw JSON on the first use and kept for other consumers: the typed `StreamEvent` (for
+/// typed data streams), the `Value` and the `StreamDataMessage` (for raw message streams and
+/// filters) and the `symbol` field (for routing).
+#[derive(Debug)]
+pub struct DecodedStreamMessage {
+    /// Source command
+    command: String,
+    /// The data as received from the server
+    data: Box<RawValue>,
+    /// The `symbol` field of the data
+    symbol: OnceLock<Option<String>>,
+    /// The data parsed to the `Value`
+    value: OnceLock<Arc<Value>>,
+    /// The message with the data parsed to the `Value`
+    message: OnceLock<StreamDataMessage>,
+    /// The typed data. `None` if the command is not known or the data cannot be decoded.
+    event: OnceLock<Option<StreamEvent>>,
+}
+
+
+impl DecodedStreamMessage {
+    /// Parse the command of the JSON encoded message. The data are kept raw.
+    pub fn parse(json: &[u8]) -> Result<Self, serde_json::Error> {
+        let RawStreamMessage { command, data } = from_slice(json)?;
+        Ok(Self::from_parts(command, data))
+    }
+
+    /// Wrap the already parsed `message`.
+    pub fn from_message(message: StreamDataMessage) -> Self {
+        let data = to_raw_value(&message.data).expect("The JSON value can always be serialized");
+        let decoded = Self::from_parts(message.command.clone(), data);
+        let _ = decoded.message.set(message);
+        decoded
+    }
+
+    fn from_parts(command: String, data: Box<RawValue>) -> Self {
+        Self {
+            command,
+            data,
+            symbol: OnceLock::new(),
+            value: OnceLock::new(),
+            message: OnceLock::new(),
+            event: OnceLock::new(),
+        }
+    }
+
+    /// Source command of the message.
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// The JSON encoded data as received from the server.
+    pub fn raw_data(&self) -> &str {
+        self.data.get()
+    }
+
+    /// The `symbol` field of the data. `None` if the data has no string `symbol` field.
+    pub fn symbol(&self) -> Option<&str> {
+        self.symbol
+            .get_or_init(|| from_str::<DataSymbol>(self.raw_data()).ok().and_then(|data| data.symbol))
+            .as_deref()
+    }
+
+    /// The data parsed to the `Value`. Invalid data are kept as the JSON string.
+    pub fn value(&self) -> &Arc<Value> {
+        self.value.get_or_init(|| match self.message.get() {
+            Some(message) => Arc::new(message.data.clone()),
+            None => Arc::new(from_str(self.raw_data()).unwrap_or_else(|_| Value::String(self.raw_data().to_owned()))),
+        })
+    }
+
+    /// The message with the data parsed to the `Value`.
+    pub fn message(&self) -> &StreamDataMessage {
+        self.message.get_or_init(|| StreamDataMessage { command: self.command.clone(), data: self.value().as_ref().clone() })
+    }
+
+    /// Take the message with the data parsed to the `Value`.
+    pub fn into_message(mut self) -> StreamDataMessage {
+        self.message();
+        self.message.take().expect("The message was just initialized")
+    }
+
+    /// The typed data. `None` if the command is not known or the data cannot be decoded.
+    pub fn event(&self) -> Option<&StreamEvent> {
+        self.event
+            .get_or_init(|| StreamEvent::decode(&self.command, self.raw_data()).and_then(Result::ok))
+            .as_ref()
+    }
+}
+
+
+/// Data type delivered by data streams.
+///
+/// The data are taken from the decoded `StreamEvent` shared by all data streams the message is
+/// delivered to. When the event carries other data type (or the data were not decoded), the data
+/// are deserialized from the raw message data.
+pub trait StreamData: DeserializeOwned + Send + Sync {
+    /// Take the shared data from the `event`. Return `None` if the event carries other data type.
+    fn from_event(event: &StreamEvent) -> Option<Arc<Self>>;
+
+    /// Take the shared data from the decoded `message`.
+    fn from_message(message: &DecodedStreamMessage) -> Result<Arc<Self>, serde_json::Error> {
+        match message.event().and_then(Self::from_event) {
+            Some(data) => Ok(data),
+            None => from_str(message.raw_data()).map(Arc::new),
+        }
+    }
+}
+
+
+impl StreamData for Value {
+    fn from_event(_event: &StreamEvent) -> Option<Arc<Self>> {
+        None
+    }
+
+    fn from_message(message: &DecodedStreamMessage) -> Result<Arc<Self>, serde_json::Error> {
+        Ok(message.value().clone())
+    }
+}
+
+
+impl StreamData for StreamGetBalanceData {
+    fn from_event(event: &StreamEvent) -> Option<Arc<Self>> {
+        match event {
+            StreamEvent::Balance(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+}
+
+
+impl StreamData for StreamGetCandlesData {
+    fn from_event(event: &StreamEvent) -> Option<Arc<Self>> {
+        match event {
+            StreamEvent::Candle(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+}
+
+
+impl StreamData for StreamGetKeepAliveData {
+    fn from_event(event: &StreamEvent) -> Option<Arc<Self>> {
+        match event {
+            StreamEvent::KeepAlive(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+}
+
+
+impl StreamData for StreamGetNewsData {
+    fn from_event(event: &StreamEvent) -> Option<Arc<Self>> {
+        match event {
+            StreamEvent::News(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+}
+
+
+impl StreamData for StreamGetProfitData {
+    fn from_event(event: &StreamEvent) -> Option<Arc<Self>> {
+        match event {
+            StreamEvent::Profit(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+}
+
+
+impl StreamData for StreamGetTickPricesData {
+    fn from_event(event: &StreamEvent) -> Option<Arc<Self>> {
+        match event {
+            StreamEvent::TickPrices(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+}
+
+
+impl StreamData for StreamGetTradesData {
+    fn from_event(event: &StreamEvent) -> Option<Arc<Self>> {
+        match event {
+            StreamEvent::Trade(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+}
+
+
+impl StreamData for StreamGetTradeStatusData {
+    fn from_event(event: &StreamEvent) -> Option<Arc<Self>> {
+        match event {
+            StreamEvent::TradeStatus(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rstest::rstest;
+    use serde_json::{json, Value};
+
+    use crate::schema::{DecodedStreamMessage, StreamData, StreamEvent, StreamGetKeepAliveData, StreamGetTickPricesData};
+
+    fn parse(message: Value) -> DecodedStreamMessage {
+        DecodedStreamMessage::parse(message.to_string().as_bytes()).unwrap()
+    }
+
+    #[rstest]
+    #[case("keepAlive", json ! ({"timestamp": 1}), true)]
+    #[case("keepAlive", json ! ({"timestamp": "invalid"}), false)]
+    #[case("unknownCommand", json ! ({"timestamp": 1}), false)]
+    fn decode_event(#[case] command: &str, #[case] data: Value, #[case] is_decoded: bool) {
+        assert_eq!(parse(json!({"command": command, "data": data})).event().is_some(), is_decoded);
+    }
+
+    #[test]
+    fn event_is_decoded_once_and_shared() {
+        let message = parse(json!({"command": "keepAlive", "data": {"timestamp": 1}}));
+        assert_eq!(message.event(), Some(&StreamEvent::KeepAlive(Arc::new(StreamGetKeepAliveData { timestamp: 1 }))));
+
+        let first = StreamGetKeepAliveData::from_message(&message).unwrap();
+        let second = StreamGetKeepAliveData::from_message(&message).unwrap();
+        assert_eq!(first.timestamp, 1);
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn fallback_to_raw_data() {
+        let message = parse(json!({"command": "keepAlive", "data": {"timestamp": 1}}));
+        assert!(StreamGetTickPricesData::from_message(&message).is_err());
+        assert!(Arc::ptr_eq(&Value::from_message(&message).unwrap(), message.value()));
+        assert_eq!(message.into_message().data, json!({"timestamp": 1}));
+    }
+
+    #[rstest]
+    #[case(json ! ({"symbol": "EURUSD", "ask": 1.0}), Some("EURUSD"))]
+    #[case(json ! ({"symbol": 1, "ask": 1.0}), None)]
+    #[case(json ! ({"ask": 1.0}), None)]
+    #[case(json ! ([1, 2]), None)]
+    fn symbol_of_data(#[case] data: Value, #[case] symbol: Option<&str>) {
+        assert_eq!(parse(json!({"command": "tickPrices", "data": data})).symbol(), symbol);
+    }
+}
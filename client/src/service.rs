@@ -0,0 +1,190 @@
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_util::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{to_value, Value};
+use tower_service::Service;
+
+use crate::{BasicXtbConnection, BasicXtbConnectionError, ProcessedMessage, XtbClient, XtbClientError, XtbConnection};
+use crate::transport::Transport;
+
+
+/// Command of the request/response API used as the request of the `tower::Service`.
+///
+/// The command carries the command name and the typed payload. The `RESP` type parameter is type of
+/// the response returned by the `XtbClient` service, the raw `BasicXtbConnection` service returns
+/// the `ProcessedMessage` regardless of it.
+///
+/// Example: `XtbCommand::<_, GetVersionResponse>::new(COMMAND_GET_VERSION, GetVersionRequest)`
+pub struct XtbCommand<REQ = Value, RESP = Value> {
+    /// The command name (e.g. `getVersion`)
+    pub command: String,
+    /// The command arguments
+    pub payload: REQ,
+    /// Type of the response
+    response: PhantomData<fn() -> RESP>,
+}
+
+
+impl<REQ, RESP> XtbCommand<REQ, RESP> {
+    /// Create new command with the `payload`.
+    pub fn new(command: impl Into<String>, payload: REQ) -> Self {
+        Self { command: command.into(), payload, response: PhantomData }
+    }
+}
+
+
+impl<REQ: Clone, RESP> Clone for XtbCommand<REQ, RESP> {
+    fn clone(&self) -> Self {
+        Self::new(self.command.clone(), self.payload.clone())
+    }
+}
+
+
+impl<REQ: Debug, RESP> Debug for XtbCommand<REQ, RESP> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("XtbCommand")
+            .field("command", &self.command)
+            .field("payload", &self.payload)
+            .finish()
+    }
+}
+
+
+/// The client sends the command and deserializes the response into the `RESP`.
+///
+/// The request timeout of the client applies. The service is not ready when the client was closed.
+impl<REQ, RESP> Service<XtbCommand<REQ, RESP>> for XtbClient
+    where
+        REQ: Serialize + Send + 'static,
+        RESP: DeserializeOwned + Default + Send + 'static
+{
+    type Response = RESP;
+    type Error = XtbClientError;
+    type Future = BoxFuture<'static, Result<RESP, XtbClientError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.is_closed() {
+            return Poll::Ready(Err(XtbClientError::ClientClosed));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: XtbCommand<REQ, RESP>) -> Self::Future {
+        let client = self.clone();
+        Box::pin(async move {
+            client.send_and_wait_or_default(&request.command, request.payload).await
+        })
+    }
+}
+
+
+/// The connection sends the command and returns the raw response.
+///
+/// The service is not ready when the connection was closed.
+impl<T, REQ, RESP> Service<XtbCommand<REQ, RESP>> for Arc<BasicXtbConnection<T>>
+    where
+        T: Transport + 'static,
+        REQ: Serialize,
+{
+    type Response = ProcessedMessage;
+    type Error = BasicXtbConnectionError;
+    type Future = BoxFuture<'static, Result<ProcessedMessage, BasicXtbConnectionError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if !self.is_connected() {
+            return Poll::Ready(Err(BasicXtbConnectionError::ConnectionClosed { code: None, reason: None }));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: XtbCommand<REQ, RESP>) -> Self::Future {
+        let connection = self.clone();
+        let payload = to_value(request.payload);
+        Box::pin(async move {
+            let payload = payload.map_err(BasicXtbConnectionError::SerializationError)?;
+            connection.send_command(&request.command, Some(payload)).await?.await
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod service {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use serde_json::{from_str, json, Value};
+        use tokio::spawn;
+        use tokio::time::sleep;
+        use tokio_tungstenite::tungstenite::Message;
+        use tower::{BoxError, ServiceBuilder, ServiceExt};
+        use tower::timeout::error::Elapsed;
+
+        use crate::{BasicXtbConnection, BasicXtbConnectionError, ChannelTransport, Fault, FaultRule, MockFixtures, MockServer, ProcessedMessage, XtbClientError, XtbCommand};
+        use crate::schema::{COMMAND_GET_SERVER_TIME, COMMAND_GET_VERSION, GetServerTimeRequest, GetVersionRequest, GetVersionResponse};
+
+        #[tokio::test]
+        async fn client_typed_response() {
+            let fixtures = MockFixtures::new().with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+
+            let command = XtbCommand::<_, GetVersionResponse>::new(COMMAND_GET_VERSION, GetVersionRequest);
+            let response = client.clone().oneshot(command).await.unwrap();
+            assert_eq!(response.version, "2.5.0");
+
+            client.clone().close().await.unwrap();
+            let command = XtbCommand::<_, Value>::new(COMMAND_GET_VERSION, GetVersionRequest);
+            assert!(matches!(client.oneshot(command).await, Err(XtbClientError::ClientClosed)));
+        }
+
+        #[tokio::test]
+        async fn client_with_layers() {
+            let fixtures = MockFixtures::new()
+                .with_response(COMMAND_GET_VERSION, json!({"version": "2.5.0"}))
+                .with_fault(FaultRule::on_command(COMMAND_GET_SERVER_TIME, Fault::NoResponse));
+            let server = MockServer::start(fixtures).await.unwrap();
+            let client = server.client_builder().build("user", "password").await.unwrap();
+            let service = ServiceBuilder::new()
+                .concurrency_limit(4)
+                .timeout(Duration::from_millis(500))
+                .service(client);
+
+            let command = XtbCommand::<_, GetVersionResponse>::new(COMMAND_GET_VERSION, GetVersionRequest);
+            assert_eq!(service.clone().oneshot(command).await.unwrap().version, "2.5.0");
+
+            let command = XtbCommand::<_, Value>::new(COMMAND_GET_SERVER_TIME, GetServerTimeRequest);
+            let error: BoxError = service.oneshot(command).await.unwrap_err();
+            assert!(error.is::<Elapsed>());
+        }
+
+        #[tokio::test]
+        async fn connection_raw_response() {
+            let (client_end, mut server_end) = ChannelTransport::pair();
+            let connection = Arc::new(BasicXtbConnection::from_transport(client_end));
+
+            let responder = spawn(async move {
+                let request: Value = from_str(&server_end.recv().await.unwrap().to_string()).unwrap();
+                let response = json!({"status": true, "returnData": {"version": "2.5.0"}, "customTag": request["customTag"]});
+                server_end.send(Message::text(response.to_string())).unwrap();
+                server_end
+            });
+            let command: XtbCommand = XtbCommand::new(COMMAND_GET_VERSION, Value::Null);
+            match connection.clone().oneshot(command).await.unwrap() {
+                ProcessedMessage::Response(response) => assert_eq!(response.return_data, Some(json!({"version": "2.5.0"}))),
+                other => panic!("Expected response, but {:?}", other),
+            }
+
+            drop(responder.await.unwrap());
+            sleep(Duration::from_millis(10)).await;
+            let command: XtbCommand = XtbCommand::new(COMMAND_GET_VERSION, Value::Null);
+            assert!(matches!(connection.oneshot(command).await, Err(BasicXtbConnectionError::ConnectionClosed { .. })));
+        }
+    }
+}
@@ -1,18 +1,28 @@
+use std::fmt::{Debug, Formatter};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
 use async_trait::async_trait;
-use futures_util::stream::SplitSink;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{SinkExt, Stream, StreamExt};
 use serde::Serialize;
 use serde_json::{Map, to_string, to_value, Value};
 use thiserror::Error;
-use tokio::sync::broadcast::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
-use tokio_tungstenite::connect_async;
+use tokio::time::Instant;
 use tokio_tungstenite::tungstenite::Message;
-use tracing::{debug, error, info};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tracing::{debug, error, info, warn};
 use url::Url;
-use crate::schema::{StreamDataMessage, SubscribeRequest, UnsubscribeRequest};
+use crate::schema::{DecodedStreamMessage, Request, StreamDataMessage, SubscribeRequest, UnsubscribeRequest};
 
-use crate::listener::{listen_for_stream_data, Stream, StreamDataMessageHandler};
+use crate::listener::{listen_for_stream_data, StreamDataMessageHandler};
+use crate::connector::{connect_websocket, ConnectOptions};
+use crate::delivery::{DeliveryPolicy, Mailbox, SubscriberRegistry};
+use crate::interceptor::{InterceptorChain, Rejection};
+use crate::recording::{WireDirection, WireTap};
+use crate::transport::{Transport, WebSocketTransport};
 
 /// Common interface for stream command api of the XTB.
 #[async_trait]
@@ -37,37 +47,155 @@ pub trait XtbStreamConnection {
 }
 
 
-#[derive(Debug)]
-pub struct BasicXtbStreamConnection {
+/// Default number of messages kept for slow message streams
+pub const DEFAULT_STREAM_CHANNEL_CAPACITY: usize = 64;
+
+
+pub struct BasicXtbStreamConnection<T: Transport = WebSocketTransport> {
     /// Stream session id used to identify for the stream server
     stream_session_id: String,
-    /// Sender of messages used for delivering messages to `MessageStream` implementors
-    sender: Sender<StreamDataMessage>,
+    /// Message streams the incoming messages are delivered to
+    registry: SubscriberRegistry,
+    /// Capacity of message streams made with the default delivery policy
+    capacity: usize,
     /// Sink used for sending messages to the XTB server
-    sink: SplitSink<Stream, Message>,
+    sink: T::Sink,
     /// Handle used for join of listening task
     listener_join: JoinHandle<()>,
+    /// Recording of the traffic. If `None`, nothing is recorded.
+    wire_tap: Option<WireTap>,
+    /// Hooks called for every subscribe and unsubscribe command
+    interceptors: InterceptorChain,
 }
 
 
 impl BasicXtbStreamConnection {
     /// Create new instance of the stream connection.
     pub async fn new(url: Url, stream_session_id: String) -> Result<Self, BasicXtbStreamConnectionError> {
-        let (sender, _) = channel(64usize);
-        let host_clone = url.as_str().to_owned();
-        let (conn, _) = connect_async(url).await.map_err(|_| BasicXtbStreamConnectionError::CannotConnect(host_clone))?;
-        let (sink, stream) = conn.split();
-        let listener_join = listen_for_stream_data(stream, MessageHandler::new(sender.clone()));
-        Ok(Self {
+        Self::new_with_tap(url, stream_session_id, None).await
+    }
+
+    /// Create new instance of the stream connection. If the `wire_tap` is set, the traffic is recorded.
+    pub async fn new_with_tap(url: Url, stream_session_id: String, wire_tap: Option<WireTap>) -> Result<Self, BasicXtbStreamConnectionError> {
+        Self::connect(url, stream_session_id, &ConnectOptions::default(), wire_tap).await
+    }
+
+    /// Create new instance of the stream connection connected using the `options` (e.g. a proxy).
+    /// If the `wire_tap` is set, the traffic is recorded.
+    pub async fn connect(url: Url, stream_session_id: String, options: &ConnectOptions, wire_tap: Option<WireTap>) -> Result<Self, BasicXtbStreamConnectionError> {
+        Self::connect_with_capacity(url, stream_session_id, options, wire_tap, DEFAULT_STREAM_CHANNEL_CAPACITY).await
+    }
+
+    /// Same as the `connect`, but message streams keep up to `capacity` messages not received yet.
+    pub async fn connect_with_capacity(url: Url, stream_session_id: String, options: &ConnectOptions, wire_tap: Option<WireTap>, capacity: usize) -> Result<Self, BasicXtbStreamConnectionError> {
+        let transport = Self::open_socket(url, options).await?;
+        Ok(Self::from_transport_with_capacity(transport, stream_session_id, wire_tap, capacity))
+    }
+
+    /// Replace the underlying socket by a new one connected to the `url`.
+    ///
+    /// Message streams made by this connection continue to receive messages from the new socket.
+    /// Subscriptions are not restored, the caller is responsible for subscribing again with the new `stream_session_id`.
+    pub async fn reconnect(&mut self, url: Url, stream_session_id: String) -> Result<(), BasicXtbStreamConnectionError> {
+        self.reconnect_with_options(url, stream_session_id, &ConnectOptions::default()).await
+    }
+
+    /// Same as the `reconnect`, but the new socket is connected using the `options`.
+    pub async fn reconnect_with_options(&mut self, url: Url, stream_session_id: String, options: &ConnectOptions) -> Result<(), BasicXtbStreamConnectionError> {
+        let transport = Self::open_socket(url, options).await?;
+        self.replace_transport(transport, stream_session_id);
+        Ok(())
+    }
+
+    /// Open the websocket connected to the `url`.
+    async fn open_socket(url: Url, options: &ConnectOptions) -> Result<WebSocketTransport, BasicXtbStreamConnectionError> {
+        connect_websocket(&url, options).await.map_err(|err| {
+            error!("Cannot connect to stream server {}: {:?}", url, err);
+            BasicXtbStreamConnectionError::CannotConnect(url.to_string())
+        })
+    }
+}
+
+
+impl<T: Transport> BasicXtbStreamConnection<T> {
+    /// Create new instance of the stream connection communicating through the `transport`.
+    pub fn from_transport(transport: T, stream_session_id: String) -> Self {
+        Self::from_transport_with_tap(transport, stream_session_id, None)
+    }
+
+    /// Create new instance of the stream connection communicating through the `transport`.
+    /// If the `wire_tap` is set, the traffic is recorded.
+    pub fn from_transport_with_tap(transport: T, stream_session_id: String, wire_tap: Option<WireTap>) -> Self {
+        Self::from_transport_with_capacity(transport, stream_session_id, wire_tap, DEFAULT_STREAM_CHANNEL_CAPACITY)
+    }
+
+    /// Create new instance of the stream connection communicating through the `transport`.
+    ///
+    /// Message streams made by the `make_message_stream` method keep up to `capacity` messages not
+    /// received yet. When a message stream falls behind, the oldest messages are dropped and the
+    /// stream yields `DataStreamError::Lagged` (see `DeliveryPolicy::DropOldest`).
+    pub fn from_transport_with_capacity(transport: T, stream_session_id: String, wire_tap: Option<WireTap>, capacity: usize) -> Self {
+        let registry = SubscriberRegistry::default();
+        let (sink, stream) = transport.split();
+        let listener_join = listen_for_stream_data::<T>(stream, MessageHandler::new(registry.clone()), wire_tap.clone());
+        Self {
             stream_session_id,
-            sender,
+            registry,
+            capacity,
             sink,
             listener_join,
-        })
+            wire_tap,
+            interceptors: InterceptorChain::default(),
+        }
+    }
+
+    /// Replace the underlying transport by the new one.
+    ///
+    /// Message streams made by this connection continue to receive messages from the new
+    /// transport. The new transport is recorded as a new connection.
+    pub fn replace_transport(&mut self, transport: T, stream_session_id: String) {
+        let (sink, stream) = transport.split();
+        self.listener_join.abort();
+        self.sink = sink;
+        self.wire_tap = self.wire_tap.as_ref().map(WireTap::renew);
+        self.listener_join = listen_for_stream_data::<T>(stream, MessageHandler::new(self.registry.clone()), self.wire_tap.clone());
+        self.stream_session_id = stream_session_id;
+    }
+
+    /// Create message stream receiving messages matching the `filter` according to the `policy`.
+    pub fn make_message_stream_with_policy(&self, filter: DataMessageFilter, policy: DeliveryPolicy) -> BasicMessageStream {
+        BasicMessageStream::new(self.registry.register(filter, policy))
+    }
+
+    /// Set the interceptors called for every subscribe and unsubscribe command. The previous chain
+    /// is replaced.
+    pub fn set_interceptors(&mut self, interceptors: InterceptorChain) {
+        self.interceptors = interceptors;
+    }
+
+    /// Return true if the connection to the stream server is still alive.
+    pub fn is_connected(&self) -> bool {
+        !self.listener_join.is_finished()
+    }
+
+    /// Send the close frame with the normal close code to the server.
+    ///
+    /// The server is expected to close the connection. No command can be sent after this call.
+    pub async fn close(&mut self) -> Result<(), BasicXtbStreamConnectionError> {
+        let message = Message::Close(Some(CloseFrame { code: CloseCode::Normal, reason: "".into() }));
+        self.sink.send(message).await.map_err(BasicXtbStreamConnectionError::CannotSend)
+    }
+
+    /// Pass the command and its arguments to interceptors. Return the request modified by
+    /// interceptors.
+    fn intercept(&self, command: &str, arguments: Option<Value>) -> Result<Request, Rejection> {
+        let mut request = Request { command: command.to_owned(), arguments, custom_tag: None };
+        self.interceptors.before_send(&mut request)?;
+        Ok(request)
     }
 
     /// Build message from request and arguments and send it to the server.
-    async fn assemble_and_send<T: Serialize>(&mut self, request: T, arguments: Option<Value>) -> Result<(), BasicXtbStreamConnectionError> {
+    async fn assemble_and_send<R: Serialize>(&mut self, request: R, arguments: Option<Value>) -> Result<(), BasicXtbStreamConnectionError> {
         let mut obj = to_value(request).map_err(|err| BasicXtbStreamConnectionError::SerializationFailed(err))?;
         let prepared_arguments = Self::prepare_arguments(arguments)?;
 
@@ -77,7 +205,13 @@ impl BasicXtbStreamConnection {
         }
         let serialized = to_string(&obj).map_err(|err| BasicXtbStreamConnectionError::SerializationFailed(err))?;
         let message = Message::text(serialized);
-        self.sink.send(message).await.map_err(|err| BasicXtbStreamConnectionError::CannotSend(err))
+        // only requests really sent are recorded
+        let recorded = self.wire_tap.as_ref().map(|tap| (tap, message.clone(), Instant::now()));
+        self.sink.send(message).await.map_err(|err| BasicXtbStreamConnectionError::CannotSend(err))?;
+        if let Some((tap, message, sent_at)) = recorded {
+            tap.record_at(WireDirection::Outgoing, &message, sent_at);
+        }
+        Ok(())
     }
 
     /// Check and prepare arguments.
@@ -97,22 +231,34 @@ impl BasicXtbStreamConnection {
 }
 
 
-impl Drop for BasicXtbStreamConnection {
+impl<T: Transport> Debug for BasicXtbStreamConnection<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicXtbStreamConnection")
+            .field("stream_session_id", &self.stream_session_id)
+            .field("receivers", &self.registry.len())
+            .finish()
+    }
+}
+
+
+impl<T: Transport> Drop for BasicXtbStreamConnection<T> {
     fn drop(&mut self) {
         self.listener_join.abort();
+        self.registry.close();
     }
 }
 
 
 #[async_trait]
-impl XtbStreamConnection for BasicXtbStreamConnection {
+impl<T: Transport> XtbStreamConnection for BasicXtbStreamConnection<T> {
     type MessageStream = BasicMessageStream;
 
     type Error = BasicXtbStreamConnectionError;
 
     async fn subscribe(&mut self, command: &str, arguments: Option<Value>) -> Result<(), Self::Error> {
+        let Request { command, arguments, .. } = self.intercept(command, arguments).map_err(|rejection| Self::Error::rejected(command, rejection))?;
         let request = SubscribeRequest::default()
-            .with_command(command)
+            .with_command(&command)
             .with_stream_session_id(&self.stream_session_id);
         info!("Subscribing for {command}");
         debug!("Subscription arguments are {arguments:?}");
@@ -120,43 +266,42 @@ impl XtbStreamConnection for BasicXtbStreamConnection {
     }
 
     async fn unsubscribe(&mut self, command: &str, arguments: Option<Value>) -> Result<(), Self::Error> {
-        let request = UnsubscribeRequest::default().with_command(command);
+        let Request { command, arguments, .. } = self.intercept(command, arguments).map_err(|rejection| Self::Error::rejected(command, rejection))?;
+        let request = UnsubscribeRequest::default().with_command(&command);
         info!("Unsubscribing from {command}");
         debug!("Unsubscription arguments are {arguments:?}");
         self.assemble_and_send(request, arguments).await
     }
 
     async fn make_message_stream(&mut self, filter: DataMessageFilter) -> Self::MessageStream {
-        BasicMessageStream::new(filter, self.sender.subscribe())
+        self.make_message_stream_with_policy(filter, DeliveryPolicy::DropOldest(self.capacity))
     }
 }
 
 
 /// Handle incoming data messages from stream
 struct MessageHandler {
-    /// Broadcast sender for messages
-    sender: Sender<StreamDataMessage>,
+    /// Message streams the messages are delivered to
+    registry: SubscriberRegistry,
 }
 
 
 impl MessageHandler {
     /// Create new instance of the MessageHandler
-    pub fn new(sender: Sender<StreamDataMessage>) -> Self {
-        Self { sender }
+    pub fn new(registry: SubscriberRegistry) -> Self {
+        Self { registry }
     }
 }
 
 
 #[async_trait]
 impl StreamDataMessageHandler for MessageHandler {
-    async fn handle_message(&self, message: StreamDataMessage) {
-        let cmd = message.command.to_owned();
+    async fn handle_message(&self, message: DecodedStreamMessage) {
+        let cmd = message.command().to_owned();
         info!("Handling incoming message {cmd}");
         debug!("Incoming message: {message:?}");
-        match self.sender.send(message) {
-            Err(err) => error!("Cannot broadcast message: {}", err),
-            _ => debug!("Message {cmd} was broadcast to the {} receivers", self.sender.len())
-        }
+        let delivered = self.registry.dispatch(message);
+        debug!("Message {cmd} was delivered to the {delivered} receivers");
     }
 }
 
@@ -253,6 +398,16 @@ pub enum BasicXtbStreamConnectionError {
     SerializationFailed(serde_json::Error),
     #[error("Only Value::Object can be used for the arguments")]
     InvalidArgumentsType,
+    #[error("The command '{command}' was rejected by an interceptor: {reason}")]
+    Rejected { command: String, reason: String },
+}
+
+
+impl BasicXtbStreamConnectionError {
+    /// Create the `Rejected` error of the `command`.
+    fn rejected(command: &str, rejection: Rejection) -> Self {
+        Self::Rejected { command: command.to_owned(), reason: rejection.reason }
+    }
 }
 
 
@@ -268,31 +423,44 @@ pub trait MessageStream {
 }
 
 
+/// Stream of messages matching the filter.
+///
+/// Messages can be received by the `MessageStream::next` method or by the `futures_util::Stream`
+/// implementation, so all `StreamExt` combinators can be used.
+///
+/// Messages are queued according to the `DeliveryPolicy` of the stream. When messages are dropped
+/// because the consumer is too slow, the `Stream` implementation yields `DataStreamError::Lagged`
+/// with the number of dropped messages and continues with the oldest message kept. The
+/// `MessageStream::next` method skips the lag.
 pub struct BasicMessageStream {
-    /// The filter for messages
-    filter: DataMessageFilter,
-    /// Stream with incoming messages
-    stream: Receiver<StreamDataMessage>,
+    /// Queue of incoming messages matching the filter
+    mailbox: Arc<Mailbox>,
 }
 
 
 impl BasicMessageStream {
     /// Create new instance
-    pub fn new(filter: DataMessageFilter, stream: Receiver<StreamDataMessage>) -> Self {
+    pub(crate) fn new(mailbox: Arc<Mailbox>) -> Self {
         BasicMessageStream {
-            filter,
-            stream,
+            mailbox,
         }
     }
+
+    /// Receive the next message with its data kept raw. The message is shared with other message
+    /// streams it was delivered to.
+    pub fn poll_next_decoded(&self, cx: &mut Context<'_>) -> Poll<Option<Result<Arc<DecodedStreamMessage>, DataStreamError>>> {
+        self.mailbox.poll_receive(cx)
+    }
 }
 
 
 #[async_trait]
 impl MessageStream for BasicMessageStream {
     async fn next(&mut self) -> Option<StreamDataMessage> {
-        while let Some(msg) = self.stream.recv().await.ok() {
-            if self.filter.test_message(&msg) {
-                return Some(msg);
+        while let Some(item) = StreamExt::next(self).await {
+            match item {
+                Ok(msg) => return Some(msg),
+                Err(err) => warn!("Message stream error skipped: {}", err),
             }
         }
         None
@@ -300,6 +468,34 @@ impl MessageStream for BasicMessageStream {
 }
 
 
+impl Stream for BasicMessageStream {
+    type Item = Result<StreamDataMessage, DataStreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_next_decoded(cx).map(|item| item.map(|result| result.map(|message| {
+            // the message is cloned only if it is shared with other message streams
+            Arc::try_unwrap(message).map(DecodedStreamMessage::into_message).unwrap_or_else(|shared| shared.message().clone())
+        })))
+    }
+}
+
+
+impl Drop for BasicMessageStream {
+    fn drop(&mut self) {
+        self.mailbox.detach();
+    }
+}
+
+
+#[derive(Debug, Error)]
+pub enum DataStreamError {
+    #[error("Cannot deserialize value: {0}")]
+    CannotDeserializeValue(serde_json::Error),
+    #[error("The consumer is too slow, {0} messages were dropped")]
+    Lagged(u64),
+}
+
+
 #[cfg(test)]
 mod tests {
     mod data_message_filter {
@@ -382,4 +578,67 @@ mod tests {
             assert_eq!(f.test_message(&msg), false)
         }
     }
+
+    mod channel_transport {
+        use std::time::Duration;
+
+        use futures_util::StreamExt;
+        use serde_json::{from_str, json, Value};
+        use tokio::time::sleep;
+        use tokio_tungstenite::tungstenite::Message;
+
+        use crate::{BasicXtbStreamConnection, ChannelTransport, DataMessageFilter, DataStreamError, MessageStream, XtbStreamConnection};
+
+        #[tokio::test]
+        async fn subscribe_and_receive() {
+            let (client_end, mut server_end) = ChannelTransport::pair();
+            let mut connection = BasicXtbStreamConnection::from_transport(client_end, "session".to_owned());
+            let mut stream = connection.make_message_stream(DataMessageFilter::Command("keepAlive".to_owned())).await;
+
+            connection.subscribe("getKeepAlive", None).await.unwrap();
+            let request: Value = from_str(&server_end.recv().await.unwrap().to_string()).unwrap();
+            assert_eq!(request["command"], "getKeepAlive");
+            assert_eq!(request["streamSessionId"], "session");
+
+            let message = json!({"command": "keepAlive", "data": {"timestamp": 1}});
+            server_end.send(Message::text(message.to_string())).unwrap();
+            let received = MessageStream::next(&mut stream).await.unwrap();
+            assert_eq!(received.data, json!({"timestamp": 1}));
+        }
+
+        #[tokio::test]
+        async fn stream_combinators() {
+            let (client_end, server_end) = ChannelTransport::pair();
+            let mut connection = BasicXtbStreamConnection::from_transport(client_end, "session".to_owned());
+            let stream = connection.make_message_stream(DataMessageFilter::Command("keepAlive".to_owned())).await;
+
+            for (command, timestamp) in [("keepAlive", 1), ("balance", 2), ("keepAlive", 3), ("keepAlive", 4)] {
+                let message = json!({"command": command, "data": {"timestamp": timestamp}});
+                server_end.send(Message::text(message.to_string())).unwrap();
+            }
+            let timestamps: Vec<_> = stream
+                .map(|message| message.unwrap().data["timestamp"].clone())
+                .take(2)
+                .collect()
+                .await;
+            assert_eq!(timestamps, vec![json!(1), json!(3)]);
+        }
+
+        #[tokio::test]
+        async fn lagged_stream_continues() {
+            let (client_end, server_end) = ChannelTransport::pair();
+            let mut connection = BasicXtbStreamConnection::from_transport_with_capacity(client_end, "session".to_owned(), None, 2);
+            let mut stream = connection.make_message_stream(DataMessageFilter::Always).await;
+
+            for timestamp in 1..=5 {
+                let message = json!({"command": "keepAlive", "data": {"timestamp": timestamp}});
+                server_end.send(Message::text(message.to_string())).unwrap();
+            }
+            sleep(Duration::from_millis(50)).await;
+
+            assert!(matches!(StreamExt::next(&mut stream).await, Some(Err(DataStreamError::Lagged(3)))));
+            assert_eq!(StreamExt::next(&mut stream).await.unwrap().unwrap().data, json!({"timestamp": 4}));
+            assert_eq!(StreamExt::next(&mut stream).await.unwrap().unwrap().data, json!({"timestamp": 5}));
+        }
+    }
 }
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::io::BufReader;
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ring::digest::{digest, SHA256};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use thiserror::Error;
+use tracing::warn;
+use webpki::{EndEntityCert, KeyUsage, VerifiedPath};
+use x509_parser::parse_x509_certificate;
+
+
+/// TLS configuration used for connections to the XTB servers.
+///
+/// By default, the server certificates are verified against the native root certificates of the
+/// platform. The verification can be extended by extra root certificates and by pinning of the
+/// public keys (SPKI pins) of the servers. If the custom `ClientConfig` is set, it is used as is
+/// and the root certificate options are ignored. The custom `ClientConfig` cannot be combined with
+/// pins, because its certificate verifier cannot be extended.
+#[derive(Clone)]
+pub struct TlsOptions {
+    /// Custom rustls configuration replacing everything else
+    client_config: Option<Arc<ClientConfig>>,
+    /// Root certificates trusted in addition to the native ones
+    extra_roots: Vec<CertificateDer<'static>>,
+    /// If true, the native root certificates of the platform are trusted
+    include_native_roots: bool,
+    /// SHA-256 hashes of the SubjectPublicKeyInfo accepted for the host
+    pins: HashMap<String, Vec<[u8; 32]>>,
+}
+
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self {
+            client_config: None,
+            extra_roots: Vec::new(),
+            include_native_roots: true,
+            pins: HashMap::new(),
+        }
+    }
+}
+
+
+impl TlsOptions {
+    /// Use the custom rustls configuration. The root certificate options are ignored and no pin
+    /// can be set.
+    pub fn with_client_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.client_config = Some(config);
+        self
+    }
+
+    /// Trust the root certificate (DER encoded).
+    pub fn with_root_certificate(mut self, certificate: CertificateDer<'static>) -> Self {
+        self.extra_roots.push(certificate);
+        self
+    }
+
+    /// Trust all root certificates in the PEM encoded `pem`.
+    pub fn with_root_certificates_pem(mut self, pem: &[u8]) -> Result<Self, TlsError> {
+        let mut reader = BufReader::new(pem);
+        for certificate in rustls_pemfile::certs(&mut reader) {
+            self.extra_roots.push(certificate.map_err(|err| TlsError::InvalidPem(err.to_string()))?);
+        }
+        Ok(self)
+    }
+
+    /// Do not trust the native root certificates of the platform. Only the extra root
+    /// certificates are trusted.
+    pub fn without_native_roots(mut self) -> Self {
+        self.include_native_roots = false;
+        self
+    }
+
+    /// Pin the public key of the `host` (e.g. `ws.xtb.com`).
+    ///
+    /// The `pin` is base64 encoded SHA-256 hash of the DER encoded SubjectPublicKeyInfo (the same
+    /// format as used by the HPKP). The pin may belong to the server certificate or to an
+    /// intermediate certificate of the verified chain. Certificates sent by the server but not used
+    /// in the verified chain are ignored and root certificates cannot be pinned. If more pins are set
+    /// for one host, at least one of them must match.
+    pub fn with_spki_pin(mut self, host: &str, pin: &str) -> Result<Self, TlsError> {
+        let hash: [u8; 32] = BASE64.decode(pin).ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| TlsError::InvalidPin(pin.to_owned()))?;
+        self.pins.entry(host.to_owned()).or_default().push(hash);
+        Ok(self)
+    }
+
+    /// Build the rustls configuration from the options.
+    pub(crate) fn client_config(&self) -> Result<Arc<ClientConfig>, TlsError> {
+        if let Some(config) = &self.client_config {
+            if !self.pins.is_empty() {
+                return Err(TlsError::PinsWithClientConfig);
+            }
+            return Ok(config.clone());
+        }
+
+        let mut roots = RootCertStore::empty();
+        if self.include_native_roots {
+            let native = rustls_native_certs::load_native_certs().map_err(|err| TlsError::NativeRoots(err.to_string()))?;
+            let (_, ignored) = roots.add_parsable_certificates(native);
+            if ignored > 0 {
+                warn!("{ignored} native root certificates cannot be parsed");
+            }
+        }
+        let (_, ignored) = roots.add_parsable_certificates(self.extra_roots.iter().cloned());
+        if ignored > 0 {
+            warn!("{ignored} extra root certificates cannot be parsed");
+        }
+
+        let builder = ClientConfig::builder();
+        let config = if self.pins.is_empty() {
+            builder.with_root_certificates(roots).with_no_client_auth()
+        } else {
+            let roots = Arc::new(roots);
+            let inner = WebPkiServerVerifier::builder(roots.clone())
+                .build()
+                .map_err(|err| TlsError::Verifier(err.to_string()))?;
+            let verifier = PinningVerifier { inner, roots, pins: self.pins.clone() };
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth()
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+
+impl Debug for TlsOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsOptions")
+            .field("client_config", &self.client_config.is_some())
+            .field("extra_roots", &self.extra_roots.len())
+            .field("include_native_roots", &self.include_native_roots)
+            .field("pinned_hosts", &self.pins.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+
+/// Return SHA-256 hash of the SubjectPublicKeyInfo of the DER encoded `certificate`.
+///
+/// Return `None` if the certificate cannot be parsed.
+pub fn spki_sha256(certificate: &CertificateDer) -> Option<[u8; 32]> {
+    let (_, certificate) = parse_x509_certificate(certificate.as_ref()).ok()?;
+    digest(&SHA256, certificate.public_key().raw).as_ref().try_into().ok()
+}
+
+
+/// Verify the certificate chain by the webpki and then check pins of the host.
+///
+/// Pins are matched against the end entity certificate and intermediates of a chain built by the
+/// webpki only. So a pinned certificate appended to an unrelated chain is not accepted.
+#[derive(Debug)]
+struct PinningVerifier {
+    /// Verifier of the chain
+    inner: Arc<WebPkiServerVerifier>,
+    /// Trust anchors used to build the pinned chain
+    roots: Arc<RootCertStore>,
+    /// Accepted SPKI hashes by the host
+    pins: HashMap<String, Vec<[u8; 32]>>,
+}
+
+
+impl PinningVerifier {
+    /// Return true if the end entity certificate or an intermediate of a verified chain matches
+    /// one of the `pins`.
+    fn is_pinned(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>], pins: &[[u8; 32]], now: UnixTime) -> bool {
+        let is_pinned = |certificate: &CertificateDer<'_>| spki_sha256(certificate).is_some_and(|hash| pins.contains(&hash));
+        if is_pinned(end_entity) {
+            return true;
+        }
+        let Ok(certificate) = EndEntityCert::try_from(end_entity) else {
+            return false;
+        };
+        // the path builder tries other chains until the callback accepts one
+        let verify_path = |path: &VerifiedPath<'_>| match path.intermediate_certificates().any(|cert| is_pinned(&cert.der())) {
+            true => Ok(()),
+            false => Err(webpki::Error::UnknownIssuer),
+        };
+        certificate.verify_for_usage(
+            default_provider().signature_verification_algorithms.all,
+            &self.roots.roots,
+            intermediates,
+            now,
+            KeyUsage::server_auth(),
+            None,
+            Some(&verify_path),
+        ).is_ok()
+    }
+}
+
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>], server_name: &ServerName<'_>, ocsp_response: &[u8], now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_owned(),
+            ServerName::IpAddress(address) => std::net::IpAddr::from(*address).to_string(),
+            _ => return Ok(verified),
+        };
+        let Some(pins) = self.pins.get(&host) else {
+            return Ok(verified);
+        };
+        if self.is_pinned(end_entity, intermediates, pins, now) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(format!("No certificate of {host} matches the pinned public keys")))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Cannot read the PEM certificates: {0}")]
+    InvalidPem(String),
+    #[error("The pin is not base64 encoded SHA-256 hash: {0}")]
+    InvalidPin(String),
+    #[error("Cannot load the native root certificates: {0}")]
+    NativeRoots(String),
+    #[error("Cannot build the certificate verifier: {0}")]
+    Verifier(String),
+    #[error("Pins cannot be combined with the custom client configuration")]
+    PinsWithClientConfig,
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod tls {
+        use std::sync::Arc;
+
+        use base64::Engine;
+        use base64::engine::general_purpose::STANDARD as BASE64;
+        use futures_util::{SinkExt, StreamExt};
+        use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+        use rustls::{ClientConfig, RootCertStore, ServerConfig};
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+        use tokio::net::TcpListener;
+        use tokio::spawn;
+        use tokio_rustls::TlsAcceptor;
+        use tokio_tungstenite::accept_async;
+        use tokio_tungstenite::tungstenite::Message;
+        use url::Url;
+
+        use crate::{connect_websocket, ConnectOptions, ConnectorError, spki_sha256, TlsError, TlsOptions};
+
+        /// Certificates of the test server.
+        struct TestPki {
+            /// Self-signed root certificate
+            root: CertificateDer<'static>,
+            /// Certificate authority signed by the root
+            intermediate: CertificateDer<'static>,
+            /// Certificate of the `localhost` signed by the intermediate
+            leaf: CertificateDer<'static>,
+            /// Private key of the leaf certificate
+            leaf_key: Vec<u8>,
+        }
+
+        fn make_pki() -> TestPki {
+            let root_key = KeyPair::generate().unwrap();
+            let mut root_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            root_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let root = root_params.self_signed(&root_key).unwrap();
+            let root_issuer = Issuer::new(root_params, root_key);
+
+            let intermediate_key = KeyPair::generate().unwrap();
+            let mut intermediate_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            intermediate_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let intermediate = intermediate_params.signed_by(&intermediate_key, &root_issuer).unwrap();
+            let issuer = Issuer::new(intermediate_params, intermediate_key);
+
+            let leaf_key = KeyPair::generate().unwrap();
+            let leaf = CertificateParams::new(vec!["localhost".to_owned()]).unwrap().signed_by(&leaf_key, &issuer).unwrap();
+            TestPki {
+                root: root.der().clone(),
+                intermediate: intermediate.der().clone(),
+                leaf: leaf.der().clone(),
+                leaf_key: leaf_key.serialize_der(),
+            }
+        }
+
+        /// Start TLS websocket echo server sending the leaf and the intermediate. Return its url.
+        async fn start_echo_server(pki: &TestPki) -> Url {
+            start_echo_server_with_chain(pki, vec![pki.leaf.clone(), pki.intermediate.clone()]).await
+        }
+
+        /// Start TLS websocket echo server sending the `chain`. Return its url.
+        async fn start_echo_server_with_chain(pki: &TestPki, chain: Vec<CertificateDer<'static>>) -> Url {
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(pki.leaf_key.clone()));
+            let config = ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(chain, key)
+                .unwrap();
+            let acceptor = TlsAcceptor::from(Arc::new(config));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let acceptor = acceptor.clone();
+                    spawn(async move {
+                        let Ok(stream) = acceptor.accept(stream).await else { return };
+                        let Ok(mut socket) = accept_async(stream).await else { return };
+                        while let Some(Ok(message)) = socket.next().await {
+                            if message.is_text() && socket.send(message).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            });
+            Url::parse(&format!("wss://localhost:{port}")).unwrap()
+        }
+
+        async fn assert_echo(url: &Url, tls: TlsOptions) {
+            let options = ConnectOptions::default().with_tls(tls);
+            let mut socket = connect_websocket(url, &options).await.unwrap();
+            socket.send(Message::text("hello")).await.unwrap();
+            assert_eq!(socket.next().await.unwrap().unwrap(), Message::text("hello"));
+        }
+
+        fn pin_of(certificate: &CertificateDer) -> String {
+            BASE64.encode(spki_sha256(certificate).unwrap())
+        }
+
+        #[tokio::test]
+        async fn extra_root_is_trusted() {
+            let pki = make_pki();
+            let url = start_echo_server(&pki).await;
+            assert_echo(&url, TlsOptions::default().without_native_roots().with_root_certificate(pki.root.clone())).await;
+        }
+
+        #[tokio::test]
+        async fn unknown_root_is_rejected() {
+            let pki = make_pki();
+            let url = start_echo_server(&pki).await;
+            let other_root = make_pki().root;
+            let options = ConnectOptions::default().with_tls(TlsOptions::default().without_native_roots().with_root_certificate(other_root));
+
+            let result = connect_websocket(&url, &options).await;
+            assert!(matches!(result, Err(ConnectorError::Handshake(_))));
+        }
+
+        #[tokio::test]
+        async fn matching_pin_is_accepted() {
+            let pki = make_pki();
+            let url = start_echo_server(&pki).await;
+            let tls = TlsOptions::default()
+                .without_native_roots()
+                .with_root_certificate(pki.root.clone())
+                .with_spki_pin("localhost", &pin_of(&pki.leaf))
+                .unwrap();
+            assert_echo(&url, tls).await;
+        }
+
+        #[tokio::test]
+        async fn pin_of_intermediate_in_verified_chain_is_accepted() {
+            let pki = make_pki();
+            let url = start_echo_server(&pki).await;
+            let tls = TlsOptions::default()
+                .without_native_roots()
+                .with_root_certificate(pki.root.clone())
+                .with_spki_pin("localhost", &pin_of(&make_pki().leaf))
+                .unwrap()
+                .with_spki_pin("localhost", &pin_of(&pki.intermediate))
+                .unwrap();
+            assert_echo(&url, tls).await;
+        }
+
+        #[tokio::test]
+        async fn pinned_certificate_appended_to_unrelated_chain_is_rejected() {
+            // the server chain is trusted, the pinned intermediate is trusted too, but it does not
+            // issue the server certificate
+            let pki = make_pki();
+            let pinned = make_pki();
+            let chain = vec![pki.leaf.clone(), pki.intermediate.clone(), pinned.intermediate.clone()];
+            let url = start_echo_server_with_chain(&pki, chain).await;
+            let tls = TlsOptions::default()
+                .without_native_roots()
+                .with_root_certificate(pki.root.clone())
+                .with_root_certificate(pinned.root.clone())
+                .with_spki_pin("localhost", &pin_of(&pinned.intermediate))
+                .unwrap();
+            let options = ConnectOptions::default().with_tls(tls);
+
+            let result = connect_websocket(&url, &options).await;
+            assert!(matches!(result, Err(ConnectorError::Handshake(_))));
+        }
+
+        #[tokio::test]
+        async fn mismatching_pin_is_rejected() {
+            let pki = make_pki();
+            let url = start_echo_server(&pki).await;
+            let tls = TlsOptions::default()
+                .without_native_roots()
+                .with_root_certificate(pki.root.clone())
+                .with_spki_pin("localhost", &pin_of(&make_pki().leaf))
+                .unwrap();
+            let options = ConnectOptions::default().with_tls(tls);
+
+            let result = connect_websocket(&url, &options).await;
+            assert!(matches!(result, Err(ConnectorError::Handshake(_))));
+        }
+
+        #[tokio::test]
+        async fn custom_client_config() {
+            let pki = make_pki();
+            let url = start_echo_server(&pki).await;
+            let mut roots = RootCertStore::empty();
+            roots.add(pki.root.clone()).unwrap();
+            let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+            assert_echo(&url, TlsOptions::default().with_client_config(Arc::new(config))).await;
+        }
+
+        #[test]
+        fn pins_with_client_config_are_rejected() {
+            let pki = make_pki();
+            let config = ClientConfig::builder().with_root_certificates(RootCertStore::empty()).with_no_client_auth();
+            let tls = TlsOptions::default()
+                .with_client_config(Arc::new(config))
+                .with_spki_pin("localhost", &pin_of(&pki.leaf))
+                .unwrap();
+
+            assert!(matches!(tls.client_config(), Err(TlsError::PinsWithClientConfig)));
+        }
+
+        #[test]
+        fn spki_of_malformed_certificate() {
+            let pki = make_pki();
+            let mut truncated = pki.leaf.as_ref().to_vec();
+            truncated.truncate(truncated.len() / 2);
+
+            assert!(spki_sha256(&CertificateDer::from(truncated)).is_none());
+            assert!(spki_sha256(&CertificateDer::from(vec![0x31, 0x00])).is_none());
+        }
+
+        #[test]
+        fn invalid_pin() {
+            let result = TlsOptions::default().with_spki_pin("ws.xtb.com", "not a pin");
+            assert!(matches!(result, Err(TlsError::InvalidPin(_))));
+        }
+
+        #[test]
+        fn root_certificates_from_pem() {
+            let pki = make_pki();
+            let pem = format!(
+                "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                BASE64.encode(pki.root.as_ref())
+            );
+            let options = TlsOptions::default().with_root_certificates_pem(pem.as_bytes()).unwrap();
+            assert_eq!(options.extra_roots, vec![pki.root]);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{Sink, Stream};
+use futures_util::stream::{SplitSink, SplitStream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+
+
+/// Error returned by transports.
+pub type TransportError = tokio_tungstenite::tungstenite::Error;
+
+
+/// The default transport - websocket connected by the tokio-tungstenite.
+pub type WebSocketTransport = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+
+/// Transport of websocket messages between a connection and a server.
+///
+/// The transport consists of a message sink (outgoing messages) and a message stream (incoming
+/// messages). Connections (`BasicXtbConnection` and `BasicXtbStreamConnection`) are generic over
+/// this trait, so the default websocket can be replaced by e.g. in-memory channel, proxied socket
+/// or a recording wrapper.
+pub trait Transport: Send + 'static {
+    /// Sink of outgoing messages
+    type Sink: Sink<Message, Error=TransportError> + Send + Unpin + 'static;
+
+    /// Stream of incoming messages. The stream is finished when the transport is closed.
+    type Stream: Stream<Item=Result<Message, TransportError>> + Send + Unpin + 'static;
+
+    /// Split the transport into the sink and the stream.
+    fn split(self) -> (Self::Sink, Self::Stream);
+}
+
+
+impl<S> Transport for WebSocketStream<S>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static
+{
+    type Sink = SplitSink<Self, Message>;
+
+    type Stream = SplitStream<Self>;
+
+    fn split(self) -> (Self::Sink, Self::Stream) {
+        StreamExt::split(self)
+    }
+}
+
+
+/// In-memory transport made of two channels.
+///
+/// Transports are created in pairs by the `ChannelTransport::pair()`. Messages sent to one end are
+/// received by the other one. When one end is dropped, the stream of the other end is finished.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    /// Sender of outgoing messages
+    sender: UnboundedSender<Message>,
+    /// Receiver of incoming messages
+    receiver: UnboundedReceiver<Message>,
+}
+
+
+impl ChannelTransport {
+    /// Create pair of connected transports.
+    pub fn pair() -> (Self, Self) {
+        let (left_sender, right_receiver) = unbounded_channel();
+        let (right_sender, left_receiver) = unbounded_channel();
+        (
+            Self { sender: left_sender, receiver: left_receiver },
+            Self { sender: right_sender, receiver: right_receiver },
+        )
+    }
+
+    /// Send message to the other end.
+    ///
+    /// If the other end was dropped, the message is returned back as the error.
+    pub fn send(&self, message: Message) -> Result<(), Message> {
+        self.sender.send(message).map_err(|err| err.0)
+    }
+
+    /// Receive message from the other end.
+    ///
+    /// Return `None` if the other end was dropped.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.receiver.recv().await
+    }
+}
+
+
+impl Transport for ChannelTransport {
+    type Sink = ChannelSink;
+
+    type Stream = ChannelStream;
+
+    fn split(self) -> (Self::Sink, Self::Stream) {
+        (ChannelSink(self.sender), ChannelStream(self.receiver))
+    }
+}
+
+
+/// Sink part of the `ChannelTransport`.
+#[derive(Debug)]
+pub struct ChannelSink(UnboundedSender<Message>);
+
+
+impl Sink<Message> for ChannelSink {
+    type Error = TransportError;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.0.is_closed() {
+            Poll::Ready(Err(TransportError::ConnectionClosed))
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.0.send(item).map_err(|_| TransportError::ConnectionClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+/// Stream part of the `ChannelTransport`.
+#[derive(Debug)]
+pub struct ChannelStream(UnboundedReceiver<Message>);
+
+
+impl Stream for ChannelStream {
+    type Item = Result<Message, TransportError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx).map(|message| message.map(Ok))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    mod channel_transport {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        use crate::{ChannelTransport, Transport};
+
+        #[tokio::test]
+        async fn deliver_messages_both_ways() {
+            let (left, mut right) = ChannelTransport::pair();
+            let (mut sink, mut stream) = left.split();
+
+            sink.send(Message::text("ping")).await.unwrap();
+            assert_eq!(right.recv().await, Some(Message::text("ping")));
+
+            right.send(Message::text("pong")).unwrap();
+            assert_eq!(stream.next().await.unwrap().unwrap(), Message::text("pong"));
+        }
+
+        #[tokio::test]
+        async fn stream_ends_when_peer_is_dropped() {
+            let (left, right) = ChannelTransport::pair();
+            let (mut sink, mut stream) = left.split();
+            drop(right);
+
+            assert!(stream.next().await.is_none());
+            assert!(sink.send(Message::text("ping")).await.is_err());
+        }
+    }
+}